@@ -1,3 +1,4 @@
+/// @docImport 'dart:async';
 /// @docImport 'dart:isolate';
 /// @docImport 'package:weblocks/weblocks.dart';
 library;
@@ -9,22 +10,243 @@ library;
 ///
 /// To obtain an instance of this class, use [lockManager].
 abstract interface class LockManager {
+  /// Requests the lock identified by [name].
+  ///
+  /// On native platforms, locks are only shared between isolates of the same
+  /// process by default. With [crossProcess], the lock is also backed by a
+  /// lock file so that it is exclusive across processes. Locks held by other
+  /// processes are released by the operating system when these processes exit.
+  /// The directory storing lock files can be changed with [setLockDirectory].
+  /// Note that [steal] can't preempt holders in other processes, such requests
+  /// will wait for them instead.
+  ///
+  /// On the web, locks are always shared between all tabs and workers of the
+  /// same origin and [crossProcess] has no effect.
+  ///
+  /// When a [timeout] is set, the request is dropped if it couldn't be granted
+  /// in time. [LockRequest.completion] then completes with a
+  /// [TimeoutException]. Timeouts can't be combined with [steal] or
+  /// [ifAvailable].
+  ///
+  /// With [detectDeadlocks], native platforms check whether waiting for this
+  /// request would complete a cycle of clients waiting on each other. In that
+  /// case, [LockRequest.completion] completes with a [DeadlockDetected]
+  /// exception. Since isolates are asynchronous, an isolate waiting on a lock
+  /// it holds itself is not considered a deadlock. This option has no effect
+  /// on the web.
+  ///
+  /// With [reentrant], a request made by an owner that already holds the lock
+  /// is granted immediately instead of waiting for the lock to be released.
+  /// The owner is this isolate unless an explicit, non-zero [owner] token is
+  /// given, which allows sharing ownership across isolates. The lock is then
+  /// held until all of these requests have been released, see
+  /// [HeldLock.holdCount]. Shared requests can re-enter exclusive locks, but
+  /// exclusive requests re-entering shared locks are queued as usual.
+  /// Reentrant requests are not supported on the web.
+  ///
+  /// With a [lease], the lock is released automatically if it hasn't been
+  /// renewed with [HeldLock.renew] for that duration after being granted. This
+  /// protects against isolates that hang while holding a lock. Expired locks
+  /// complete [HeldLock.expired]. Leases are only supported on native
+  /// platforms.
   LockRequest request(
     String name, {
     bool exclusive = true,
     bool ifAvailable = false,
     bool steal = false,
+    bool crossProcess = false,
+    bool detectDeadlocks = false,
+    bool reentrant = false,
+    int? owner,
+    Duration? timeout,
+    Duration? lease,
+  });
+
+  /// Obtains the lock identified by [name] if it's available right now.
+  ///
+  /// Unlike requests made with `ifAvailable` in [request], the result is
+  /// available synchronously. This returns null if the lock can't be granted
+  /// without waiting. With [crossProcess], the lock file must also be
+  /// available right away, see [request]. Cross-process locks can't be
+  /// obtained from a [namespace], so this always returns null there.
+  ///
+  /// To save a port per lock, no events are received for the lock unless
+  /// [observeStolen] is enabled. Otherwise, [HeldLock.stolen] never completes
+  /// and [HeldLock.upgrade] and [HeldLock.wait] throw. Synchronous requests
+  /// are only supported on native platforms.
+  HeldLock? tryRequest(
+    String name, {
+    bool exclusive = true,
+    bool crossProcess = false,
+    bool observeStolen = false,
   });
 
+  /// Requests all [locks] at once.
+  ///
+  /// The locks are granted together once all of them are available, so that
+  /// the request never holds some of them while waiting for others. Requests
+  /// obtaining overlapping sets of locks this way can't deadlock each other,
+  /// regardless of the order in which the locks are listed. While waiting,
+  /// later conflicting requests for these locks wait for the group, so that it
+  /// can't be starved by locks that are continuously requested.
+  ///
+  /// At least one lock must be listed, and each lock may only be listed once.
+  /// With hierarchical names, a lock and its ancestor can't be requested
+  /// together. Requesting multiple locks is only supported on native
+  /// platforms.
+  MultiLockRequest requestAll(Iterable<({String name, bool exclusive})> locks);
+
+  /// Takes [permits] permits from the named semaphore identified by [name].
+  ///
+  /// A semaphore allows at most [maxPermits] permits to be taken at the same
+  /// time across all isolates, all requests need to use the same [maxPermits]
+  /// while the semaphore is in use. Requests are granted in the order in which
+  /// they were made, and the returned future completes once the permits have
+  /// been taken. Semaphores and locks don't share names.
+  ///
+  /// Semaphores are only supported on native platforms.
+  Future<SemaphorePermits> acquirePermits(
+    String name, {
+    required int maxPermits,
+    int permits = 1,
+  });
+
+  /// Wakes up requests waiting on the [condition] of the lock identified by
+  /// [name] through [HeldLock.wait].
+  ///
+  /// By default, only the request that has been waiting the longest is woken
+  /// up. With [all], all waiting requests are woken up. The caller doesn't
+  /// need to hold the lock. Condition variables are only supported on native
+  /// platforms.
+  void notifyCondition(String name, String condition, {bool all = false});
+
   /// Returns a [LockManagerSnapshot] describing pending and held lock requests
   /// on this manager.
-  Future<LockManagerSnapshot> query();
+  ///
+  /// At most one filter may be given: With [name], only requests on the lock
+  /// or semaphore with that name are reported. With [prefix], only requests on
+  /// names starting with that prefix are reported. With [clientId], only
+  /// requests made by clients with that id are reported (see
+  /// [LockInfo.clientId]). On native platforms, filtering by name is cheap
+  /// regardless of how many locks are in use.
+  Future<LockManagerSnapshot> query({
+    String? name,
+    String? prefix,
+    String? clientId,
+  });
+
+  /// Returns a stream of [LockEvent]s describing changes to the lock called
+  /// [name], or to all locks whose name starts with [name] if [prefix] is
+  /// enabled.
+  ///
+  /// Events are reported for requests from all isolates using this manager. A
+  /// watcher is registered when the stream is listened to, and removed when
+  /// the subscription is cancelled. Watching locks is only supported on native
+  /// platforms.
+  Stream<LockEvent> watch(String name, {bool prefix = false});
+
+  /// Returns contention metrics for every lock name used on this manager
+  /// since the metrics have last been reset with [resetMetrics].
+  ///
+  /// Locks in use and a limited amount of recently used lock names are
+  /// reported individually. Metrics of other names are merged into an entry
+  /// with a `null` [LockMetrics.name], so that names derived from data (like
+  /// one lock per row) don't consume memory without bounds.
+  ///
+  /// Metrics are only supported on native platforms.
+  Future<List<LockMetrics>> metrics();
+
+  /// Forgets all metrics reported by [metrics].
+  void resetMetrics();
+
+  /// Releases all locks and semaphore permits obtained through this manager,
+  /// cancels its pending requests and stops its broadcast channels and
+  /// [watch]ers from receiving messages.
+  ///
+  /// Pending requests complete with a [LockRequestCancelled] error. Afterwards,
+  /// new lock requests are cancelled right away and new semaphore requests
+  /// fail. Calling this again has no effect.
+  ///
+  /// This is useful when an isolate shuts down deliberately, or when a logical
+  /// client logs out. It is only supported on native platforms.
+  void shutdown();
+
+  /// Renders queue lengths, holder counts, broadcast channel subscribers and
+  /// the [metrics] of this manager in the OpenMetrics text format, for
+  /// instance to serve them on a `/metrics` endpoint.
+  ///
+  /// Lock and channel names are used as `name` labels. To bound the amount of
+  /// series, at most [maxLockNames] and [maxChannelNames] names are reported
+  /// individually, preferring busy locks and channels. The others are
+  /// aggregated into series without a `name` label.
+  ///
+  /// This is only supported on native platforms.
+  Future<String> openMetrics({
+    int maxLockNames = 100,
+    int maxChannelNames = 100,
+  });
+
+  /// Changes the [QueuePolicy] deciding in which order pending requests for
+  /// the lock identified by [name] are granted.
+  ///
+  /// The policy applies to all isolates until it is changed again. Only
+  /// [QueuePolicy.fifo] is supported on the web, other policies throw an
+  /// [UnsupportedError] there.
+  void setQueuePolicy(String name, QueuePolicy policy);
+
+  /// Enables hierarchical lock names using the given [separator], or disables
+  /// them if it's null.
+  ///
+  /// With a separator like `/`, `db/users` is the parent of `db/users/42`.
+  /// Holding a lock implies intention locks on all of its ancestors, so that
+  /// conflicts are detected across levels: While `db/users/42` is held
+  /// exclusively, `db` and `db/users` can't be obtained in any mode. Shared
+  /// requests on a descendant only conflict with exclusive ancestors. Intention
+  /// locks are reported in [query] with [LockInfo.intention].
+  ///
+  /// The setting applies to all isolates using this namespace, but not to
+  /// lock files of cross-process locks. Hierarchical names are only supported
+  /// on native platforms.
+  void setHierarchySeparator(String? separator);
+
+  /// Changes the directory storing lock files for `crossProcess` locks, which
+  /// is also used to discover other processes for `crossProcess` broadcast
+  /// channels.
+  ///
+  /// Only processes using the same directory coordinate with each other. By
+  /// default, a directory in the system's temporary directory that is only
+  /// accessible to the current user is used. The `WEBLOCKS_LOCK_DIR`
+  /// environment variable also changes the default. Users that can write to
+  /// the directory can block locks by locking their files, so it shouldn't be
+  /// writable by untrusted users.
+  ///
+  /// The setting applies to the whole process, and only to lock files opened
+  /// afterwards. It has no effect on the web.
+  void setLockDirectory(String path);
+
+  /// Returns a [LockManager] for the isolated namespace identified by [name].
+  ///
+  /// Locks, semaphores and broadcast channels in different namespaces are
+  /// independent of each other, so their names never collide. This includes
+  /// the default namespace used by [lockManager]. Isolates using the same
+  /// [name] share the namespace.
+  ///
+  /// Namespaces are only supported on native platforms. Since lock files are
+  /// not namespaced, cross-process locks and channels are only available in
+  /// the default namespace.
+  LockManager namespace(String name);
 
   /// Creates a [BroadcastChannel] instance identified by its name.
   ///
   /// This allows different isolates, tabs or web workers to communicate with
   /// each other without setting up a prior communication channel.
-  BroadcastChannel broadcastChannel(String name);
+  ///
+  /// With [crossProcess], channels on native platforms also exchange messages
+  /// with channels of the same name in other processes on the same machine
+  /// that have enabled [crossProcess] as well. This uses Unix domain sockets
+  /// and is not supported on Windows. On the web, channels are always shared
+  /// between all tabs and workers of the same origin.
+  BroadcastChannel broadcastChannel(String name, {bool crossProcess = false});
 }
 
 /// A cross-platform implementation of the [Broadcast Channel API](https://developer.mozilla.org/en-US/docs/Web/API/Broadcast_Channel_API).
@@ -58,6 +280,173 @@ abstract interface class BroadcastChannel implements Stream<String> {
   void close();
 }
 
+/// Policies deciding the order in which pending requests are granted.
+///
+/// Requests with `steal: true` are always granted first, regardless of the
+/// policy.
+enum QueuePolicy {
+  /// Requests are granted in the order in which they were made.
+  ///
+  /// This is the behavior of the Web Locks API: A pending exclusive request
+  /// blocks all later shared requests, even if the lock is currently held in
+  /// shared mode.
+  fifo,
+
+  /// Shared requests overtake pending exclusive requests whenever they can be
+  /// granted.
+  ///
+  /// This maximizes concurrency for shared requests, but exclusive requests
+  /// may starve.
+  readerPreferring,
+
+  /// Exclusive requests overtake pending shared requests, and new shared
+  /// requests wait while an exclusive request is pending.
+  ///
+  /// Shared requests may starve with this policy.
+  writerPreferring,
+
+  /// Shared and exclusive phases alternate.
+  ///
+  /// When an exclusive holder releases the lock, all waiting shared requests
+  /// are granted together. Afterwards, the next exclusive request is granted.
+  /// Neither shared nor exclusive requests can starve with this policy.
+  phaseFair,
+}
+
+/// A change to a lock reported by [LockManager.watch].
+final class LockEvent {
+  /// What has happened to the request.
+  final LockEventType type;
+
+  /// The name of the lock.
+  final String name;
+
+  /// The client id of the request (see [LockInfo.clientId]).
+  final String clientId;
+
+  /// The id of the request, as reported by [LockInfo.requestId].
+  final int requestId;
+
+  /// Whether the request is for an exclusive lock.
+  final bool exclusive;
+
+  /// Creates a [LockEvent] from its fields.
+  const LockEvent({
+    required this.type,
+    required this.name,
+    required this.clientId,
+    required this.requestId,
+    required this.exclusive,
+  });
+
+  @override
+  String toString() {
+    return '(${type.name}: $name, request: $requestId, client: $clientId)';
+  }
+}
+
+/// Contention metrics of a lock name, reported by [LockManager.metrics].
+final class LockMetrics {
+  /// The name of the lock, or `null` for the merged metrics of lock names that
+  /// haven't been used recently.
+  final String? name;
+
+  /// How often the lock has been granted.
+  final int acquisitions;
+
+  /// How often the lock has been stolen from a holder.
+  final int steals;
+
+  /// How often an `ifAvailable` request could not be granted.
+  final int unavailable;
+
+  /// The time requests spent waiting for the lock to be granted.
+  final DurationHistogram waitTime;
+
+  /// The time requests held the lock before releasing it or having it stolen.
+  final DurationHistogram holdTime;
+
+  /// Creates [LockMetrics] from its fields.
+  const LockMetrics({
+    required this.name,
+    required this.acquisitions,
+    required this.steals,
+    required this.unavailable,
+    required this.waitTime,
+    required this.holdTime,
+  });
+
+  @override
+  String toString() {
+    return '(name: $name, acquisitions: $acquisitions, steals: $steals, '
+        'unavailable: $unavailable)';
+  }
+}
+
+/// A histogram of durations, as part of [LockMetrics].
+final class DurationHistogram {
+  /// The upper bounds (inclusive) of the buckets in [counts].
+  final List<Duration> bounds;
+
+  /// How many durations fall into each bucket.
+  ///
+  /// This has one more entry than [bounds], counting durations exceeding the
+  /// last bound.
+  final List<int> counts;
+
+  /// The sum of all recorded durations.
+  final Duration sum;
+
+  /// Creates a [DurationHistogram] from its fields.
+  const DurationHistogram({
+    required this.bounds,
+    required this.counts,
+    required this.sum,
+  });
+
+  /// The amount of recorded durations.
+  int get count => counts.fold(0, (a, b) => a + b);
+}
+
+/// The kind of change described by a [LockEvent].
+enum LockEventType {
+  /// The request has been added to the queue of the lock.
+  enqueued,
+
+  /// The request has left the queue without being granted because the lock
+  /// file of a `crossProcess` request could not be locked.
+  failed,
+
+  /// The lock has been granted to the request.
+  granted,
+
+  /// The request has left the lock, either after holding it or while it was
+  /// still pending (for instance because it has been cancelled or has timed
+  /// out).
+  released,
+
+  /// The lock has been stolen from the request.
+  stolen,
+
+  /// The request was made with `ifAvailable` and the lock wasn't available.
+  unavailable,
+}
+
+/// Permits taken from a named semaphore through [LockManager.acquirePermits].
+abstract interface class SemaphorePermits {
+  /// The name of the semaphore.
+  String get name;
+
+  /// The amount of permits taken.
+  int get permits;
+
+  /// Returns the permits to the semaphore.
+  ///
+  /// Like locks, permits are also returned when the owning isolate exits or
+  /// when this object becomes unreachable.
+  void release();
+}
+
 /// A consistent snapshot of all requests being active at a point in time.
 final class LockManagerSnapshot {
   /// All requests that are currently pending (haven't been granted or
@@ -108,16 +497,81 @@ final class LockInfo implements LockDescription {
   @override
   final String name;
 
+  /// The [QueuePolicy] of the lock at the time of the snapshot.
+  final QueuePolicy queuePolicy;
+
+  /// For requests on a semaphore, the amount of permits taken or requested.
+  ///
+  /// This is null for lock requests.
+  final int? permits;
+
+  /// For requests on a semaphore, the total amount of permits of the
+  /// semaphore.
+  final int? maxPermits;
+
+  /// Whether this describes an intention lock on an ancestor of a lock held
+  /// with hierarchical names (see [LockManager.setHierarchySeparator]).
+  ///
+  /// Intention locks are IX locks if [exclusive] is true, and IS locks
+  /// otherwise.
+  final bool intention;
+
+  /// An id identifying the request across snapshots.
+  ///
+  /// Intention locks report the id of the request implying them. This is null
+  /// on the web.
+  final int? requestId;
+
+  /// When the request was made, or null on the web.
+  final DateTime? enqueuedAt;
+
+  /// When the request was granted, or null if it is pending or on the web.
+  final DateTime? grantedAt;
+
+  /// For pending requests, the position of the request in the queue of the
+  /// lock.
+  ///
+  /// With a [queuePolicy] other than [QueuePolicy.fifo], requests may not be
+  /// granted in this order. This is null for held requests and on the web.
+  final int? queuePosition;
+
+  /// Whether the request has been made with `steal` enabled.
+  final bool steal;
+
+  /// Whether the request has been made with `ifAvailable` enabled.
+  final bool ifAvailable;
+
   /// Creates a [LockInfo] description from its fields.
   const LockInfo({
     required this.clientId,
     required this.exclusive,
     required this.name,
+    this.queuePolicy = QueuePolicy.fifo,
+    this.permits,
+    this.maxPermits,
+    this.intention = false,
+    this.requestId,
+    this.enqueuedAt,
+    this.grantedAt,
+    this.queuePosition,
+    this.steal = false,
+    this.ifAvailable = false,
   });
 
   @override
   String toString() {
-    return '(name: $name, exclusive: $exclusive, client: $clientId)';
+    if (permits != null) {
+      return '(semaphore: $name, permits: $permits/$maxPermits, '
+          'client: $clientId)';
+    }
+
+    if (intention) {
+      return '(name: $name, intention: ${exclusive ? 'IX' : 'IS'}, '
+          'client: $clientId)';
+    }
+
+    return '(name: $name, exclusive: $exclusive, client: $clientId, '
+        'policy: ${queuePolicy.name})';
   }
 }
 
@@ -130,14 +584,59 @@ abstract interface class LockRequest extends LockDescription {
   /// Cancels this request.
   ///
   /// This will make [completion] complete with a [LockRequestCancelled]
-  /// exception.
+  /// exception. It is illegal to cancel requests after [completion] has
+  /// completed.
   ///
-  /// It is illegal to cancel locks requested with `ifAvailable: true` or with
-  /// `steal: true`. To cancel those, await [completion] and immediately call
-  /// [HeldLock.release].
+  /// Requests made with `ifAvailable: true` or with `steal: true` are resolved
+  /// right away, but the result is only reported asynchronously. Cancelling
+  /// them before [completion] has completed releases the lock again if it has
+  /// already been granted. Note that the lock stays stolen from its previous
+  /// holders in that case.
+  void cancel();
+}
+
+/// A pending request for multiple locks made through
+/// [LockManager.requestAll].
+abstract interface class MultiLockRequest {
+  /// The requested locks.
+  List<LockDescription> get locks;
+
+  /// A future completing once all [locks] are held.
+  ///
+  /// If the locks can't be requested together, this completes with a
+  /// [StateError].
+  Future<HeldLocks> get completion;
+
+  /// Cancels this request.
+  ///
+  /// This will make [completion] complete with a [LockRequestCancelled]
+  /// exception. It is illegal to cancel requests after [completion] has
+  /// completed.
   void cancel();
 }
 
+/// Locks obtained together through [LockManager.requestAll], which are held
+/// until [release] is called.
+abstract interface class HeldLocks {
+  /// The held locks.
+  List<LockDescription> get locks;
+
+  /// A future that completes once any of the [locks] gets stolen by another
+  /// request.
+  ///
+  /// The remaining locks are released when that happens.
+  Future<void> get stolen;
+
+  /// Whether any of the [locks] has been stolen by another request.
+  bool get isStolen;
+
+  /// Releases all locks.
+  ///
+  /// Like for [HeldLock.release], locks are also released automatically when
+  /// the owning isolate exits or when this object becomes unreachable.
+  void release();
+}
+
 /// A lock currently being held (until [release] is called).
 abstract interface class HeldLock extends LockDescription {
   /// A future that completes once this lock gets stolen by another request.
@@ -151,6 +650,53 @@ abstract interface class HeldLock extends LockDescription {
   /// For a future that completes once this request is stolen, see [stolen].
   bool get isStolen;
 
+  /// A future that completes once this lock has been released because its
+  /// lease has expired.
+  ///
+  /// This only happens for requests with a `lease` in [LockManager.request].
+  Future<void> get expired;
+
+  /// Renews the lease of this lock, so that it expires after the lease
+  /// duration has passed again.
+  ///
+  /// This does nothing for locks without a lease, or if the lease has already
+  /// expired.
+  void renew();
+
+  /// The amount of requests of the same owner holding this lock, including
+  /// this one, at the time it was granted.
+  ///
+  /// This is only larger than one for `reentrant` requests in
+  /// [LockManager.request].
+  int get holdCount;
+
+  /// Atomically upgrades this shared lock to an exclusive lock.
+  ///
+  /// The returned future completes once all other holders have released the
+  /// lock. Pending requests are not granted in the meantime, so no other
+  /// exclusive holder can get in between. The future completes with a
+  /// [StateError] if another holder is upgrading the lock at the same time,
+  /// or if this lock is stolen or released before the upgrade completes.
+  ///
+  /// Upgrades are only supported on native platforms, and not for locks
+  /// requested with `crossProcess: true`.
+  Future<void> upgrade();
+
+  /// Atomically releases this exclusive lock and waits for the [condition] to
+  /// be notified with [LockManager.notifyCondition].
+  ///
+  /// After being notified, the request is queued again and the returned
+  /// future completes once it holds the lock again. This is only supported on
+  /// native platforms, and only for locks held exclusively.
+  Future<void> wait(String condition);
+
+  /// Turns this exclusive lock into a shared lock without releasing it.
+  ///
+  /// Pending shared requests may be granted afterwards. For locks requested
+  /// with `crossProcess: true`, other processes remain excluded until the lock
+  /// is released. This is only supported on native platforms.
+  void downgrade();
+
   /// Releases the lock, allowing another request to progress.
   ///
   /// Both on the web and on native platforms, locks are automatically released
@@ -172,3 +718,25 @@ final class LockRequestCancelled implements Exception {
     return 'Lock request was cancelled';
   }
 }
+
+/// An exception thrown by [LockRequest.completion] for requests made with
+/// `detectDeadlocks: true` when waiting for them would deadlock.
+final class DeadlockDetected implements Exception {
+  /// The clients waiting on each other.
+  ///
+  /// Each entry describes a client and the lock it is waiting for. That lock
+  /// is held or requested by the client of the next entry, with the last
+  /// entry wrapping around to the first one. The first entry is the request
+  /// that has been failed.
+  final List<({String lock, String clientId})> cycle;
+
+  const DeadlockDetected(this.cycle);
+
+  @override
+  String toString() {
+    final description = cycle
+        .map((e) => '${e.clientId} waits for ${e.lock}')
+        .join(', ');
+    return 'Deadlock detected: $description';
+  }
+}
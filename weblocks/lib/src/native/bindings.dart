@@ -5,22 +5,55 @@ library;
 
 import 'dart:ffi';
 
-@Native<Pointer<Void> Function(Size, Pointer<Uint8>, Pointer<Void>)>(
-  isLeaf: true,
-)
+@Native<Pointer<Void> Function(Size, Pointer<Uint8>)>(isLeaf: true)
+external Pointer<Void> pkg_weblocks_manager_new(
+  int length,
+  Pointer<Uint8> name,
+);
+
+@Native<Void Function(Pointer<Void>)>(isLeaf: true)
+external void pkg_weblocks_manager_free(Pointer<Void> manager);
+
+@Native<
+  Pointer<Void> Function(Size, Pointer<Uint8>, Pointer<Void>, Pointer<Void>)
+>(isLeaf: true)
 external Pointer<Void> pkg_weblocks_client(
   int length,
   Pointer<Uint8> name,
   Pointer<Void> dartDL,
+  Pointer<Void> manager,
 );
 
 @Native<Void Function(Pointer<Void>)>(isLeaf: true)
 external void pkg_weblocks_free_client(Pointer<Void> client);
 
 @Native<
-  Pointer<Void> Function(Size, Pointer<Uint8>, Pointer<Void>, Uint32, Int64)
+  Pointer<Void> Function(
+    Size,
+    Pointer<Uint8>,
+    Pointer<Void>,
+    Uint32,
+    Uint64,
+    Int64,
+    Int64,
+    Int64,
+  )
 >()
 external Pointer<Void> pkg_weblocks_obtain(
+  int length,
+  Pointer<Uint8> name,
+  Pointer<Void> client,
+  int flags,
+  int owner,
+  int timeoutMicros,
+  int leaseMicros,
+  int port,
+);
+
+@Native<
+  Pointer<Void> Function(Size, Pointer<Uint8>, Pointer<Void>, Uint32, Int64)
+>()
+external Pointer<Void> pkg_weblocks_try_obtain(
   int length,
   Pointer<Uint8> name,
   Pointer<Void> client,
@@ -31,16 +64,159 @@ external Pointer<Void> pkg_weblocks_obtain(
 @Native<Void Function(Pointer<Void>)>()
 external void pkg_weblocks_unlock(Pointer<Void> ptr);
 
+@Native<
+  Pointer<Void> Function(
+    Pointer<Void>,
+    Size,
+    Pointer<Uint8>,
+    Pointer<IntPtr>,
+    Pointer<Uint32>,
+    Int64,
+  )
+>()
+external Pointer<Void> pkg_weblocks_obtain_all(
+  Pointer<Void> client,
+  int count,
+  Pointer<Uint8> names,
+  Pointer<IntPtr> nameLengths,
+  Pointer<Uint32> flags,
+  int port,
+);
+
+@Native<Void Function(Pointer<Void>)>()
+external void pkg_weblocks_unlock_all(Pointer<Void> ptr);
+
+@Native<Void Function(Pointer<Void>)>()
+external void pkg_weblocks_abort(Pointer<Void> ptr);
+
+@Native<Void Function(Pointer<Void>)>()
+external void pkg_weblocks_renew(Pointer<Void> ptr);
+
+@Native<Void Function(Pointer<Void>)>()
+external void pkg_weblocks_upgrade(Pointer<Void> ptr);
+
+@Native<Void Function(Pointer<Void>)>()
+external void pkg_weblocks_downgrade(Pointer<Void> ptr);
+
+@Native<
+  Pointer<Void> Function(
+    Size,
+    Pointer<Uint8>,
+    Pointer<Void>,
+    Uint32,
+    Uint32,
+    Int64,
+  )
+>()
+external Pointer<Void> pkg_weblocks_acquire_permits(
+  int length,
+  Pointer<Uint8> name,
+  Pointer<Void> client,
+  int maxPermits,
+  int permits,
+  int port,
+);
+
+@Native<Void Function(Pointer<Void>)>()
+external void pkg_weblocks_release_permits(Pointer<Void> ptr);
+
+@Native<Void Function(Pointer<Void>, Size, Pointer<Uint8>)>()
+external void pkg_weblocks_wait(
+  Pointer<Void> ptr,
+  int conditionLength,
+  Pointer<Uint8> condition,
+);
+
+@Native<
+  Void Function(
+    Pointer<Void>,
+    Size,
+    Pointer<Uint8>,
+    Size,
+    Pointer<Uint8>,
+    Bool,
+  )
+>()
+external void pkg_weblocks_notify(
+  Pointer<Void> client,
+  int nameLength,
+  Pointer<Uint8> name,
+  int conditionLength,
+  Pointer<Uint8> condition,
+  bool all,
+);
+
+@Native<Void Function(Pointer<Void>, Size, Pointer<Uint8>, Uint32)>()
+external void pkg_weblocks_set_queue_policy(
+  Pointer<Void> client,
+  int length,
+  Pointer<Uint8> name,
+  int policy,
+);
+
+@Native<Void Function(Pointer<Void>, Size, Pointer<Uint8>)>()
+external void pkg_weblocks_set_hierarchy_separator(
+  Pointer<Void> client,
+  int length,
+  Pointer<Uint8> separator,
+);
+
+@Native<Void Function(Size, Pointer<Uint8>)>(isLeaf: true)
+external void pkg_weblocks_set_lock_directory(int length, Pointer<Uint8> path);
+
+@Native<
+  Pointer<Void> Function(Pointer<Void>, Size, Pointer<Uint8>, Bool, Int64)
+>()
+external Pointer<Void> pkg_weblocks_watch(
+  Pointer<Void> client,
+  int length,
+  Pointer<Uint8> name,
+  bool prefix,
+  int port,
+);
+
+@Native<Void Function(Pointer<Void>)>()
+external void pkg_weblocks_unwatch(Pointer<Void> watcher);
+
+@Native<Void Function(Pointer<Void>)>()
+external void pkg_weblocks_client_shutdown(Pointer<Void> client);
+
+@Native<Void Function(Pointer<Void>, Uint64)>(isLeaf: true)
+external void pkg_weblocks_metrics(Pointer<Void> client, int port);
+
+@Native<Void Function(Pointer<Void>)>(isLeaf: true)
+external void pkg_weblocks_reset_metrics(Pointer<Void> client);
+
+@Native<Void Function(Pointer<Void>, Uint32, Uint32, Uint64)>(isLeaf: true)
+external void pkg_weblocks_openmetrics(
+  Pointer<Void> client,
+  int maxLockNames,
+  int maxChannelNames,
+  int port,
+);
+
 @Native<Void Function(Pointer<Void>, Uint64)>(isLeaf: true)
 external void pkg_weblocks_snapshot(Pointer<Void> client, int port);
 
-@Native<Pointer<Void> Function(Size, Pointer<Uint8>, Pointer<Void>, Int64)>(
+@Native<Void Function(Pointer<Void>, Uint32, Size, Pointer<Uint8>, Uint64)>(
   isLeaf: true,
 )
+external void pkg_weblocks_snapshot_filtered(
+  Pointer<Void> client,
+  int kind,
+  int length,
+  Pointer<Uint8> filter,
+  int port,
+);
+
+@Native<
+  Pointer<Void> Function(Size, Pointer<Uint8>, Pointer<Void>, Uint32, Int64)
+>(isLeaf: true)
 external Pointer<Void> pkg_weblocks_broadcast_channel_new(
   int nameLength,
   Pointer<Uint8> name,
   Pointer<Void> client,
+  int flags,
   int port,
 );
 
@@ -56,6 +232,11 @@ external Pointer<Void> pkg_weblocks_broadcast_channel_send(
 const FLAG_SHARED = 0x01;
 const FLAG_STEAL = 0x02;
 const FLAG_IF_AVAILABLE = 0x04;
+const FLAG_CROSS_PROCESS = 0x08;
+const FLAG_DETECT_DEADLOCKS = 0x10;
+const FLAG_REENTRANT = 0x20;
+
+const CHANNEL_FLAG_CROSS_PROCESS = 0x01;
 
 final clientFinalizer = NativeFinalizer(
   Native.addressOf(pkg_weblocks_free_client),
@@ -63,6 +244,18 @@ final clientFinalizer = NativeFinalizer(
 
 final requestFinalizer = NativeFinalizer(Native.addressOf(pkg_weblocks_unlock));
 
+final groupFinalizer = NativeFinalizer(
+  Native.addressOf(pkg_weblocks_unlock_all),
+);
+
+final permitsFinalizer = NativeFinalizer(
+  Native.addressOf(pkg_weblocks_release_permits),
+);
+
+final watcherFinalizer = NativeFinalizer(
+  Native.addressOf(pkg_weblocks_unwatch),
+);
+
 final channelFinalizer = NativeFinalizer(
   Native.addressOf(pkg_weblocks_broadcast_channel_free),
 );
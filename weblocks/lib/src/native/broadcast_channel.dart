@@ -33,7 +33,11 @@ final class NativeBroadcastChannel extends Stream<String>
     });
   }
 
-  factory NativeBroadcastChannel(Pointer<Void> client, String name) {
+  factory NativeBroadcastChannel(
+    Pointer<Void> client,
+    String name, {
+    bool crossProcess = false,
+  }) {
     final receive = ReceivePort('Receive for broadcast channel $name');
 
     return using((alloc) {
@@ -44,6 +48,7 @@ final class NativeBroadcastChannel extends Stream<String>
         encodedName.length,
         bytes,
         client,
+        crossProcess ? CHANNEL_FLAG_CROSS_PROCESS : 0,
         receive.sendPort.nativePort,
       );
       return NativeBroadcastChannel._(name, channel, receive);
@@ -0,0 +1,147 @@
+import 'dart:async';
+import 'dart:convert';
+import 'dart:ffi';
+import 'dart:isolate';
+
+import 'package:ffi/ffi.dart';
+
+import '../interface.dart';
+import 'bindings.dart';
+
+/// A native request for multiple locks, which are granted and released
+/// together.
+final class NativeLockGroup implements Finalizable {
+  final Pointer<Void> _group;
+  final List<LockDescription> locks;
+
+  final Completer<void> _granted = Completer();
+  final Completer<void> _stolen = Completer();
+  late final StreamSubscription<void> _portSubscription;
+
+  bool _isClosed = false;
+
+  NativeLockGroup._(this._group, this.locks, ReceivePort port) {
+    groupFinalizer.attach(this, _group, detach: this);
+
+    _portSubscription = port.listen((msg) {
+      final type = (msg as List)[0] as String;
+      switch (type) {
+        case 'locked':
+          _granted.complete();
+        case 'stolen':
+          _stolen.complete();
+          close();
+        case 'shutdown':
+          if (!_granted.isCompleted) {
+            _granted.completeError(const LockRequestCancelled());
+          }
+          close();
+        case 'error':
+          _granted.completeError(StateError(msg[1] as String));
+          close();
+        default:
+          throw StateError('unknown message from native implementation: $msg');
+      }
+    });
+  }
+
+  factory NativeLockGroup(
+    Pointer<Void> client,
+    List<({String name, bool exclusive})> locks,
+  ) {
+    final port = ReceivePort('obtaining ${locks.length} locks');
+    final encoded = [for (final lock in locks) utf8.encode(lock.name)];
+    final totalLength = encoded.fold(0, (sum, name) => sum + name.length);
+
+    final group = using((alloc) {
+      final names = alloc<Uint8>(totalLength);
+      final lengths = alloc<IntPtr>(locks.length);
+      final flags = alloc<Uint32>(locks.length);
+
+      var offset = 0;
+      for (final (i, name) in encoded.indexed) {
+        names.asTypedList(totalLength).setAll(offset, name);
+        offset += name.length;
+        lengths[i] = name.length;
+        flags[i] = locks[i].exclusive ? 0 : FLAG_SHARED;
+      }
+
+      return pkg_weblocks_obtain_all(
+        client,
+        locks.length,
+        names,
+        lengths,
+        flags,
+        port.sendPort.nativePort,
+      );
+    });
+
+    return NativeLockGroup._(group, [
+      for (final lock in locks) _RequestedLock(lock.name, lock.exclusive),
+    ], port);
+  }
+
+  void close() {
+    if (!_isClosed) {
+      _isClosed = true;
+      _portSubscription.cancel();
+
+      groupFinalizer.detach(this);
+      pkg_weblocks_unlock_all(_group);
+    }
+  }
+}
+
+final class NativeMultiLockRequest implements MultiLockRequest {
+  final NativeLockGroup _group;
+
+  NativeMultiLockRequest(this._group);
+
+  @override
+  List<LockDescription> get locks => _group.locks;
+
+  @override
+  Future<HeldLocks> get completion async {
+    await _group._granted.future;
+    return _NativeHeldLocks(_group);
+  }
+
+  @override
+  void cancel() {
+    if (_group._granted.isCompleted) {
+      throw StateError('Cannot cancel requests that have already been granted');
+    }
+
+    _group._granted.completeError(const LockRequestCancelled());
+    _group.close();
+  }
+}
+
+final class _NativeHeldLocks implements HeldLocks {
+  final NativeLockGroup _group;
+
+  _NativeHeldLocks(this._group);
+
+  @override
+  List<LockDescription> get locks => _group.locks;
+
+  @override
+  Future<void> get stolen => _group._stolen.future;
+
+  @override
+  bool get isStolen => _group._stolen.isCompleted;
+
+  @override
+  void release() {
+    _group.close();
+  }
+}
+
+final class _RequestedLock implements LockDescription {
+  @override
+  final String name;
+  @override
+  final bool exclusive;
+
+  _RequestedLock(this.name, this.exclusive);
+}
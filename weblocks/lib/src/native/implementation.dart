@@ -3,6 +3,7 @@ library;
 import 'dart:async';
 import 'dart:convert';
 import 'dart:ffi';
+import 'dart:io';
 import 'dart:isolate';
 import 'dart:typed_data';
 
@@ -11,23 +12,32 @@ import 'package:ffi/ffi.dart';
 import '../interface.dart';
 import 'bindings.dart';
 import 'broadcast_channel.dart';
+import 'group.dart';
+import 'semaphore.dart';
+import 'watcher.dart';
 
 final class NativeLockManager implements LockManager, Finalizable {
+  final String _clientName;
   final Pointer<Void> _client;
 
-  NativeLockManager._(this._client) {
+  NativeLockManager._(this._clientName, this._client) {
     clientFinalizer.attach(this, _client);
   }
 
-  factory NativeLockManager(String clientName) {
+  /// Creates a client called [clientName].
+  ///
+  /// The client is bound to the native [manager] returned by
+  /// `pkg_weblocks_manager_new`, or to the default manager if it's null.
+  factory NativeLockManager(String clientName, {Pointer<Void>? manager}) {
     final encoded = utf8.encode(clientName);
     return using((alloc) {
       final client = pkg_weblocks_client(
         encoded.length,
         alloc.allocBytes(encoded),
         NativeApi.initializeApiDLData,
+        manager ?? nullptr,
       );
-      return NativeLockManager._(client);
+      return NativeLockManager._(clientName, client);
     });
   }
 
@@ -45,6 +55,12 @@ final class NativeLockManager implements LockManager, Finalizable {
     bool exclusive = true,
     bool ifAvailable = false,
     bool steal = false,
+    bool crossProcess = false,
+    bool detectDeadlocks = false,
+    bool reentrant = false,
+    int? owner,
+    Duration? timeout,
+    Duration? lease,
   }) {
     // Prevent things forbidden on the web for consistency
     if (name.startsWith('-')) {
@@ -55,6 +71,13 @@ final class NativeLockManager implements LockManager, Finalizable {
         'When steal is enable, exclusive must also be true and ifAvailable must be disabled.',
       );
     }
+    if (timeout != null && (steal || ifAvailable)) {
+      throw ArgumentError.value(
+        timeout,
+        'timeout',
+        'Timeouts are not supported for steal or ifAvailable requests.',
+      );
+    }
 
     final port = ReceivePort('obtaining lock $name');
     final encoded = utf8.encode(name);
@@ -69,6 +92,15 @@ final class NativeLockManager implements LockManager, Finalizable {
     if (steal) {
       flags |= FLAG_STEAL;
     }
+    if (crossProcess) {
+      flags |= FLAG_CROSS_PROCESS;
+    }
+    if (detectDeadlocks) {
+      flags |= FLAG_DETECT_DEADLOCKS;
+    }
+    if (reentrant) {
+      flags |= FLAG_REENTRANT;
+    }
 
     final request = using((alloc) {
       return pkg_weblocks_obtain(
@@ -76,6 +108,9 @@ final class NativeLockManager implements LockManager, Finalizable {
         alloc.allocBytes(encoded),
         _client,
         flags,
+        owner ?? 0,
+        timeout?.inMicroseconds ?? -1,
+        lease?.inMicroseconds ?? -1,
         port.sendPort.nativePort,
       );
     });
@@ -84,28 +119,150 @@ final class NativeLockManager implements LockManager, Finalizable {
       request: request,
       name: name,
       exclusive: exclusive,
+      timeout: timeout,
+      port: port,
+    );
+    return _NativeLockRequest(internalRequest);
+  }
+
+  @override
+  HeldLock? tryRequest(
+    String name, {
+    bool exclusive = true,
+    bool crossProcess = false,
+    bool observeStolen = false,
+  }) {
+    if (name.startsWith('-')) {
+      throw ArgumentError.value(name, 'name', 'Must not start with a hyphen');
+    }
+
+    final port = observeStolen ? ReceivePort('holding lock $name') : null;
+    final encoded = utf8.encode(name);
+    final request = using((alloc) {
+      return pkg_weblocks_try_obtain(
+        encoded.length,
+        alloc.allocBytes(encoded),
+        _client,
+        (exclusive ? 0 : FLAG_SHARED) | (crossProcess ? FLAG_CROSS_PROCESS : 0),
+        port?.sendPort.nativePort ?? 0,
+      );
+    });
+
+    if (request == nullptr) {
+      port?.close();
+      return null;
+    }
+
+    final internalRequest = _InternalLockRequest(
+      request: request,
+      name: name,
+      exclusive: exclusive,
+      timeout: null,
       port: port,
     );
-    return _NativeLockRequest(internalRequest, ifAvailable || steal);
+    internalRequest._granted.complete();
+    return _NativeHeldLock(internalRequest);
+  }
+
+  @override
+  MultiLockRequest requestAll(Iterable<({String name, bool exclusive})> locks) {
+    final list = locks.toList();
+    if (list.isEmpty) {
+      throw ArgumentError.value(
+        list,
+        'locks',
+        'At least one lock must be requested',
+      );
+    }
+    final names = <String>{};
+    for (final lock in list) {
+      if (lock.name.startsWith('-')) {
+        throw ArgumentError.value(
+          lock.name,
+          'locks',
+          'Names must not start with a hyphen',
+        );
+      }
+      if (!names.add(lock.name)) {
+        throw ArgumentError.value(
+          lock.name,
+          'locks',
+          'Locks may only be requested once',
+        );
+      }
+    }
+
+    return NativeMultiLockRequest(NativeLockGroup(_client, list));
   }
 
   @override
-  Future<LockManagerSnapshot> query() async {
+  Future<LockManagerSnapshot> query({
+    String? name,
+    String? prefix,
+    String? clientId,
+  }) async {
+    if ([name, prefix, clientId].nonNulls.length > 1) {
+      throw ArgumentError('Only one of name, prefix and clientId may be set.');
+    }
     final port = ReceivePort('LockManager.query()');
-    pkg_weblocks_snapshot(_client, port.sendPort.nativePort);
+    final (kind, filter) = switch ((name, prefix, clientId)) {
+      (final name?, _, _) => (0, name),
+      (_, final prefix?, _) => (1, prefix),
+      (_, _, final clientId?) => (2, clientId),
+      _ => (-1, ''),
+    };
+
+    if (kind == -1) {
+      pkg_weblocks_snapshot(_client, port.sendPort.nativePort);
+    } else {
+      final encoded = utf8.encode(filter);
+      using((alloc) {
+        pkg_weblocks_snapshot_filtered(
+          _client,
+          kind,
+          encoded.length,
+          alloc.allocBytes(encoded),
+          port.sendPort.nativePort,
+        );
+      });
+    }
 
     final msg = (await port.first) as List;
     final held = <LockInfo>[];
     final pending = <LockInfo>[];
 
-    for (var i = 0; i < msg.length; i += 4) {
-      final name = msg[i] as String;
-      final clientId = msg[i + 1] as String;
-      final exclusive = msg[i + 2] as bool;
-      final isHeld = msg[i + 3] as bool;
+    // The first value is the amount of values per request. Newer fields are
+    // appended, so fields we don't know about are skipped.
+    final fields = msg[0] as int;
+    for (var i = 1; i < msg.length; i += fields) {
+      Object? field(int index) => index < fields ? msg[i + index] : null;
+      DateTime? time(int index) => switch (field(index)) {
+        final int micros => DateTime.fromMicrosecondsSinceEpoch(micros),
+        _ => null,
+      };
+
+      final name = field(0) as String;
+      final clientId = field(1) as String;
+      final exclusive = field(2) as bool;
+      final isHeld = field(3) as bool;
+      final policy = _queuePolicyNames[field(4) as String]!;
 
       (isHeld ? held : pending).add(
-        LockInfo(name: name, clientId: clientId, exclusive: exclusive),
+        LockInfo(
+          name: name,
+          clientId: clientId,
+          exclusive: exclusive,
+          queuePolicy: policy,
+          permits: field(5) as int?,
+          maxPermits: field(6) as int?,
+          intention: field(7) as bool? ?? false,
+          requestId: field(8) as int?,
+          enqueuedAt: time(9),
+          grantedAt: time(10),
+          queuePosition: field(11) as int?,
+          steal: field(12) as bool? ?? false,
+          ifAvailable: field(13) as bool? ?? false,
+        ),
       );
     }
 
@@ -113,51 +270,382 @@ final class NativeLockManager implements LockManager, Finalizable {
   }
 
   @override
-  BroadcastChannel broadcastChannel(String name) {
-    return NativeBroadcastChannel(_client, name);
+  void setQueuePolicy(String name, QueuePolicy policy) {
+    final encoded = utf8.encode(name);
+    using((alloc) {
+      pkg_weblocks_set_queue_policy(
+        _client,
+        encoded.length,
+        alloc.allocBytes(encoded),
+        policy.index,
+      );
+    });
+  }
+
+  @override
+  void setHierarchySeparator(String? separator) {
+    final encoded = utf8.encode(separator ?? '');
+    using((alloc) {
+      pkg_weblocks_set_hierarchy_separator(
+        _client,
+        encoded.length,
+        alloc.allocBytes(encoded),
+      );
+    });
+  }
+
+  @override
+  void setLockDirectory(String path) {
+    final encoded = utf8.encode(path);
+    using((alloc) {
+      pkg_weblocks_set_lock_directory(
+        encoded.length,
+        alloc.allocBytes(encoded),
+      );
+    });
+  }
+
+  @override
+  LockManager namespace(String name) {
+    final encoded = utf8.encode(name);
+    final manager = using((alloc) {
+      return pkg_weblocks_manager_new(
+        encoded.length,
+        alloc.allocBytes(encoded),
+      );
+    });
+
+    // The client keeps the manager alive, so we don't need the handle anymore.
+    final namespaced = NativeLockManager(_clientName, manager: manager);
+    pkg_weblocks_manager_free(manager);
+    return namespaced;
+  }
+
+  @override
+  void notifyCondition(String name, String condition, {bool all = false}) {
+    final encodedName = utf8.encode(name);
+    final encodedCondition = utf8.encode(condition);
+    using((alloc) {
+      pkg_weblocks_notify(
+        _client,
+        encodedName.length,
+        alloc.allocBytes(encodedName),
+        encodedCondition.length,
+        alloc.allocBytes(encodedCondition),
+        all,
+      );
+    });
+  }
+
+  @override
+  Stream<LockEvent> watch(String name, {bool prefix = false}) {
+    return Stream.multi((controller) {
+      final watcher = NativeLockWatcher(_client, name, prefix, controller.add);
+      controller.onCancel = watcher.close;
+    });
+  }
+
+  @override
+  Future<List<LockMetrics>> metrics() async {
+    final port = ReceivePort('LockManager.metrics()');
+    pkg_weblocks_metrics(_client, port.sendPort.nativePort);
+    final msg = (await port.first) as List;
+
+    final boundCount = msg[0] as int;
+    final bounds = [
+      for (final bound in msg.sublist(1, boundCount + 1))
+        Duration(microseconds: bound as int),
+    ];
+    final histogramLength = boundCount + 3;
+
+    DurationHistogram histogram(int start) {
+      return DurationHistogram(
+        bounds: bounds,
+        sum: Duration(microseconds: msg[start + 1] as int),
+        counts: msg.sublist(start + 2, start + histogramLength).cast(),
+      );
+    }
+
+    return [
+      for (
+        var i = boundCount + 1;
+        i < msg.length;
+        i += 4 + 2 * histogramLength
+      )
+        LockMetrics(
+          name: msg[i] as String?,
+          acquisitions: msg[i + 1] as int,
+          steals: msg[i + 2] as int,
+          unavailable: msg[i + 3] as int,
+          waitTime: histogram(i + 4),
+          holdTime: histogram(i + 4 + histogramLength),
+        ),
+    ];
+  }
+
+  @override
+  void resetMetrics() {
+    pkg_weblocks_reset_metrics(_client);
+  }
+
+  @override
+  void shutdown() {
+    pkg_weblocks_client_shutdown(_client);
+  }
+
+  @override
+  Future<String> openMetrics({
+    int maxLockNames = 100,
+    int maxChannelNames = 100,
+  }) async {
+    RangeError.checkValueInInterval(
+      maxLockNames,
+      0,
+      0xFFFFFFFF,
+      'maxLockNames',
+    );
+    RangeError.checkValueInInterval(
+      maxChannelNames,
+      0,
+      0xFFFFFFFF,
+      'maxChannelNames',
+    );
+
+    final port = ReceivePort('LockManager.openMetrics()');
+    pkg_weblocks_openmetrics(
+      _client,
+      maxLockNames,
+      maxChannelNames,
+      port.sendPort.nativePort,
+    );
+    return (await port.first) as String;
+  }
+
+  @override
+  Future<SemaphorePermits> acquirePermits(
+    String name, {
+    required int maxPermits,
+    int permits = 1,
+  }) async {
+    if (maxPermits < 1 || maxPermits > 0xFFFFFFFF) {
+      throw RangeError.range(maxPermits, 1, 0xFFFFFFFF, 'maxPermits');
+    }
+    RangeError.checkValueInInterval(permits, 1, maxPermits, 'permits');
+
+    return await NativeSemaphorePermits.acquire(
+      _client,
+      name,
+      maxPermits: maxPermits,
+      permits: permits,
+    );
+  }
+
+  @override
+  BroadcastChannel broadcastChannel(String name, {bool crossProcess = false}) {
+    return NativeBroadcastChannel(_client, name, crossProcess: crossProcess);
   }
 }
 
+const _queuePolicyNames = {
+  'fifo': QueuePolicy.fifo,
+  'reader-preferring': QueuePolicy.readerPreferring,
+  'writer-preferring': QueuePolicy.writerPreferring,
+  'phase-fair': QueuePolicy.phaseFair,
+};
+
 final class _InternalLockRequest implements Finalizable {
   final Pointer<Void> request;
   final String name;
   final bool exclusive;
+  final Duration? timeout;
 
   final Completer<void> _granted = Completer();
   final Completer<void> _stolen = Completer();
+  final Completer<void> _expired = Completer();
+  Completer<void>? _upgrade;
+  Completer<void>? _wait;
+
+  /// Whether the lock is currently held exclusively, which can change after
+  /// the lock has been upgraded or downgraded.
+  late bool holdsExclusive = exclusive;
 
   var closed = false;
+  var aborted = false;
   var wasUnavailable = false;
+  var holdCount = 1;
   StreamSubscription? receivePortSubscription;
 
   _InternalLockRequest({
     required this.request,
     required this.name,
     required this.exclusive,
-    required ReceivePort port,
+    required this.timeout,
+    required ReceivePort? port,
   }) {
     requestFinalizer.attach(this, request, detach: this);
 
-    receivePortSubscription = port.listen((msg) {
-      switch (msg[0] as String) {
+    receivePortSubscription = port?.listen((msg) {
+      final type = msg[0] as String;
+      if (aborted) {
+        // We've already completed the request as cancelled, we're only waiting
+        // for the native side to confirm that the request is gone.
+        if (type != 'locked') {
+          close();
+        }
+        return;
+      }
+
+      switch (type) {
         case 'stolen':
           _stolen.complete();
+          _failUpgrade('The lock has been stolen');
           close();
         case 'unavailable':
           wasUnavailable = true;
           _granted.complete();
           close();
+        case 'locked' when _wait != null:
+          // Granted again after waiting on a condition.
+          _wait!.complete();
+          _wait = null;
         case 'locked':
+          if (msg.length > 1) {
+            holdCount = msg[1] as int;
+          }
           _granted.complete();
+        case 'shutdown':
+          if (!_granted.isCompleted) {
+            _granted.completeError(const LockRequestCancelled());
+          }
+          close();
+        case 'expired':
+          _expired.complete();
+          _failUpgrade('The lock has expired');
+          close();
+        case 'upgraded':
+          holdsExclusive = true;
+          _upgrade?.complete();
+          _upgrade = null;
+        case 'upgrade-failed':
+          _failUpgrade(msg[1] as String);
+        case 'woken':
+          // The request has been queued again, we'll get a `locked` message
+          // once it holds the lock.
+          break;
+        case 'wait-failed':
+          _failWait('Only exclusive locks can wait on conditions');
+        case 'timeout':
+          _granted.completeError(
+            TimeoutException('Lock $name could not be obtained', timeout),
+          );
+          close();
+        case 'deadlock':
+          _granted.completeError(
+            DeadlockDetected([
+              for (var i = 1; i < msg.length; i += 2)
+                (lock: msg[i] as String, clientId: msg[i + 1] as String),
+            ]),
+          );
+          close();
+        case 'error':
+          _granted.completeError(
+            FileSystemException(
+              'Could not obtain cross-process lock: ${msg[1]}',
+            ),
+          );
+          close();
         default:
           throw StateError('unknown message from native implementation: $msg');
       }
     });
   }
 
+  /// Aborts this request, the native side will respond with an `aborted`
+  /// message once the request has been removed.
+  void abort() {
+    if (!closed && !aborted) {
+      aborted = true;
+      pkg_weblocks_abort(request);
+    }
+  }
+
+  Future<void> upgrade() {
+    if (closed) {
+      throw StateError('The lock has been released');
+    }
+    _checkReceivesEvents();
+
+    final completer = _upgrade;
+    if (completer != null) {
+      return completer.future;
+    }
+
+    final upgrade = _upgrade = Completer();
+    pkg_weblocks_upgrade(request);
+    return upgrade.future;
+  }
+
+  void downgrade() {
+    if (closed) {
+      throw StateError('The lock has been released');
+    }
+    if (_upgrade != null) {
+      throw StateError('Cannot downgrade while an upgrade is pending');
+    }
+
+    holdsExclusive = false;
+    pkg_weblocks_downgrade(request);
+  }
+
+  Future<void> wait(String condition) {
+    if (closed) {
+      throw StateError('The lock has been released');
+    }
+    if (_wait != null || _upgrade != null) {
+      throw StateError('Cannot wait while an upgrade or wait is pending');
+    }
+    if (!holdsExclusive) {
+      throw StateError('Only exclusive locks can wait on conditions');
+    }
+    _checkReceivesEvents();
+
+    final wait = _wait = Completer();
+    final encoded = utf8.encode(condition);
+    using((alloc) {
+      pkg_weblocks_wait(request, encoded.length, alloc.allocBytes(encoded));
+    });
+    return wait.future;
+  }
+
+  void renew() {
+    if (!closed) {
+      pkg_weblocks_renew(request);
+    }
+  }
+
+  void _checkReceivesEvents() {
+    if (receivePortSubscription == null) {
+      throw StateError(
+        'Requires a lock obtained with tryRequest(observeStolen: true)',
+      );
+    }
+  }
+
+  void _failWait(String reason) {
+    _wait?.completeError(StateError(reason));
+    _wait = null;
+  }
+
+  void _failUpgrade(String reason) {
+    _upgrade?.completeError(StateError(reason));
+    _upgrade = null;
+  }
+
   void close() {
     if (!closed) {
       closed = true;
+      _failUpgrade('The lock has been released');
+      _failWait('The lock has been released');
       requestFinalizer.detach(this);
       pkg_weblocks_unlock(request);
       receivePortSubscription?.cancel();
@@ -167,24 +655,17 @@ final class _InternalLockRequest implements Finalizable {
 
 final class _NativeLockRequest implements LockRequest {
   final _InternalLockRequest _request;
-  final bool isIfAvailableOrSteal;
 
-  _NativeLockRequest(this._request, this.isIfAvailableOrSteal);
+  _NativeLockRequest(this._request);
 
   @override
   void cancel() {
-    if (isIfAvailableOrSteal) {
-      throw StateError('Cannot cancel steal or ifAvailable requests');
-    }
     if (_request._granted.isCompleted) {
       throw StateError('Cannot cancel requests that have already been granted');
     }
 
-    if (!_request._granted.isCompleted) {
-      _request._granted.completeError(const LockRequestCancelled());
-    }
-
-    _request.close();
+    _request._granted.completeError(const LockRequestCancelled());
+    _request.abort();
   }
 
   @override
@@ -212,7 +693,25 @@ final class _NativeHeldLock implements HeldLock {
   bool get isStolen => _request._stolen.isCompleted;
 
   @override
-  bool get exclusive => _request.exclusive;
+  int get holdCount => _request.holdCount;
+
+  @override
+  Future<void> get expired => _request._expired.future;
+
+  @override
+  void renew() => _request.renew();
+
+  @override
+  Future<void> upgrade() => _request.upgrade();
+
+  @override
+  void downgrade() => _request.downgrade();
+
+  @override
+  Future<void> wait(String condition) => _request.wait(condition);
+
+  @override
+  bool get exclusive => _request.holdsExclusive;
 
   @override
   String get name => _request.name;
@@ -0,0 +1,64 @@
+import 'dart:convert';
+import 'dart:ffi';
+import 'dart:isolate';
+
+import 'package:ffi/ffi.dart';
+
+import '../interface.dart';
+import 'bindings.dart';
+import 'implementation.dart';
+
+final class NativeSemaphorePermits implements Finalizable, SemaphorePermits {
+  @override
+  final String name;
+  @override
+  final int permits;
+  final Pointer<Void> _request;
+
+  bool _isReleased = false;
+
+  NativeSemaphorePermits._(this.name, this.permits, this._request) {
+    permitsFinalizer.attach(this, _request, detach: this);
+  }
+
+  static Future<SemaphorePermits> acquire(
+    Pointer<Void> client,
+    String name, {
+    required int maxPermits,
+    required int permits,
+  }) async {
+    final port = ReceivePort('acquiring permits of $name');
+    final encoded = utf8.encode(name);
+
+    final request = using((alloc) {
+      return pkg_weblocks_acquire_permits(
+        encoded.length,
+        alloc.allocBytes(encoded),
+        client,
+        maxPermits,
+        permits,
+        port.sendPort.nativePort,
+      );
+    });
+
+    // Attach the finalizer before waiting, so that the permits are returned
+    // if this isolate stops before using them.
+    final result = NativeSemaphorePermits._(name, permits, request);
+    final msg = (await port.first) as List;
+    if (msg[0] == 'error') {
+      result.release();
+      throw StateError(msg[1] as String);
+    }
+
+    return result;
+  }
+
+  @override
+  void release() {
+    if (!_isReleased) {
+      _isReleased = true;
+      permitsFinalizer.detach(this);
+      pkg_weblocks_release_permits(_request);
+    }
+  }
+}
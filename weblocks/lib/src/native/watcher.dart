@@ -0,0 +1,68 @@
+import 'dart:async';
+import 'dart:convert';
+import 'dart:ffi';
+import 'dart:isolate';
+
+import 'package:ffi/ffi.dart';
+
+import '../interface.dart';
+import 'bindings.dart';
+import 'implementation.dart';
+
+/// A native watcher registration forwarding events to a listener.
+final class NativeLockWatcher implements Finalizable {
+  final Pointer<Void> _watcher;
+  final ReceivePort _port;
+  late final StreamSubscription<void> _portSubscription;
+
+  bool _isClosed = false;
+
+  NativeLockWatcher._(this._watcher, this._port, void Function(LockEvent) add) {
+    watcherFinalizer.attach(this, _watcher, detach: this);
+
+    _portSubscription = _port.listen((msg) {
+      final [type, name, clientId, requestId, exclusive] = msg as List;
+      add(
+        LockEvent(
+          type: LockEventType.values.byName(type as String),
+          name: name as String,
+          clientId: clientId as String,
+          requestId: requestId as int,
+          exclusive: exclusive as bool,
+        ),
+      );
+    });
+  }
+
+  factory NativeLockWatcher(
+    Pointer<Void> client,
+    String name,
+    bool prefix,
+    void Function(LockEvent) add,
+  ) {
+    final port = ReceivePort('watching $name');
+    final encoded = utf8.encode(name);
+
+    final watcher = using((alloc) {
+      return pkg_weblocks_watch(
+        client,
+        encoded.length,
+        alloc.allocBytes(encoded),
+        prefix,
+        port.sendPort.nativePort,
+      );
+    });
+    return NativeLockWatcher._(watcher, port, add);
+  }
+
+  void close() {
+    if (!_isClosed) {
+      _isClosed = true;
+      _portSubscription.cancel();
+      _port.close();
+
+      watcherFinalizer.detach(this);
+      pkg_weblocks_unwatch(_watcher);
+    }
+  }
+}
@@ -15,12 +15,32 @@ final class WebLockManager implements LockManager {
   WebLockManager(this._implementation);
 
   @override
-  Future<LockManagerSnapshot> query() async {
+  Future<LockManagerSnapshot> query({
+    String? name,
+    String? prefix,
+    String? clientId,
+  }) async {
+    if ([name, prefix, clientId].nonNulls.length > 1) {
+      throw ArgumentError('Only one of name, prefix and clientId may be set.');
+    }
     final snapshot = await _implementation.query().toDart;
 
+    // The Web Locks API has no filters, so we apply them here.
+    bool include(LockInfo info) {
+      return (name == null || info.name == name) &&
+          (prefix == null || info.name.startsWith(prefix)) &&
+          (clientId == null || info.clientId == clientId);
+    }
+
     return LockManagerSnapshot(
-      pending: snapshot.pending.toDart.map((e) => e.asLockInfo).toList(),
-      held: snapshot.held.toDart.map((e) => e.asLockInfo).toList(),
+      pending: snapshot.pending.toDart
+          .map((e) => e.asLockInfo)
+          .where(include)
+          .toList(),
+      held: snapshot.held.toDart
+          .map((e) => e.asLockInfo)
+          .where(include)
+          .toList(),
     );
   }
 
@@ -30,7 +50,27 @@ final class WebLockManager implements LockManager {
     bool exclusive = true,
     bool ifAvailable = false,
     bool steal = false,
+    bool crossProcess = false,
+    bool detectDeadlocks = false,
+    bool reentrant = false,
+    int? owner,
+    Duration? timeout,
+    Duration? lease,
   }) {
+    if (lease != null) {
+      throw UnsupportedError('Lock leases are not supported on the web.');
+    }
+    if (reentrant) {
+      throw UnsupportedError('Reentrant locks are not supported on the web.');
+    }
+    if (timeout != null && (steal || ifAvailable)) {
+      throw ArgumentError.value(
+        timeout,
+        'timeout',
+        'Timeouts are not supported for steal or ifAvailable requests.',
+      );
+    }
+
     final canAbort = !steal && !ifAvailable;
     final abort = canAbort ? web.AbortController() : null;
     final state = _WebLockRequestState(
@@ -86,11 +126,108 @@ final class WebLockManager implements LockManager {
       return null;
     });
 
+    if (timeout != null) {
+      Timer(timeout, () {
+        if (!state._didAcquire.isCompleted) {
+          abort!.abort();
+          state._didAcquire.completeError(
+            TimeoutException('Lock $name could not be obtained', timeout),
+          );
+          state._release.complete();
+        }
+      });
+    }
+
     return _LockRequest(state);
   }
 
   @override
-  BroadcastChannel broadcastChannel(String name) {
+  HeldLock? tryRequest(
+    String name, {
+    bool exclusive = true,
+    bool crossProcess = false,
+    bool observeStolen = false,
+  }) {
+    throw UnsupportedError(
+      'Synchronous lock requests are not supported on the web.',
+    );
+  }
+
+  @override
+  MultiLockRequest requestAll(Iterable<({String name, bool exclusive})> locks) {
+    throw UnsupportedError(
+      'Requesting multiple locks is not supported on the web.',
+    );
+  }
+
+  @override
+  void setQueuePolicy(String name, QueuePolicy policy) {
+    if (policy != QueuePolicy.fifo) {
+      throw UnsupportedError('The Web Locks API only supports FIFO queues.');
+    }
+  }
+
+  @override
+  Future<SemaphorePermits> acquirePermits(
+    String name, {
+    required int maxPermits,
+    int permits = 1,
+  }) {
+    throw UnsupportedError('Semaphores are not supported on the web.');
+  }
+
+  @override
+  void setLockDirectory(String path) {
+    // Locks are always shared across tabs on the web, there are no lock files.
+  }
+
+  @override
+  void setHierarchySeparator(String? separator) {
+    throw UnsupportedError('Hierarchical locks are not supported on the web.');
+  }
+
+  @override
+  Stream<LockEvent> watch(String name, {bool prefix = false}) {
+    throw UnsupportedError('Watching locks is not supported on the web.');
+  }
+
+  @override
+  Future<List<LockMetrics>> metrics() {
+    throw UnsupportedError('Lock metrics are not supported on the web.');
+  }
+
+  @override
+  void resetMetrics() {
+    throw UnsupportedError('Lock metrics are not supported on the web.');
+  }
+
+  @override
+  void shutdown() {
+    throw UnsupportedError(
+      'Shutting down clients is not supported on the web.',
+    );
+  }
+
+  @override
+  Future<String> openMetrics({
+    int maxLockNames = 100,
+    int maxChannelNames = 100,
+  }) {
+    throw UnsupportedError('Lock metrics are not supported on the web.');
+  }
+
+  @override
+  LockManager namespace(String name) {
+    throw UnsupportedError('Namespaces are not supported on the web.');
+  }
+
+  @override
+  void notifyCondition(String name, String condition, {bool all = false}) {
+    throw UnsupportedError('Condition variables are not supported on the web.');
+  }
+
+  @override
+  BroadcastChannel broadcastChannel(String name, {bool crossProcess = false}) {
     return _WebBroadcastChannel(web.BroadcastChannel(name));
   }
 }
@@ -145,10 +282,8 @@ final class _LockRequest implements LockRequest {
       );
     }
 
-    if (_state.abortController == null) {
-      throw StateError("Can't cancel requests that have steal or ifAvailable");
-    }
-
+    // Requests with steal or ifAvailable can't have an abort signal. If they
+    // get granted later, completing _release makes us release them right away.
     if (!_state._release.isCompleted) {
       _state.abortController?.abort();
       _state._didAcquire.completeError(const LockRequestCancelled());
@@ -181,6 +316,30 @@ final class _HeldLock implements HeldLock {
   @override
   Future<void> get stolen => _state._stolen.future;
 
+  @override
+  int get holdCount => 1;
+
+  @override
+  Future<void> get expired => Completer<void>().future;
+
+  @override
+  void renew() {}
+
+  @override
+  Future<void> upgrade() {
+    throw UnsupportedError('Lock upgrades are not supported on the web.');
+  }
+
+  @override
+  Future<void> wait(String condition) {
+    throw UnsupportedError('Condition variables are not supported on the web.');
+  }
+
+  @override
+  void downgrade() {
+    throw UnsupportedError('Lock downgrades are not supported on the web.');
+  }
+
   @override
   void release() {
     if (!_state._release.isCompleted) {
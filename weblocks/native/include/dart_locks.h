@@ -0,0 +1,84 @@
+/*
+ * C interface for obtaining the locks managed by the dart-locks library from
+ * native threads that aren't running Dart.
+ *
+ * The library must be the same one loaded by Dart, so that native threads and
+ * isolates share the same locks. Names are UTF-8 buffers that don't need to be
+ * null-terminated. See native/src/lib.rs for the full documentation of these
+ * functions.
+ */
+#ifndef DART_LOCKS_H
+#define DART_LOCKS_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Flags for pkg_weblocks_obtain_blocking. */
+#define PKG_WEBLOCKS_FLAG_SHARED 0x01
+#define PKG_WEBLOCKS_FLAG_STEAL 0x02
+#define PKG_WEBLOCKS_FLAG_IF_AVAILABLE 0x04
+#define PKG_WEBLOCKS_FLAG_CROSS_PROCESS 0x08
+#define PKG_WEBLOCKS_FLAG_DETECT_DEADLOCKS 0x10
+#define PKG_WEBLOCKS_FLAG_REENTRANT 0x20
+
+/* Failures written by pkg_weblocks_obtain_blocking. */
+#define PKG_WEBLOCKS_FAILURE_TIMED_OUT 1
+#define PKG_WEBLOCKS_FAILURE_UNAVAILABLE 2
+#define PKG_WEBLOCKS_FAILURE_DEADLOCK 3
+#define PKG_WEBLOCKS_FAILURE_SHUT_DOWN 4
+#define PKG_WEBLOCKS_FAILURE_FAILED 5
+#define PKG_WEBLOCKS_FAILURE_NOT_NATIVE 6
+
+typedef struct pkg_weblocks_manager pkg_weblocks_manager;
+typedef struct pkg_weblocks_client pkg_weblocks_client;
+typedef struct pkg_weblocks_request pkg_weblocks_request;
+
+/*
+ * Returns a handle to the isolated lock manager called `name`, creating it if
+ * necessary. Free it with pkg_weblocks_manager_free.
+ */
+const pkg_weblocks_manager *pkg_weblocks_manager_new(intptr_t name_length,
+                                                     const uint8_t *name);
+void pkg_weblocks_manager_free(const pkg_weblocks_manager *manager);
+
+/*
+ * Creates a client for native threads, bound to `manager` or to the default
+ * manager shared with Dart if it's null. Free it with pkg_weblocks_free_client.
+ */
+const pkg_weblocks_client *pkg_weblocks_native_client(
+    intptr_t name_length, const uint8_t *name,
+    const pkg_weblocks_manager *manager);
+
+/*
+ * Releases all locks held by `client` and cancels its pending requests, which
+ * then fail with PKG_WEBLOCKS_FAILURE_SHUT_DOWN.
+ */
+void pkg_weblocks_client_shutdown(const pkg_weblocks_client *client);
+void pkg_weblocks_free_client(const pkg_weblocks_client *client);
+
+/*
+ * Blocks the calling thread until the lock called `name` has been granted.
+ *
+ * `owner` is the owner token for reentrant requests, or zero. When
+ * `timeout_micros` is not negative, this stops waiting after that time.
+ *
+ * Returns a request holding the lock, which must be released with
+ * pkg_weblocks_unlock. Otherwise, returns null and writes one of the
+ * PKG_WEBLOCKS_FAILURE_ constants to `failure` unless it's null.
+ */
+const pkg_weblocks_request *pkg_weblocks_obtain_blocking(
+    intptr_t name_length, const uint8_t *name,
+    const pkg_weblocks_client *client, uint32_t flags, uint64_t owner,
+    int64_t timeout_micros, uint32_t *failure);
+
+/* Releases a lock obtained with pkg_weblocks_obtain_blocking. */
+void pkg_weblocks_unlock(const pkg_weblocks_request *request);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* DART_LOCKS_H */
@@ -0,0 +1,583 @@
+//! A safe Rust API for the lock manager.
+//!
+//! Clients created here share state with the Dart-facing exports: The default manager is the same
+//! one used by Dart isolates, and namespaces correspond to `LockManager.namespace` in Dart. This
+//! allows Rust code loaded into the same process to coordinate with isolates.
+//!
+//! This state lives in statics of the library, so it's only shared if Rust code uses the same copy
+//! of the library that Dart has loaded. A plugin that links this crate into a shared library of
+//! its own gets separate locks, managers and channels that Dart never sees. Such plugins must be
+//! linked into the artifact Dart loads, or use the C interface of that artifact (declared in
+//! `include/dart_locks.h`) instead.
+//!
+//! Events are delivered to callbacks through [NativePort]s. Callbacks run on a background thread
+//! after the manager has released its internal locks, so they may call into the lock manager. They
+//! delay all other events and must not block, for instance by waiting for another lock.
+
+use std::{
+    ffi::CString,
+    sync::{Arc, atomic::AtomicBool},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    LOCKS, LockClient, QueuePolicy, SnapshotFilter,
+    blocking::{self, LockFailure},
+    broadcast_channel::BroadcastChannelReference,
+    collect_snapshot,
+    dart::{DartApi, DartPort, NativeMessage, NativePort},
+    future::{LockFuture, RequestEvents},
+    namespace_manager,
+    state::LockRequest,
+};
+
+/// A client of a lock manager, comparable to a Dart isolate using the `LockManager` API.
+///
+/// Dropping the client doesn't release locks obtained through it, those are released when their
+/// [LockHandle] is dropped.
+pub struct Client {
+    inner: Arc<LockClient>,
+}
+
+impl Client {
+    /// Creates a client of the default lock manager, which is shared with Dart isolates that
+    /// haven't been bound to a namespace.
+    pub fn new(name: &str) -> Self {
+        Self::with_manager(name, LOCKS.clone())
+    }
+
+    /// Creates a client of the isolated manager called `namespace`, which is shared with Dart
+    /// clients bound to the same namespace.
+    pub fn namespaced(name: &str, namespace: &str) -> Self {
+        Self::with_manager(name, namespace_manager(namespace))
+    }
+
+    fn with_manager(name: &str, manager: Arc<crate::manager::LockManager>) -> Self {
+        Self {
+            inner: Arc::new(LockClient {
+                name: name.to_string(),
+                api: DartApi::native(),
+                manager,
+                shut_down: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// The name of this client, reported as the client id in snapshots.
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Requests the lock called `name`, returning a future that resolves once it's granted.
+    ///
+    /// The future doesn't depend on a specific async runtime. Dropping it cancels the request, and
+    /// dropping the [LockGuard](crate::LockGuard) it resolves to releases the lock.
+    pub fn request(&self, name: &str, options: &LockOptions) -> LockFuture {
+        let events = Arc::new(RequestEvents::default());
+        let port = RequestEvents::port(&events);
+
+        let request = Arc::new(options.to_request(name, &self.inner, port.port()));
+        self.inner.manager.lock(request.clone());
+        LockFuture::new(
+            LockHandle {
+                request,
+                _port: Some(port),
+            },
+            events,
+        )
+    }
+
+    /// Requests the lock called `name`, calling `on_event` once it's granted and for events that
+    /// happen while it's held.
+    ///
+    /// The request is cancelled or the lock is released when the returned handle is dropped.
+    pub fn request_with_events(
+        &self,
+        name: &str,
+        options: &LockOptions,
+        on_event: impl Fn(RequestEvent) + Send + Sync + 'static,
+    ) -> LockHandle {
+        let port = NativePort::new(move |message| {
+            if let Some(event) = RequestEvent::decode(&message) {
+                on_event(event);
+            }
+        });
+
+        let request = Arc::new(options.to_request(name, &self.inner, port.port()));
+        self.inner.manager.lock(request.clone());
+        LockHandle {
+            request,
+            _port: Some(port),
+        }
+    }
+
+    /// Requests the lock called `name` and blocks the current thread until the request completes.
+    ///
+    /// With [LockOptions::timeout], this stops waiting after that time. Events happening after the
+    /// lock has been granted, like it being stolen, are not reported.
+    pub fn lock_blocking(
+        &self,
+        name: &str,
+        options: &LockOptions,
+    ) -> Result<LockHandle, LockFailure> {
+        let request = blocking::lock_blocking(|port| options.to_request(name, &self.inner, port))?;
+        Ok(LockHandle {
+            request,
+            _port: None,
+        })
+    }
+
+    /// Obtains the lock called `name` if that's possible without waiting.
+    ///
+    /// Like [Self::lock_blocking], events happening after the lock has been granted are not
+    /// reported.
+    pub fn try_lock(&self, name: &str, shared: bool) -> Option<LockHandle> {
+        let options = LockOptions {
+            shared,
+            if_available: true,
+            ..Default::default()
+        };
+        let request = Arc::new(options.to_request(name, &self.inner, DartPort::ILLEGAL));
+
+        self.inner.manager.try_lock(&request).then_some(LockHandle {
+            request,
+            _port: None,
+        })
+    }
+
+    /// Returns the pending and held requests on locks and semaphores of this client's manager
+    /// matching `filter`.
+    pub fn snapshot(&self, filter: SnapshotFilter) -> Vec<LockInfo> {
+        collect_snapshot(&self.inner.manager, &filter)
+            .into_iter()
+            .map(|snapshot| LockInfo {
+                name: snapshot.name.to_string_lossy().into_owned(),
+                client_id: snapshot.client_id.to_string_lossy().into_owned(),
+                exclusive: snapshot.exclusive,
+                held: snapshot.held,
+                policy: snapshot.policy,
+                permits: snapshot.permits,
+                max_permits: snapshot.max_permits,
+                intention: snapshot.intention,
+                request_id: snapshot.id,
+                enqueued_at: snapshot.enqueued_at,
+                granted_at: snapshot.granted_at,
+                queue_position: snapshot.queue_position,
+                steal: snapshot.steal,
+                if_available: snapshot.if_available,
+            })
+            .collect()
+    }
+
+    /// Subscribes to the broadcast channel called `name`, calling `on_message` for messages sent
+    /// by other clients.
+    ///
+    /// With `cross_process`, messages are also exchanged with other processes. Like for locks, this
+    /// is only supported by the default manager.
+    pub fn broadcast_channel(
+        &self,
+        name: &str,
+        cross_process: bool,
+        on_message: impl Fn(String) + Send + Sync + 'static,
+    ) -> BroadcastChannel {
+        let port = NativePort::new(move |message| {
+            if let NativeMessage::String(message) = message {
+                on_message(message);
+            }
+        });
+
+        let channel =
+            BroadcastChannelReference::new(self.inner.clone(), name, cross_process, port.port());
+        BroadcastChannel {
+            channel,
+            _port: port,
+        }
+    }
+
+    /// Releases all locks and cancels all requests of this client, see `LockManager.shutdown` in
+    /// Dart.
+    pub fn shutdown(&self) {
+        self.inner.shut_down();
+    }
+}
+
+/// Options for lock requests made through a [Client], matching the options of
+/// `LockManager.request` in Dart.
+#[derive(Clone, Debug, Default)]
+pub struct LockOptions {
+    /// Whether the lock is requested in shared mode instead of exclusively.
+    pub shared: bool,
+    /// Whether the lock should be stolen from its current holders.
+    pub steal: bool,
+    /// Whether the request should only be granted if the lock is available right away.
+    pub if_available: bool,
+    /// Whether the lock should also exclude other processes.
+    pub cross_process: bool,
+    /// Whether the request should fail instead of waiting in a deadlock.
+    pub detect_deadlocks: bool,
+    /// Whether the request is granted right away if its owner already holds the lock.
+    pub reentrant: bool,
+    /// An explicit owner token for [Self::reentrant] requests. The client is the owner otherwise.
+    pub owner: Option<u64>,
+    /// How long to wait for the lock before giving up.
+    pub timeout: Option<Duration>,
+    /// How long the lock is held before it's released unless renewed with [LockHandle::renew].
+    pub lease: Option<Duration>,
+}
+
+impl LockOptions {
+    fn to_request(&self, name: &str, client: &Arc<LockClient>, port: DartPort) -> LockRequest {
+        LockRequest {
+            name: name.to_string(),
+            client: client.clone(),
+            shared: self.shared,
+            steal: self.steal,
+            if_available: self.if_available,
+            cross_process: self.cross_process,
+            detect_deadlocks: self.detect_deadlocks,
+            reentrant: self.reentrant,
+            owner: self.owner,
+            deadline: self.timeout.map(|timeout| Instant::now() + timeout),
+            lease: self.lease,
+            lease_expires: Default::default(),
+            group: None,
+            holds_lock: Default::default(),
+            timeline: Default::default(),
+            timer: Default::default(),
+            notify: port,
+        }
+    }
+}
+
+/// An event reported to the callback of [Client::request_with_events].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RequestEvent {
+    /// The lock has been granted. For reentrant requests, this includes the amount of requests of
+    /// the same owner holding the lock.
+    Locked { hold_count: usize },
+    /// The request was made with [LockOptions::if_available] and the lock wasn't available.
+    Unavailable,
+    /// The lock has been stolen by another request.
+    Stolen,
+    /// The request hasn't been granted within its [LockOptions::timeout].
+    TimedOut,
+    /// The lock has been released because its [LockOptions::lease] has expired.
+    Expired,
+    /// Waiting for the request would deadlock. The cycle lists each lock with the name of the
+    /// client waiting for it.
+    Deadlock(Vec<(String, String)>),
+    /// The request has been cancelled because the client has been shut down.
+    ShutDown,
+    /// The lock file of a cross-process request could not be locked.
+    Failed(String),
+}
+
+impl RequestEvent {
+    pub(crate) fn decode(message: &NativeMessage) -> Option<Self> {
+        let NativeMessage::Array(parts) = message else {
+            return None;
+        };
+        let string = |index: usize| match parts.get(index) {
+            Some(NativeMessage::String(value)) => value.clone(),
+            _ => String::new(),
+        };
+
+        Some(match message.kind()? {
+            "locked" => Self::Locked {
+                hold_count: match parts.get(1) {
+                    Some(NativeMessage::Int(count)) => *count as usize,
+                    _ => 1,
+                },
+            },
+            "unavailable" => Self::Unavailable,
+            "stolen" => Self::Stolen,
+            "timeout" => Self::TimedOut,
+            "expired" => Self::Expired,
+            "deadlock" => Self::Deadlock(
+                (1..parts.len())
+                    .step_by(2)
+                    .map(|i| (string(i), string(i + 1)))
+                    .collect(),
+            ),
+            "shutdown" => Self::ShutDown,
+            "error" => Self::Failed(string(1)),
+            _ => return None,
+        })
+    }
+}
+
+/// A lock request made through a [Client].
+///
+/// Dropping the handle cancels the request if it's still pending, or releases the lock.
+pub struct LockHandle {
+    request: Arc<LockRequest>,
+    _port: Option<NativePort>,
+}
+
+impl LockHandle {
+    /// The name of the requested lock.
+    pub fn name(&self) -> &str {
+        &self.request.name
+    }
+
+    /// Renews the [LockOptions::lease] of a held lock.
+    pub fn renew(&self) {
+        self.request.client.manager.renew_lease(&self.request);
+    }
+
+    /// Releases the lock, or cancels the request if it's still pending.
+    pub fn release(self) {}
+}
+
+impl Drop for LockHandle {
+    fn drop(&mut self) {
+        let manager = self.request.client.manager.clone();
+        manager.close_request(self.request.clone());
+    }
+}
+
+/// A request on a lock or semaphore, as reported by [Client::snapshot].
+#[derive(Clone, Debug)]
+pub struct LockInfo {
+    /// The name of the lock or semaphore.
+    pub name: String,
+    /// The name of the client that made the request.
+    pub client_id: String,
+    pub exclusive: bool,
+    /// Whether the request holds the lock, or is still pending otherwise.
+    pub held: bool,
+    pub policy: QueuePolicy,
+    /// For semaphores, the amount of permits taken or requested.
+    pub permits: Option<u32>,
+    /// For semaphores, the total amount of permits.
+    pub max_permits: Option<u32>,
+    /// Whether this describes an intention lock held on an ancestor of a hierarchical lock.
+    pub intention: bool,
+    /// An id identifying the request across snapshots.
+    pub request_id: u64,
+    pub enqueued_at: SystemTime,
+    pub granted_at: Option<SystemTime>,
+    /// For pending requests, the index of the request in the queue of the lock.
+    pub queue_position: Option<usize>,
+    pub steal: bool,
+    pub if_available: bool,
+}
+
+/// A subscription to a broadcast channel created through [Client::broadcast_channel].
+///
+/// The subscription ends when this is dropped.
+pub struct BroadcastChannel {
+    channel: BroadcastChannelReference,
+    _port: NativePort,
+}
+
+impl BroadcastChannel {
+    /// Sends `message` to all other subscribers of the channel.
+    ///
+    /// Messages containing a nul byte are truncated before it, since they can't be represented in
+    /// the messages sent to Dart.
+    pub fn send(&self, message: &str) {
+        let end = message.find('\0').unwrap_or(message.len());
+        let message = CString::new(&message[..end]).unwrap();
+        self.channel.send(&message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver};
+
+    use super::*;
+    use crate::SnapshotFilter;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Makes a request reporting its events to the returned receiver.
+    fn request(
+        client: &Client,
+        name: &str,
+        options: &LockOptions,
+    ) -> (LockHandle, Receiver<RequestEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        let handle = client.request_with_events(name, options, move |event| {
+            let _ = sender.send(event);
+        });
+        (handle, receiver)
+    }
+
+    #[test]
+    fn lock_and_release() {
+        let a = Client::namespaced("a", "api_lock_and_release");
+        let b = Client::namespaced("b", "api_lock_and_release");
+
+        let held = a.lock_blocking("lock", &Default::default()).unwrap();
+        assert!(b.try_lock("lock", false).is_none());
+        let (waiting, events) = request(&b, "lock", &Default::default());
+        assert!(events.recv_timeout(Duration::from_millis(50)).is_err());
+
+        held.release();
+        assert_eq!(
+            events.recv_timeout(TIMEOUT),
+            Ok(RequestEvent::Locked { hold_count: 1 })
+        );
+        drop(waiting);
+        assert!(a.try_lock("lock", false).is_some());
+    }
+
+    #[test]
+    fn steal() {
+        let a = Client::namespaced("a", "api_steal");
+        let b = Client::namespaced("b", "api_steal");
+
+        let (_held, events) = request(&a, "lock", &Default::default());
+        assert_eq!(
+            events.recv_timeout(TIMEOUT),
+            Ok(RequestEvent::Locked { hold_count: 1 })
+        );
+
+        let options = LockOptions {
+            steal: true,
+            ..Default::default()
+        };
+        let _stolen = b.lock_blocking("lock", &options).unwrap();
+        assert_eq!(events.recv_timeout(TIMEOUT), Ok(RequestEvent::Stolen));
+    }
+
+    #[test]
+    fn deadlocks_with_nul_in_names() {
+        let a = Client::namespaced("a\0", "api_deadlocks_with_nul_in_names");
+        let b = Client::namespaced("b", "api_deadlocks_with_nul_in_names");
+        let options = LockOptions {
+            detect_deadlocks: true,
+            ..Default::default()
+        };
+
+        let _x = a.lock_blocking("x\0", &Default::default()).unwrap();
+        let _y = b.lock_blocking("y", &Default::default()).unwrap();
+        let (_waiting, _events) = request(&a, "y", &options);
+        let (_deadlocked, events) = request(&b, "x\0", &options);
+
+        let Ok(RequestEvent::Deadlock(cycle)) = events.recv_timeout(TIMEOUT) else {
+            panic!("expected a deadlock");
+        };
+        assert!(cycle.contains(&(String::new(), "b".to_string())));
+        assert!(cycle.contains(&("y".to_string(), String::new())));
+    }
+
+    #[test]
+    fn snapshots() {
+        let a = Client::namespaced("a", "api_snapshots");
+        let b = Client::namespaced("b", "api_snapshots");
+
+        let _held = a.lock_blocking("lock", &Default::default()).unwrap();
+        let (_pending, _events) = request(&b, "lock", &Default::default());
+        let _other = b.lock_blocking("other", &Default::default()).unwrap();
+
+        let snapshot = a.snapshot(SnapshotFilter::Name("lock"));
+        assert_eq!(snapshot.len(), 2);
+        let held = snapshot.iter().find(|info| info.held).unwrap();
+        assert_eq!(held.client_id, "a");
+        assert!(held.granted_at.is_some());
+        let pending = snapshot.iter().find(|info| !info.held).unwrap();
+        assert_eq!(pending.client_id, "b");
+        assert_eq!(pending.queue_position, Some(0));
+
+        assert_eq!(a.snapshot(SnapshotFilter::Client("b")).len(), 2);
+        assert_eq!(a.snapshot(SnapshotFilter::All).len(), 3);
+    }
+
+    #[test]
+    fn requeued_waiters_restart_their_wait() {
+        let a = Client::namespaced("a", "api_requeued_waiters_restart_their_wait");
+        let b = Client::namespaced("b", "api_requeued_waiters_restart_their_wait");
+        let manager = &a.inner.manager;
+
+        let (waiter, events) = request(&a, "lock", &Default::default());
+        assert_eq!(
+            events.recv_timeout(TIMEOUT),
+            Ok(RequestEvent::Locked { hold_count: 1 })
+        );
+        let first_grant = a.snapshot(SnapshotFilter::Client("a"))[0]
+            .granted_at
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        manager.wait_on_condition(&waiter.request, "condition".to_string());
+        let holder = b.lock_blocking("lock", &Default::default()).unwrap();
+        let notified_at = SystemTime::now();
+        manager.notify_condition("lock", "condition", false);
+
+        let pending = &a.snapshot(SnapshotFilter::Client("a"))[0];
+        assert!(!pending.held);
+        assert_eq!(pending.granted_at, None);
+        assert!(pending.enqueued_at >= notified_at);
+
+        holder.release();
+        assert_eq!(
+            events.recv_timeout(TIMEOUT),
+            Ok(RequestEvent::Locked { hold_count: 1 })
+        );
+        let held = &a.snapshot(SnapshotFilter::Client("a"))[0];
+        assert!(held.held);
+        assert!(held.granted_at.unwrap() > first_grant);
+
+        // Neither wait includes the time the lock was held before waiting on the condition.
+        let mut wait_time = None;
+        manager.metrics.inspect(|name, metrics| {
+            if name == Some("lock") {
+                wait_time = Some((metrics.wait_time.count, metrics.wait_time.sum_micros));
+            }
+        });
+        let (count, sum_micros) = wait_time.unwrap();
+        assert_eq!(count, 3);
+        assert!(sum_micros < 50_000);
+    }
+
+    #[test]
+    fn channels() {
+        let a = Client::namespaced("a", "api_channels");
+        let b = Client::namespaced("b", "api_channels");
+
+        let (sender, received) = mpsc::channel();
+        let _subscription = b.broadcast_channel("channel", false, move |message| {
+            let _ = sender.send(message);
+        });
+        let channel = a.broadcast_channel("channel", false, |_| {});
+        channel.send("hello");
+        assert_eq!(received.recv_timeout(TIMEOUT).as_deref(), Ok("hello"));
+    }
+
+    #[test]
+    fn callbacks_may_use_the_manager() {
+        let a = Arc::new(Client::namespaced("a", "api_callbacks"));
+        let b = Client::namespaced("b", "api_callbacks");
+
+        // Reply to messages from the callback, which runs while another client sends.
+        let (sender, replies) = mpsc::channel();
+        let replying = Arc::new(std::sync::Mutex::new(None::<BroadcastChannel>));
+        let _subscription = a.broadcast_channel("channel", false, {
+            let replying = replying.clone();
+            move |message| {
+                if let Some(channel) = &*replying.lock().unwrap() {
+                    channel.send(&format!("re: {message}"));
+                }
+            }
+        });
+        *replying.lock().unwrap() = Some(a.broadcast_channel("channel", false, |_| {}));
+        let channel = b.broadcast_channel("channel", false, move |message| {
+            let _ = sender.send(message);
+        });
+        channel.send("hello");
+        assert_eq!(replies.recv_timeout(TIMEOUT).as_deref(), Ok("re: hello"));
+
+        // Inspect the manager from the callback of a lock request.
+        let (sender, snapshots) = mpsc::channel();
+        let _held = b.request_with_events("lock", &Default::default(), {
+            let a = a.clone();
+            move |_| {
+                let _ = sender.send(a.snapshot(SnapshotFilter::Name("lock")).len());
+            }
+        });
+        assert_eq!(snapshots.recv_timeout(TIMEOUT), Ok(1));
+    }
+}
@@ -0,0 +1,74 @@
+//! Lock requests made from native threads, which block until the lock is granted.
+//!
+//! These requests go through the same [crate::manager::LockManager] as requests from Dart, so
+//! native holders and Dart holders exclude each other. Instead of a `SendPort`, they're notified
+//! through a [NativePort].
+
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::{
+    dart::{DartPort, NativeMessage, NativePort},
+    state::LockRequest,
+};
+
+/// Why a blocking lock request has not been granted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum LockFailure {
+    /// The request has not been granted before its
+    /// [LockOptions::timeout](crate::LockOptions::timeout).
+    TimedOut = 1,
+    /// The request was a [LockOptions::if_available](crate::LockOptions::if_available) request
+    /// and the lock was not available.
+    Unavailable = 2,
+    /// Waiting for the request would have completed a cycle in the wait-for graph.
+    Deadlock = 3,
+    /// The client has been shut down.
+    ShutDown = 4,
+    /// The lock file of a [LockOptions::cross_process](crate::LockOptions::cross_process) request
+    /// could not be locked.
+    Failed = 5,
+}
+
+/// Makes the request returned by `request` and blocks the calling thread until it completes.
+///
+/// `request` is called with the port the request needs to notify. Its client must have been
+/// created with [crate::dart::DartApi::native]. Once the lock has been granted, further events
+/// (like the lock being stolen) are dropped.
+pub fn lock_blocking(
+    request: impl FnOnce(DartPort) -> LockRequest,
+) -> Result<Arc<LockRequest>, LockFailure> {
+    let outcome = Arc::new((Mutex::new(None::<NativeMessage>), Condvar::new()));
+    let port = NativePort::new({
+        let outcome = outcome.clone();
+        move |message| {
+            let (slot, completed) = &*outcome;
+            let mut slot = slot.lock().unwrap();
+            // The first event posted to a new request decides its outcome.
+            if slot.is_none() {
+                *slot = Some(message);
+                completed.notify_all();
+            }
+        }
+    });
+
+    let request = Arc::new(request(port.port()));
+    request.client.manager.lock(request.clone());
+
+    let (slot, completed) = &*outcome;
+    let message = completed
+        .wait_while(slot.lock().unwrap(), |message| message.is_none())
+        .unwrap()
+        .take()
+        .unwrap();
+    drop(port);
+
+    match message.kind() {
+        Some("locked") => Ok(request),
+        Some("timeout") => Err(LockFailure::TimedOut),
+        Some("unavailable") => Err(LockFailure::Unavailable),
+        Some("deadlock") => Err(LockFailure::Deadlock),
+        Some("shutdown") => Err(LockFailure::ShutDown),
+        _ => Err(LockFailure::Failed),
+    }
+}
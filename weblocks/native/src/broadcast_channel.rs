@@ -5,21 +5,54 @@ use std::{
     sync::{Arc, Mutex, Weak},
 };
 
-use lazy_static::lazy_static;
-
 use crate::{
-    LockClient,
+    LOCKS, LockClient, channel_transport,
     dart::{DartObject, DartPort},
+    manager::LockManager,
 };
 
-lazy_static! {
-    /// All currently-active channels.
-    static ref CHANNELS: Mutex<HashMap<String, Weak<BroadcastChannel>>> = Mutex::new(HashMap::new());
+/// All currently-active channels of a [LockManager].
+#[derive(Default)]
+pub struct ChannelRegistry {
+    channels: Mutex<HashMap<String, Weak<BroadcastChannel>>>,
+}
+
+impl ChannelRegistry {
+    /// Unsubscribes `client` from all channels.
+    pub fn remove_client(&self, client: &Arc<LockClient>) {
+        // See [Self::subscriber_counts] for why we collect channels first.
+        let channels: Vec<_> = {
+            let channels = self.channels.lock().unwrap();
+            channels.values().filter_map(Weak::upgrade).collect()
+        };
+
+        for channel in &channels {
+            let mut clients = channel.clients.lock().unwrap();
+            clients.retain(|c| !Arc::ptr_eq(&c.client, client));
+        }
+    }
+
+    /// Returns the name and the amount of subscribed clients of every active channel.
+    pub fn subscriber_counts(&self) -> Vec<(String, usize)> {
+        // Dropping the last reference to a channel removes it from the registry, so we must not
+        // hold the lock while the upgraded references are dropped.
+        let channels: Vec<_> = {
+            let channels = self.channels.lock().unwrap();
+            channels.values().filter_map(Weak::upgrade).collect()
+        };
+
+        channels
+            .iter()
+            .map(|channel| (channel.name.clone(), channel.clients.lock().unwrap().len()))
+            .collect()
+    }
 }
 
 struct BroadcastChannel {
     self_: Cell<Option<Weak<Self>>>,
     name: String,
+    /// The manager owning the [ChannelRegistry] this channel is registered in.
+    manager: Arc<LockManager>,
     clients: Mutex<Vec<BroadcastChannelClient>>,
 }
 
@@ -28,17 +61,18 @@ struct BroadcastChannel {
 unsafe impl Sync for BroadcastChannel {}
 
 impl BroadcastChannel {
-    fn lookup(name: &str) -> Arc<Self> {
-        let mut channels = CHANNELS.lock().unwrap();
-        if let Some(existing) = channels.get(name) {
-            if let Some(channel) = existing.upgrade() {
-                return channel;
-            }
+    fn lookup(manager: &Arc<LockManager>, name: &str) -> Arc<Self> {
+        let mut channels = manager.channels.channels.lock().unwrap();
+        if let Some(existing) = channels.get(name)
+            && let Some(channel) = existing.upgrade()
+        {
+            return channel;
         };
 
         let channel = Self {
             self_: Cell::new(None),
             name: name.to_string(),
+            manager: manager.clone(),
             clients: Mutex::default(),
         };
         let channel = Arc::new(channel);
@@ -49,9 +83,14 @@ impl BroadcastChannel {
     }
 
     /// Insert a new client to notify for subsequent broadcast messages.
+    ///
+    /// Channels of clients that have been shut down don't receive messages. This is checked while
+    /// holding the lock so that it can't race with [ChannelRegistry::remove_client].
     fn insert_client(&self, client: BroadcastChannelClient) {
         let mut clients = self.clients.lock().unwrap();
-        clients.push(client);
+        if !client.client.is_shut_down() {
+            clients.push(client);
+        }
     }
 
     /// Removes a client to no longer notify it.
@@ -61,31 +100,67 @@ impl BroadcastChannel {
     }
 
     fn send_message(&self, sender: &BroadcastChannelClient, msg: &CStr) {
+        if sender.client.is_shut_down() {
+            return;
+        }
+
+        {
+            let clients = self.clients.lock().unwrap();
+            let mut dart_msg = DartObject::from(msg);
+
+            for client in &*clients {
+                if client != sender {
+                    client.port.send(&client.client.api, &mut dart_msg);
+                }
+            }
+        }
+
+        if sender.cross_process {
+            channel_transport::broadcast(&self.name, msg);
+        }
+    }
+
+    /// Forwards a message received from another process to local clients that opted in to
+    /// cross-process messages.
+    fn receive_remote_message(&self, msg: &CStr) {
         let clients = self.clients.lock().unwrap();
         let mut dart_msg = DartObject::from(msg);
 
         for client in &*clients {
-            if client != sender {
+            if client.cross_process {
                 client.port.send(&client.client.api, &mut dart_msg);
             }
         }
     }
 }
 
+/// Delivers a message that another process has sent on the channel `name`.
+///
+/// Only the default [LockManager] exchanges messages with other processes.
+pub fn deliver_remote_message(name: &str, msg: &CStr) {
+    let channel = {
+        let channels = LOCKS.channels.channels.lock().unwrap();
+        channels.get(name).and_then(Weak::upgrade)
+    };
+
+    if let Some(channel) = channel {
+        channel.receive_remote_message(msg);
+    }
+}
+
 impl Drop for BroadcastChannel {
     fn drop(&mut self) {
-        // When all references to a broadcast channel are dropped, remove it from the global map of
-        // named channels as well.
+        // When all references to a broadcast channel are dropped, remove it from the manager's map
+        // of named channels as well.
         // There's a potential race between the last channel with a name being dropped and a channel
         // with the same name being created concurrently. In this case, we must not remove the map's
         // entry.
-        let mut channels = CHANNELS.lock().unwrap();
-        if let Some(channel) = channels.get(&self.name) {
-            if let Some(key) = self.self_.take() {
-                if Weak::ptr_eq(&key, channel) {
-                    channels.remove(&self.name);
-                }
-            }
+        let mut channels = self.manager.channels.channels.lock().unwrap();
+        if let Some(channel) = channels.get(&self.name)
+            && let Some(key) = self.self_.take()
+            && Weak::ptr_eq(&key, channel)
+        {
+            channels.remove(&self.name);
         }
     }
 }
@@ -96,6 +171,8 @@ struct BroadcastChannelClient {
     client: Arc<LockClient>,
     /// The Dart port to send broadcast messages to.
     port: DartPort,
+    /// Whether this client sends messages to and receives messages from other processes.
+    cross_process: bool,
 }
 
 impl PartialEq for BroadcastChannelClient {
@@ -104,13 +181,36 @@ impl PartialEq for BroadcastChannelClient {
     }
 }
 
-struct BroadcastChannelReference {
+pub(crate) struct BroadcastChannelReference {
     channel: Arc<BroadcastChannel>,
     client: BroadcastChannelClient,
 }
 
 impl BroadcastChannelReference {
-    fn send(&self, message: &CStr) {
+    /// Subscribes `client` to the channel called `name`, posting messages to `port`.
+    pub(crate) fn new(
+        client: Arc<LockClient>,
+        name: &str,
+        cross_process: bool,
+        port: DartPort,
+    ) -> Self {
+        let cross_process = cross_process && client.manager.cross_process;
+        if cross_process {
+            channel_transport::start();
+        }
+
+        let channel = BroadcastChannel::lookup(&client.manager, name);
+        let client = BroadcastChannelClient {
+            client,
+            port,
+            cross_process,
+        };
+        channel.insert_client(client.clone());
+
+        Self { channel, client }
+    }
+
+    pub(crate) fn send(&self, message: &CStr) {
         self.channel.send_message(&self.client, message);
     }
 }
@@ -122,12 +222,15 @@ impl Drop for BroadcastChannelReference {
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn pkg_weblocks_broadcast_channel_new(
+extern "C" fn pkg_weblocks_broadcast_channel_new(
     name_length: isize,
     name: *const u8,
     client: *const c_void,
+    flags: u32,
     port: DartPort,
 ) -> *const c_void {
+    const FLAG_CROSS_PROCESS: u32 = 0x01;
+
     let client = unsafe {
         // Safety: Dart should only pass valid pointers.
         LockClient::increment_from_raw(client)
@@ -137,23 +240,18 @@ pub extern "C" fn pkg_weblocks_broadcast_channel_new(
         std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize))
     };
 
-    let client = BroadcastChannelClient { client, port };
-    let channel = BroadcastChannel::lookup(name);
-    channel.insert_client(client.clone());
-
-    Box::into_raw(Box::new(BroadcastChannelReference { channel, client })).cast()
+    let cross_process = (flags & FLAG_CROSS_PROCESS) != 0;
+    let channel = BroadcastChannelReference::new(client, name, cross_process, port);
+    Box::into_raw(Box::new(channel)).cast()
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn pkg_weblocks_broadcast_channel_free(channel_ref: *mut c_void) {
+extern "C" fn pkg_weblocks_broadcast_channel_free(channel_ref: *mut c_void) {
     drop(unsafe { Box::from_raw(channel_ref as *mut BroadcastChannelReference) })
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn pkg_weblocks_broadcast_channel_send(
-    channel_ref: *mut c_void,
-    msg: *const c_char,
-) {
+extern "C" fn pkg_weblocks_broadcast_channel_send(channel_ref: *mut c_void, msg: *const c_char) {
     let msg = unsafe { CStr::from_ptr(msg) };
     let channel_ref = unsafe {
         // Safety: Dart will pass a pointer valid for the duration of this call.
@@ -0,0 +1,294 @@
+//! Delivers broadcast channel messages to other processes on the same machine.
+//!
+//! Every process using cross-process channels listens on a Unix domain socket in a shared
+//! rendezvous directory. To broadcast a message, we send it to all other sockets in that directory.
+//! Sockets of processes that have exited are removed by the next process trying to reach them.
+//!
+//! Messages are written to other processes by a background thread for each peer, so that a peer
+//! that doesn't read its messages can't block senders. Peers falling too far behind miss the
+//! messages queued for them and are reconnected. A peer is only ever written to by one thread at a
+//! time, so that messages arrive in order.
+//!
+//! This transport is only available on Unix platforms, on other platforms messages are only
+//! delivered within the current process.
+
+#[cfg(unix)]
+pub use unix::{broadcast, start};
+
+#[cfg(not(unix))]
+pub fn start() {}
+
+#[cfg(not(unix))]
+pub fn broadcast(_name: &str, _message: &std::ffi::CStr) {}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        collections::HashMap,
+        ffi::{CStr, CString},
+        io::{self, ErrorKind, Read, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        path::PathBuf,
+        sync::{
+            Arc, Mutex, OnceLock,
+            atomic::{AtomicBool, Ordering},
+            mpsc::{self, Receiver, SyncSender, TrySendError},
+        },
+        thread::JoinHandle,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{broadcast_channel::deliver_remote_message, cross_process::lock_directory};
+
+    /// The maximum length of a channel name or message, longer frames are rejected.
+    const MAX_PART_LENGTH: usize = 16 * 1024 * 1024;
+    /// How many messages may be queued for a peer before we consider it stalled and drop it.
+    const MAX_QUEUED_FRAMES: usize = 1024;
+    /// How long writing to a peer may block before we consider it stalled.
+    const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+    /// The coarsest modification time resolution of file systems we expect the rendezvous
+    /// directory to be on (FAT uses two seconds).
+    const MODIFICATION_TIME_RESOLUTION: Duration = Duration::from_secs(2);
+
+    /// The transport of this process, if [start] was successful.
+    static TRANSPORT: OnceLock<Option<Transport>> = OnceLock::new();
+
+    struct Transport {
+        /// The rendezvous directory, resolved once when the transport starts.
+        directory: PathBuf,
+        /// The path of the socket this process listens on.
+        own_socket: PathBuf,
+        /// The sockets of other processes, cached until the directory changes.
+        listing: Mutex<Option<PeerListing>>,
+        /// Connections to other processes, keyed by the path of their socket.
+        peers: Mutex<HashMap<PathBuf, Peer>>,
+    }
+
+    struct PeerListing {
+        /// The modification time of the rendezvous directory when it was listed.
+        modified: SystemTime,
+        listed_at: SystemTime,
+        sockets: Arc<[PathBuf]>,
+    }
+
+    impl PeerListing {
+        /// Whether the listing is still accurate for a directory last modified at `modified`.
+        fn is_current(&self, modified: SystemTime) -> bool {
+            // With coarse timestamps, sockets created shortly after we've listed the directory
+            // may not change its modification time.
+            self.modified == modified
+                && self
+                    .listed_at
+                    .duration_since(modified)
+                    .is_ok_and(|elapsed| elapsed >= MODIFICATION_TIME_RESOLUTION)
+        }
+    }
+
+    /// A connection to another process, which is written to by a background thread.
+    struct Peer {
+        frames: SyncSender<Arc<[u8]>>,
+        /// Set once this connection has been replaced, so that the writer stops instead of writing
+        /// the frames still queued for it.
+        abandoned: Arc<AtomicBool>,
+        writer: JoinHandle<()>,
+    }
+
+    impl Peer {
+        /// Connects to the socket at `path`, replacing the `previous` connection to it.
+        ///
+        /// The new writer only starts once the previous one has stopped, so that frames written by
+        /// both can't be reordered.
+        fn connect(path: PathBuf, previous: Option<Peer>) -> Self {
+            let previous = previous.map(|previous| {
+                previous.abandoned.store(true, Ordering::Relaxed);
+                previous.writer
+            });
+
+            let (frames, pending) = mpsc::sync_channel(MAX_QUEUED_FRAMES);
+            let abandoned = Arc::new(AtomicBool::new(false));
+            let writer = std::thread::spawn({
+                let abandoned = abandoned.clone();
+                move || {
+                    if let Some(previous) = previous {
+                        let _ = previous.join();
+                    }
+                    write_frames(path, pending, &abandoned);
+                }
+            });
+
+            Self {
+                frames,
+                abandoned,
+                writer,
+            }
+        }
+    }
+
+    /// Starts listening for messages from other processes, if that hasn't happened yet.
+    pub fn start() {
+        TRANSPORT.get_or_init(|| {
+            // Failing to listen only means we won't exchange messages with other processes, which
+            // is not something we could report to Dart in a meaningful way.
+            listen().ok()
+        });
+    }
+
+    fn listen() -> io::Result<Transport> {
+        let directory = lock_directory()?.join("channels");
+        std::fs::create_dir_all(&directory)?;
+
+        // A previous process with the same id may have left its socket behind.
+        let path = directory.join(format!("{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                std::thread::spawn(move || receive_messages(stream));
+            }
+        });
+        Ok(Transport {
+            directory,
+            own_socket: path,
+            listing: Mutex::new(None),
+            peers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Reads frames sent by another process until it closes the connection or sends an invalid
+    /// frame.
+    fn receive_messages(mut stream: UnixStream) {
+        fn read_part(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
+            let mut length = [0u8; 4];
+            stream.read_exact(&mut length)?;
+            let length = u32::from_le_bytes(length) as usize;
+            if length > MAX_PART_LENGTH {
+                return Err(io::Error::new(ErrorKind::InvalidData, "frame too large"));
+            }
+
+            let mut buffer = vec![0u8; length];
+            stream.read_exact(&mut buffer)?;
+            Ok(buffer)
+        }
+
+        while let Ok(name) = read_part(&mut stream)
+            && let Ok(message) = read_part(&mut stream)
+        {
+            let (Ok(name), Ok(message)) = (String::from_utf8(name), CString::new(message)) else {
+                break;
+            };
+
+            deliver_remote_message(&name, &message);
+        }
+    }
+
+    fn encode_frame(name: &str, message: &CStr) -> Arc<[u8]> {
+        let message = message.to_bytes();
+        let mut frame = Vec::with_capacity(8 + name.len() + message.len());
+        frame.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        frame.extend_from_slice(name.as_bytes());
+        frame.extend_from_slice(&(message.len() as u32).to_le_bytes());
+        frame.extend_from_slice(message);
+        frame.into()
+    }
+
+    /// Connects to the socket at `path` and writes frames to it until the connection fails or the
+    /// [Peer] is dropped or `abandoned`.
+    fn write_frames(path: PathBuf, frames: Receiver<Arc<[u8]>>, abandoned: &AtomicBool) {
+        let mut stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                if e.kind() == ErrorKind::ConnectionRefused {
+                    // Nobody is listening on this socket anymore.
+                    let _ = std::fs::remove_file(&path);
+                }
+                return;
+            }
+        };
+        if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_err() {
+            return;
+        }
+
+        for frame in frames {
+            if abandoned.load(Ordering::Relaxed) {
+                break;
+            }
+            // Writing to a socket whose peer has exited raises SIGPIPE, which the Dart VM ignores.
+            if stream.write_all(&frame).is_err() {
+                break;
+            }
+        }
+    }
+
+    impl Transport {
+        /// Returns the sockets of other processes in the rendezvous directory.
+        fn peer_sockets(&self) -> Option<Arc<[PathBuf]>> {
+            let modified = std::fs::metadata(&self.directory)
+                .and_then(|metadata| metadata.modified())
+                .ok()?;
+            if let Some(listing) = &*self.listing.lock().unwrap()
+                && listing.is_current(modified)
+            {
+                return Some(listing.sockets.clone());
+            }
+
+            let listed_at = SystemTime::now();
+            let sockets: Arc<[PathBuf]> = std::fs::read_dir(&self.directory)
+                .ok()?
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    *path != self.own_socket && path.extension().is_some_and(|ext| ext == "sock")
+                })
+                .collect();
+
+            *self.listing.lock().unwrap() = Some(PeerListing {
+                modified,
+                listed_at,
+                sockets: sockets.clone(),
+            });
+            Some(sockets)
+        }
+    }
+
+    /// Sends a message on the channel `name` to all other processes.
+    pub fn broadcast(name: &str, message: &CStr) {
+        let Some(Some(transport)) = TRANSPORT.get() else {
+            return;
+        };
+        if name.len() > MAX_PART_LENGTH || message.count_bytes() > MAX_PART_LENGTH {
+            return;
+        }
+        let Some(sockets) = transport.peer_sockets() else {
+            return;
+        };
+
+        let frame = encode_frame(name, message);
+        let mut peers = transport.peers.lock().unwrap();
+        // Forget connections to processes that are gone.
+        peers.retain(|path, _| sockets.contains(path));
+
+        for path in sockets.iter() {
+            let peer = match peers.remove(path) {
+                Some(peer) => peer,
+                None => Peer::connect(path.clone(), None),
+            };
+
+            let peer = match peer.frames.try_send(frame.clone()) {
+                Ok(()) => peer,
+                Err(TrySendError::Disconnected(frame)) => {
+                    // Writing has failed before, reconnect in case the peer has restarted.
+                    let peer = Peer::connect(path.clone(), Some(peer));
+                    let _ = peer.frames.try_send(frame);
+                    peer
+                }
+                Err(TrySendError::Full(_)) => {
+                    // The peer isn't reading its messages. Instead of blocking, drop the messages
+                    // queued for it and reconnect.
+                    Peer::connect(path.clone(), Some(peer))
+                }
+            };
+            peers.insert(path.clone(), peer);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    fmt::Write,
+    fs::{File, OpenOptions, TryLockError},
+    io,
+    path::{Path, PathBuf},
+    sync::{Mutex, Weak},
+};
+
+use lazy_static::lazy_static;
+
+use crate::state::LockRequest;
+
+lazy_static! {
+    /// The directory in which lock files for cross-process locks are created.
+    ///
+    /// When unset, `$WEBLOCKS_LOCK_DIR` or a directory private to the current user in the system's
+    /// temporary directory is used.
+    static ref LOCK_DIRECTORY: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Changes the directory used to store lock files.
+///
+/// Processes can only coordinate on a lock if they use the same directory. Users that can write
+/// to the directory can block locks by locking their files, so it shouldn't be shared with
+/// untrusted users.
+pub fn set_lock_directory(path: PathBuf) {
+    *LOCK_DIRECTORY.lock().unwrap() = Some(path);
+}
+
+/// Returns the directory storing lock files, which is also used as a rendezvous point for
+/// cross-process broadcast channels, creating it if necessary.
+pub fn lock_directory() -> io::Result<PathBuf> {
+    let configured = LOCK_DIRECTORY.lock().unwrap().clone();
+    if let Some(configured) =
+        configured.or_else(|| std::env::var_os("WEBLOCKS_LOCK_DIR").map(PathBuf::from))
+    {
+        std::fs::create_dir_all(&configured)?;
+        return Ok(configured);
+    }
+
+    let directory = default_lock_directory();
+    create_private_directory(&directory)?;
+    Ok(directory)
+}
+
+/// The default directory for lock files.
+///
+/// The temporary directory is shared between all users on Unix, so we use a directory for each
+/// user. `$XDG_RUNTIME_DIR` would be private as well, but it isn't set for all processes of a user
+/// (like those started by cron), which then couldn't coordinate with others.
+#[cfg(unix)]
+fn default_lock_directory() -> PathBuf {
+    std::env::temp_dir().join(format!("dart-weblocks-{}", unix::effective_uid()))
+}
+
+/// The default directory for lock files, the temporary directory is private to the user on other
+/// platforms.
+#[cfg(not(unix))]
+fn default_lock_directory() -> PathBuf {
+    std::env::temp_dir().join("dart-weblocks")
+}
+
+/// Creates `path` as a directory only accessible to the current user.
+///
+/// If the directory exists already, it must have been created by the current user with the same
+/// permissions. Otherwise, another user could interfere with our locks and channels.
+#[cfg(unix)]
+fn create_private_directory(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match std::fs::DirBuilder::new().mode(0o700).create(path) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+
+    // Don't follow symlinks, those could point to a directory controlled by someone else.
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() || metadata.uid() != unix::effective_uid() || metadata.mode() & 0o077 != 0
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is not a private directory of the current user",
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_directory(path: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(path)
+}
+
+#[cfg(unix)]
+mod unix {
+    unsafe extern "C" {
+        fn geteuid() -> u32;
+    }
+
+    pub fn effective_uid() -> u32 {
+        // Safety: geteuid has no preconditions and always succeeds.
+        unsafe { geteuid() }
+    }
+}
+
+/// An advisory lock on a file, used to make a lock exclusive across processes.
+///
+/// The operating system releases these locks when the file is closed, which includes the owning
+/// process exiting or crashing.
+pub struct LockFile {
+    /// The open file, the lock is released when it gets dropped.
+    _file: File,
+    pub shared: bool,
+}
+
+impl LockFile {
+    fn open(name: &str) -> io::Result<File> {
+        let directory = lock_directory()?;
+
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(directory.join(Self::file_name(name)))
+    }
+
+    /// Derives a portable file name from the name of a lock.
+    ///
+    /// Characters that aren't safe in file names are escaped. Very long names are hashed instead,
+    /// since most file systems limit the length of a path segment.
+    fn file_name(name: &str) -> String {
+        const MAX_ESCAPED_LENGTH: usize = 128;
+
+        let mut escaped = String::new();
+        for byte in name.bytes() {
+            if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+                escaped.push(byte as char);
+            } else {
+                write!(escaped, "%{byte:02x}").unwrap();
+            }
+        }
+
+        if escaped.len() > MAX_ESCAPED_LENGTH {
+            // FNV-1a, which unlike the hashers in std is guaranteed to be stable across processes
+            // and compiler versions.
+            let mut hash: u64 = 0xcbf29ce484222325;
+            for byte in name.bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+
+            escaped.truncate(MAX_ESCAPED_LENGTH - 17);
+            // Don't leave a partial escape sequence behind.
+            if let Some(idx) = escaped[escaped.len().saturating_sub(2)..].find('%') {
+                escaped.truncate(escaped.len() - 2 + idx);
+            }
+            write!(escaped, "~{hash:016x}").unwrap();
+        }
+
+        escaped.push_str(".lock");
+        escaped
+    }
+
+    /// Attempts to lock the file for `name` without blocking.
+    ///
+    /// Returns `None` if another process holds a conflicting lock.
+    pub fn try_acquire(name: &str, shared: bool) -> io::Result<Option<Self>> {
+        let file = Self::open(name)?;
+        let result = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+
+        match result {
+            Ok(()) => Ok(Some(Self {
+                _file: file,
+                shared,
+            })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// Locks the file for `name`, blocking the current thread until other processes release
+    /// conflicting locks.
+    pub fn acquire(name: &str, shared: bool) -> io::Result<Self> {
+        let file = Self::open(name)?;
+        if shared {
+            file.lock_shared()?;
+        } else {
+            file.lock()?;
+        }
+
+        Ok(Self {
+            _file: file,
+            shared,
+        })
+    }
+}
+
+/// The state of the lock file backing a [crate::state::LockState].
+#[derive(Default)]
+pub enum LockFileSlot {
+    /// The lock file is not currently locked by this process.
+    #[default]
+    Unlocked,
+    /// A background thread is blocked on acquiring the lock file for a pending request, the queue
+    /// must not make progress until it completes.
+    Waiting(Weak<LockRequest>),
+    /// This process holds the lock file.
+    Held(LockFile),
+}
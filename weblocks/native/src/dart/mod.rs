@@ -1,7 +1,9 @@
 mod dl;
+mod native_port;
 mod object;
 
 pub use dl::DartApi;
+pub use native_port::{NativeMessage, NativePort};
 pub use object::DartObject;
 
 /// A wrapper around a native `SendPort`.
@@ -10,6 +12,10 @@ pub use object::DartObject;
 pub struct DartPort(i64);
 
 impl DartPort {
+    /// Dart's `ILLEGAL_PORT`, for requests that don't need to be notified. Posting messages to it
+    /// always fails.
+    pub const ILLEGAL: DartPort = DartPort(0);
+
     /// Sends a `message` to this port.
     ///
     /// Returns true if the external contents in `message` have been moved to Dart, false otherwise.
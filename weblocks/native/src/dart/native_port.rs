@@ -0,0 +1,170 @@
+#![allow(non_upper_case_globals)]
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+};
+
+use lazy_static::lazy_static;
+
+use crate::dart::{
+    DartApi, DartPort,
+    object::{
+        Dart_CObject_Type_Dart_CObject_kArray, Dart_CObject_Type_Dart_CObject_kBool,
+        Dart_CObject_Type_Dart_CObject_kInt64, Dart_CObject_Type_Dart_CObject_kString,
+        RawDartCObject,
+    },
+};
+
+/// A message posted to a [NativePort], decoded from the Dart object that would otherwise have been
+/// sent to an isolate.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NativeMessage {
+    Null,
+    Bool(bool),
+    Int(i64),
+    String(String),
+    Array(Vec<NativeMessage>),
+}
+
+impl NativeMessage {
+    /// For array messages, returns the first entry if it's a string. All events posted by the lock
+    /// manager start with their kind.
+    pub fn kind(&self) -> Option<&str> {
+        match self {
+            Self::Array(entries) => match entries.first() {
+                Some(Self::String(kind)) => Some(kind),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    unsafe fn decode(raw: &RawDartCObject) -> Self {
+        unsafe {
+            // Safety: The union field is determined by the type, and we only decode objects that
+            // have been constructed by a [crate::dart::DartObject].
+            match raw.type_ {
+                Dart_CObject_Type_Dart_CObject_kBool => Self::Bool(raw.value.as_bool),
+                Dart_CObject_Type_Dart_CObject_kInt64 => Self::Int(raw.value.as_int64),
+                Dart_CObject_Type_Dart_CObject_kString => Self::String(
+                    CStr::from_ptr(raw.value.as_string)
+                        .to_string_lossy()
+                        .into_owned(),
+                ),
+                Dart_CObject_Type_Dart_CObject_kArray => {
+                    let array = raw.value.as_array;
+                    let values = std::slice::from_raw_parts(array.values, array.length as usize);
+                    Self::Array(values.iter().map(|value| Self::decode(&**value)).collect())
+                }
+                _ => Self::Null,
+            }
+        }
+    }
+}
+
+type Handler = Arc<dyn Fn(NativeMessage) + Send + Sync>;
+
+lazy_static! {
+    /// Handlers of all open [NativePort]s, by their id.
+    static ref HANDLERS: Mutex<HashMap<i64, Handler>> = Mutex::new(HashMap::new());
+    /// Messages posted to [NativePort]s, delivered by a single background thread.
+    static ref DELIVERIES: Sender<(i64, NativeMessage)> = start_delivery();
+}
+
+/// A port delivering messages to a Rust closure instead of a Dart isolate.
+///
+/// Native ports are used with a [LockClient](crate::LockClient) created with [DartApi::native], so
+/// that locks can be requested from threads that aren't running Dart. Like messages sent to a
+/// `SendPort`, messages are queued when they're posted, which typically happens while internal
+/// locks of the lock manager are held. A background thread then calls the handlers in the order
+/// in which messages have been posted, so handlers may call into the lock manager. Since they
+/// delay the delivery of all other messages, they must not block.
+///
+/// The port is closed when this is dropped. Messages posted to closed ports are dropped, as they
+/// would be for a closed `ReceivePort`.
+pub struct NativePort {
+    id: i64,
+}
+
+impl NativePort {
+    pub fn new(handler: impl Fn(NativeMessage) + Send + Sync + 'static) -> Self {
+        static NEXT_ID: AtomicI64 = AtomicI64::new(1);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLERS.lock().unwrap().insert(id, Arc::new(handler));
+        Self { id }
+    }
+
+    pub fn port(&self) -> DartPort {
+        DartPort(self.id)
+    }
+}
+
+impl Drop for NativePort {
+    fn drop(&mut self) {
+        HANDLERS.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl DartApi {
+    /// An API posting messages to [NativePort]s instead of Dart isolates.
+    pub fn native() -> Self {
+        Self {
+            post_object: post_native,
+        }
+    }
+
+    /// Whether this API has been created with [Self::native], so that its clients are notified
+    /// through [NativePort]s.
+    pub fn is_native(&self) -> bool {
+        std::ptr::fn_addr_eq(
+            self.post_object,
+            post_native as unsafe extern "C" fn(i64, *mut RawDartCObject) -> bool,
+        )
+    }
+}
+
+#[cfg(test)]
+impl DartApi {
+    /// An API dropping all messages, standing in for the API of a Dart isolate in tests.
+    pub fn discarding() -> Self {
+        unsafe extern "C" fn discard(_port_id: i64, _message: *mut RawDartCObject) -> bool {
+            false
+        }
+
+        Self {
+            post_object: discard,
+        }
+    }
+}
+
+unsafe extern "C" fn post_native(port_id: i64, message: *mut RawDartCObject) -> bool {
+    if !HANDLERS.lock().unwrap().contains_key(&port_id) {
+        return false;
+    }
+
+    let message = unsafe { NativeMessage::decode(&*message) };
+    DELIVERIES.send((port_id, message)).is_ok()
+}
+
+fn start_delivery() -> Sender<(i64, NativeMessage)> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || deliver(receiver));
+    sender
+}
+
+fn deliver(messages: Receiver<(i64, NativeMessage)>) {
+    for (port_id, message) in messages {
+        // Clone the handler so that it doesn't run while we're holding the registry. Messages to
+        // ports that have been closed in the meantime are dropped.
+        let handler = HANDLERS.lock().unwrap().get(&port_id).cloned();
+        if let Some(handler) = handler {
+            handler(message);
+        }
+    }
+}
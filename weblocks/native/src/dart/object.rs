@@ -63,6 +63,18 @@ impl From<bool> for DartObject<'static> {
     }
 }
 
+impl From<i64> for DartObject<'static> {
+    fn from(value: i64) -> Self {
+        Self {
+            raw: RawDartCObject {
+                type_: Dart_CObject_Type_Dart_CObject_kInt64,
+                value: RawDartCObjectValue { as_int64: value },
+            },
+            references: PhantomData,
+        }
+    }
+}
+
 #[repr(C)]
 pub struct RawDartCObject {
     pub type_: c_int,
@@ -93,5 +105,6 @@ pub struct RawDartCObjectArray {
 
 pub const Dart_CObject_Type_Dart_CObject_kNull: c_int = 0;
 pub const Dart_CObject_Type_Dart_CObject_kBool: c_int = 1;
+pub const Dart_CObject_Type_Dart_CObject_kInt64: c_int = 3;
 pub const Dart_CObject_Type_Dart_CObject_kString: c_int = 5;
 pub const Dart_CObject_Type_Dart_CObject_kArray: c_int = 6;
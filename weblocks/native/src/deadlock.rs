@@ -0,0 +1,99 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    LockClient,
+    state::{LockRequest, LockState},
+};
+
+/// An edge in the wait-for graph: A client is waiting for a lock that can't be granted before
+/// another client makes progress.
+struct WaitsFor {
+    lock: String,
+    blocker: Arc<LockClient>,
+}
+
+/// A graph describing which [LockClient]s are waiting on which other clients.
+///
+/// Clients are identified by their address.
+#[derive(Default)]
+pub struct WaitForGraph {
+    edges: HashMap<*const LockClient, Vec<WaitsFor>>,
+}
+
+/// A step in a cycle found by [WaitForGraph::find_cycle].
+pub struct CycleEntry {
+    /// The lock that [Self::client] is waiting for.
+    pub lock: String,
+    pub client: Arc<LockClient>,
+}
+
+impl WaitForGraph {
+    /// Adds the edges for a lock to this graph.
+    pub fn add_lock(&mut self, lock: &LockState) {
+        lock.wait_for_edges(|waiting, blocker| {
+            self.edges
+                .entry(Arc::as_ptr(waiting))
+                .or_default()
+                .push(WaitsFor {
+                    lock: lock.name.clone(),
+                    blocker: blocker.clone(),
+                });
+        });
+    }
+
+    /// Finds a cycle in the graph that passes through the client of `request`, starting with the
+    /// lock `request` is waiting for.
+    pub fn find_cycle(&self, request: &LockRequest) -> Option<Vec<CycleEntry>> {
+        let start = Arc::as_ptr(&request.client);
+
+        // Depth-first search, tracking the path from the start.
+        let mut path: Vec<CycleEntry> = vec![];
+        let mut visited = vec![start];
+        let mut stack: Vec<(Arc<LockClient>, usize)> = vec![(request.client.clone(), 0)];
+
+        while let Some((client, next_edge)) = stack.last().cloned() {
+            let edges = self
+                .edges
+                .get(&Arc::as_ptr(&client))
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            // The first edge must be the one introduced by the request we're checking.
+            let edge = if stack.len() == 1 {
+                edges[next_edge..]
+                    .iter()
+                    .position(|e| e.lock == request.name)
+                    .map(|i| i + next_edge)
+            } else {
+                (next_edge < edges.len()).then_some(next_edge)
+            };
+
+            let Some(index) = edge else {
+                stack.pop();
+                path.pop();
+                continue;
+            };
+            stack.last_mut().unwrap().1 = index + 1;
+
+            let edge = &edges[index];
+            let entry = CycleEntry {
+                lock: edge.lock.clone(),
+                client,
+            };
+
+            let blocker = Arc::as_ptr(&edge.blocker);
+            if blocker == start {
+                path.push(entry);
+                return Some(path);
+            }
+
+            if !visited.contains(&blocker) {
+                visited.push(blocker);
+                path.push(entry);
+                stack.push((edge.blocker.clone(), 0));
+            }
+        }
+
+        None
+    }
+}
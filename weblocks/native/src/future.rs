@@ -0,0 +1,346 @@
+//! Lock requests awaited from async Rust code.
+//!
+//! Like blocking requests, these go through the same [crate::manager::LockManager] as requests
+//! from Dart. Events posted to their [NativePort] are stored and wake the task awaiting them, which
+//! doesn't depend on any particular executor.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{LockFailure, LockHandle, RequestEvent, dart::NativePort};
+
+/// Events of an async request, shared between its [NativePort] and the futures awaiting them.
+#[derive(Default)]
+pub(crate) struct RequestEvents {
+    inner: Mutex<EventsInner>,
+}
+
+#[derive(Default)]
+struct EventsInner {
+    /// How the request has completed, if it has.
+    outcome: Option<Result<(), LockFailure>>,
+    /// Why the lock has been lost after it was granted, if it has.
+    lost: Option<LockLoss>,
+    /// Tasks waiting for the request to complete or for the lock to be lost.
+    wakers: Vec<Waker>,
+}
+
+impl RequestEvents {
+    /// Creates a port storing events posted to it in `events`.
+    pub(crate) fn port(events: &Arc<Self>) -> NativePort {
+        let events = events.clone();
+        NativePort::new(move |message| {
+            if let Some(event) = RequestEvent::decode(&message) {
+                events.record(event);
+            }
+        })
+    }
+
+    fn record(&self, event: RequestEvent) {
+        let wakers = {
+            let mut inner = self.inner.lock().unwrap();
+            let granted = matches!(inner.outcome, Some(Ok(())));
+            match event {
+                RequestEvent::Stolen => {
+                    inner.lost.get_or_insert(LockLoss::Stolen);
+                }
+                RequestEvent::Expired if granted => {
+                    inner.lost.get_or_insert(LockLoss::Expired);
+                }
+                RequestEvent::ShutDown if granted => {
+                    inner.lost.get_or_insert(LockLoss::ShutDown);
+                }
+                // The first event posted to a new request decides its outcome.
+                _ if inner.outcome.is_some() => return,
+                RequestEvent::Locked { .. } => inner.outcome = Some(Ok(())),
+                RequestEvent::Unavailable => inner.outcome = Some(Err(LockFailure::Unavailable)),
+                RequestEvent::TimedOut => inner.outcome = Some(Err(LockFailure::TimedOut)),
+                RequestEvent::Deadlock(_) => inner.outcome = Some(Err(LockFailure::Deadlock)),
+                RequestEvent::ShutDown => inner.outcome = Some(Err(LockFailure::ShutDown)),
+                RequestEvent::Expired | RequestEvent::Failed(_) => {
+                    inner.outcome = Some(Err(LockFailure::Failed))
+                }
+            }
+            std::mem::take(&mut inner.wakers)
+        };
+
+        // Don't wake tasks while holding our own lock in case an executor polls them right away.
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Registers the task of `cx` to be woken by the next event.
+    fn register(inner: &mut EventsInner, cx: &Context) {
+        if !inner.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            inner.wakers.push(cx.waker().clone());
+        }
+    }
+}
+
+/// A pending request made with [Client::request](crate::Client::request), resolving once the lock
+/// has been granted.
+///
+/// The request is made when the future is created, not when it's first polled. Dropping the future
+/// before it completes cancels the request.
+#[must_use = "the request is cancelled when the future is dropped"]
+pub struct LockFuture {
+    handle: Option<LockHandle>,
+    events: Arc<RequestEvents>,
+}
+
+impl LockFuture {
+    pub(crate) fn new(handle: LockHandle, events: Arc<RequestEvents>) -> Self {
+        Self {
+            handle: Some(handle),
+            events,
+        }
+    }
+}
+
+impl Future for LockFuture {
+    type Output = Result<LockGuard, LockFailure>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let outcome = {
+            let mut inner = self.events.inner.lock().unwrap();
+            match inner.outcome {
+                Some(outcome) => outcome,
+                None => {
+                    RequestEvents::register(&mut inner, cx);
+                    return Poll::Pending;
+                }
+            }
+        };
+
+        let handle = self
+            .handle
+            .take()
+            .expect("LockFuture polled after completion");
+        Poll::Ready(outcome.map(|()| LockGuard {
+            handle,
+            events: self.events.clone(),
+        }))
+    }
+}
+
+/// Why a [LockGuard] no longer holds its lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockLoss {
+    /// The lock has been stolen by another request.
+    Stolen,
+    /// The [LockOptions::lease](crate::LockOptions::lease) of the lock has expired.
+    Expired,
+    /// The [Client](crate::Client) has been shut down.
+    ShutDown,
+}
+
+/// A lock obtained by awaiting a [LockFuture]. The lock is released when this is dropped.
+pub struct LockGuard {
+    handle: LockHandle,
+    events: Arc<RequestEvents>,
+}
+
+impl LockGuard {
+    /// The name of the held lock.
+    pub fn name(&self) -> &str {
+        self.handle.name()
+    }
+
+    /// Renews the [LockOptions::lease](crate::LockOptions::lease) of this lock.
+    pub fn renew(&self) {
+        self.handle.renew();
+    }
+
+    /// Whether this lock has been stolen by another request.
+    pub fn is_stolen(&self) -> bool {
+        self.lost_reason() == Some(LockLoss::Stolen)
+    }
+
+    /// Returns a future completing once this lock has been stolen by another request.
+    ///
+    /// Locks can also be lost for other reasons, which are reported by [Self::lost].
+    pub fn stolen(&self) -> Stolen<'_> {
+        Stolen { guard: self }
+    }
+
+    /// Why this lock is no longer held, if it has been lost.
+    pub fn lost_reason(&self) -> Option<LockLoss> {
+        self.events.inner.lock().unwrap().lost
+    }
+
+    /// Returns a future completing once this lock is no longer held, because it has been stolen,
+    /// because its lease has expired or because the client has been shut down.
+    pub fn lost(&self) -> Lost<'_> {
+        Lost { guard: self }
+    }
+
+    /// Releases the lock.
+    pub fn release(self) {}
+}
+
+/// The future returned by [LockGuard::stolen].
+pub struct Stolen<'a> {
+    guard: &'a LockGuard,
+}
+
+impl Future for Stolen<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut inner = self.guard.events.inner.lock().unwrap();
+        if inner.lost == Some(LockLoss::Stolen) {
+            Poll::Ready(())
+        } else {
+            RequestEvents::register(&mut inner, cx);
+            Poll::Pending
+        }
+    }
+}
+
+/// The future returned by [LockGuard::lost].
+pub struct Lost<'a> {
+    guard: &'a LockGuard,
+}
+
+impl Future for Lost<'_> {
+    type Output = LockLoss;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<LockLoss> {
+        let mut inner = self.guard.events.inner.lock().unwrap();
+        match inner.lost {
+            Some(reason) => Poll::Ready(reason),
+            None => {
+                RequestEvents::register(&mut inner, cx);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Condvar,
+        task::Wake,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{Client, LockOptions};
+
+    /// A waker counting how often it has been woken.
+    #[derive(Default)]
+    struct CountingWaker {
+        wakes: Mutex<usize>,
+        woken: Condvar,
+    }
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            *self.wakes.lock().unwrap() += 1;
+            self.woken.notify_all();
+        }
+    }
+
+    /// Polls `future` with a manual waker until it completes, failing if it isn't woken in time.
+    fn poll_until_ready<F: Future + Unpin>(future: &mut F) -> F::Output {
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            let wakes = *counter.wakes.lock().unwrap();
+            if let Poll::Ready(output) = Pin::new(&mut *future).poll(&mut cx) {
+                return output;
+            }
+
+            let (_wakes, result) = counter
+                .woken
+                .wait_timeout_while(
+                    counter.wakes.lock().unwrap(),
+                    Duration::from_secs(5),
+                    |current| *current == wakes,
+                )
+                .unwrap();
+            assert!(!result.timed_out(), "the future has not been woken");
+        }
+    }
+
+    fn is_pending<F: Future + Unpin>(future: &mut F) -> bool {
+        let waker = Waker::from(Arc::new(CountingWaker::default()));
+        Pin::new(future)
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending()
+    }
+
+    #[test]
+    fn resolves_once_granted() {
+        let a = Client::namespaced("a", "future_resolves_once_granted");
+        let b = Client::namespaced("b", "future_resolves_once_granted");
+
+        let held = poll_until_ready(&mut a.request("lock", &Default::default())).unwrap();
+        let mut waiting = b.request("lock", &Default::default());
+        assert!(is_pending(&mut waiting));
+
+        drop(held);
+        let guard = poll_until_ready(&mut waiting).unwrap();
+        assert_eq!(guard.name(), "lock");
+        assert_eq!(guard.lost_reason(), None);
+    }
+
+    #[test]
+    fn reports_stolen_locks() {
+        let a = Client::namespaced("a", "future_reports_stolen_locks");
+        let b = Client::namespaced("b", "future_reports_stolen_locks");
+
+        let guard = poll_until_ready(&mut a.request("lock", &Default::default())).unwrap();
+        let mut stolen = guard.stolen();
+        assert!(is_pending(&mut stolen));
+
+        let options = LockOptions {
+            steal: true,
+            ..Default::default()
+        };
+        let _thief = poll_until_ready(&mut b.request("lock", &options)).unwrap();
+        poll_until_ready(&mut stolen);
+        assert_eq!(poll_until_ready(&mut guard.lost()), LockLoss::Stolen);
+        assert!(guard.is_stolen());
+    }
+
+    #[test]
+    fn reports_expired_leases() {
+        let client = Client::namespaced("a", "future_reports_expired_leases");
+        let options = LockOptions {
+            lease: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let guard = poll_until_ready(&mut client.request("lock", &options)).unwrap();
+        let mut lost = guard.lost();
+        assert_eq!(poll_until_ready(&mut lost), LockLoss::Expired);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(!guard.is_stolen());
+        assert!(is_pending(&mut guard.stolen()));
+    }
+
+    #[test]
+    fn reports_shutdown_after_grant() {
+        let client = Client::namespaced("a", "future_reports_shutdown_after_grant");
+
+        let guard = poll_until_ready(&mut client.request("lock", &Default::default())).unwrap();
+        let mut lost = guard.lost();
+        assert!(is_pending(&mut lost));
+
+        client.shutdown();
+        assert_eq!(poll_until_ready(&mut lost), LockLoss::ShutDown);
+        assert_eq!(
+            poll_until_ready(&mut client.request("other", &Default::default())).err(),
+            Some(LockFailure::ShutDown)
+        );
+    }
+}
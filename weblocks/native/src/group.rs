@@ -0,0 +1,104 @@
+use std::{
+    ffi::CStr,
+    sync::{Arc, Weak},
+};
+
+use crate::{
+    LockClient,
+    dart::{DartObject, DartPort},
+    state::{LockRequest, LockRequestState},
+};
+
+/// A request for multiple locks that are granted together or not at all.
+///
+/// Each lock is requested by a member [LockRequest] pointing back to this group. Members don't
+/// wait in the queue of their lock, so a group never holds some of its locks while waiting for
+/// others. Instead, the [crate::manager::LockManager] grants all members at once as soon as every
+/// lock is available and has no pending requests made before the group. Conflicting requests made
+/// after the group wait for it, so that it isn't starved by contended locks.
+///
+/// Members share the `SendPort` of the group, which receives a single `locked` event once all
+/// locks have been granted. Events for individual members (like `stolen`) are posted to the same
+/// port.
+pub struct LockGroup {
+    /// The client issueing the request.
+    pub(crate) client: Arc<LockClient>,
+    pub members: Vec<Arc<LockRequest>>,
+    /// The `SendPort` to send events to.
+    pub notify: DartPort,
+    /// The current state of this group as it progresses.
+    pub holds_locks: LockRequestState,
+}
+
+impl LockGroup {
+    /// Creates a group requesting the given locks, described by their name and whether they're
+    /// requested in shared mode.
+    pub(crate) fn new(
+        client: Arc<LockClient>,
+        locks: Vec<(String, bool)>,
+        notify: DartPort,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|group: &Weak<Self>| {
+            let members = locks
+                .into_iter()
+                .map(|(name, shared)| {
+                    Arc::new(LockRequest {
+                        name,
+                        client: client.clone(),
+                        shared,
+                        steal: false,
+                        if_available: false,
+                        cross_process: false,
+                        detect_deadlocks: false,
+                        reentrant: false,
+                        owner: None,
+                        deadline: None,
+                        lease: None,
+                        lease_expires: Default::default(),
+                        group: Some(group.clone()),
+                        notify,
+                        holds_lock: Default::default(),
+                        timeline: Default::default(),
+                        timer: Default::default(),
+                    })
+                })
+                .collect();
+
+            Self {
+                client,
+                members,
+                notify,
+                holds_locks: Default::default(),
+            }
+        })
+    }
+
+    /// Notifies the attached Dart port that all locks have been granted.
+    pub fn notify_locked(&self) -> bool {
+        let locked = c"locked".into();
+        let mut parts = [&locked];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that the group has been cancelled because its client has
+    /// been shut down.
+    pub fn notify_shut_down(&self) -> bool {
+        let shutdown = c"shutdown".into();
+        let mut parts = [&shutdown];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that the group can't be granted.
+    pub fn notify_failed(&self, reason: &CStr) -> bool {
+        let failed = c"error".into();
+        let reason = reason.into();
+        let mut parts = [&failed, &reason];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+}
@@ -1,25 +1,57 @@
 use std::{
+    collections::HashMap,
     ffi::{CString, c_void},
+    mem::ManuallyDrop,
+    path::PathBuf,
     rc::Rc,
-    sync::Arc,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use lazy_static::lazy_static;
 
 use crate::{
     dart::{DartApi, DartObject, DartPort},
+    group::LockGroup,
     manager::LockManager,
+    semaphore::SemaphoreRequest,
     state::LockRequest,
+    watcher::LockWatcher,
 };
 
+pub use crate::{
+    api::{BroadcastChannel, Client, LockHandle, LockInfo, LockOptions, RequestEvent},
+    blocking::LockFailure,
+    future::{LockFuture, LockGuard, LockLoss, Lost, Stolen},
+    state::QueuePolicy,
+};
+
+mod api;
+mod blocking;
 mod broadcast_channel;
+mod channel_transport;
+mod cross_process;
 mod dart;
+mod deadlock;
+mod future;
+mod group;
 mod manager;
+mod metrics;
+mod openmetrics;
+mod semaphore;
 mod state;
+mod timer;
+mod watcher;
 
 lazy_static! {
-    /// The global [LockManager] instance managing all named locks for the process.
-    static ref LOCKS: LockManager = LockManager::default();
+    /// The global [LockManager] instance managing all named locks for the process, used by clients
+    /// that haven't been bound to another manager.
+    static ref LOCKS: Arc<LockManager> = Arc::new(LockManager::default());
+    /// Isolated managers created by [pkg_weblocks_manager_new], by their name.
+    static ref MANAGERS: Mutex<HashMap<String, Weak<LockManager>>> = Mutex::new(HashMap::new());
 }
 
 /// A lock client, typically there'll be one per isolate.
@@ -27,9 +59,25 @@ struct LockClient {
     /// The name of the client as registered in Dart.
     name: String,
     pub(crate) api: DartApi,
+    /// The manager handling requests of this client.
+    pub(crate) manager: Arc<LockManager>,
+    /// Set by [pkg_weblocks_client_shutdown], after which new requests are cancelled right away.
+    shut_down: AtomicBool,
 }
 
 impl LockClient {
+    fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Marks this client as shut down and cancels all of its requests, unless that has happened
+    /// before.
+    fn shut_down(self: &Arc<Self>) {
+        if !self.shut_down.swap(true, Ordering::SeqCst) {
+            self.manager.shut_down_client(self);
+        }
+    }
+
     unsafe fn increment_from_raw(raw: *const c_void) -> Arc<Self> {
         let client = raw.cast::<LockClient>();
         unsafe { Arc::increment_strong_count(client) };
@@ -45,28 +93,144 @@ struct RequestSnapshot {
     client_id: CString,
     exclusive: bool,
     held: bool,
+    policy: QueuePolicy,
+    /// The id of the request, see [state::RequestTimeline::id].
+    id: u64,
+    enqueued_at: SystemTime,
+    granted_at: Option<SystemTime>,
+    /// For pending requests, the index of the request in the queue of the lock.
+    queue_position: Option<usize>,
+    steal: bool,
+    if_available: bool,
+    /// For semaphores, the amount of permits taken or requested.
+    permits: Option<u32>,
+    /// For semaphores, the total amount of permits.
+    max_permits: Option<u32>,
+    /// Whether this describes an intention lock held on an ancestor of a hierarchical lock (IX if
+    /// [Self::exclusive], IS otherwise).
+    intention: bool,
+}
+
+/// Returns a handle to the isolated [LockManager] called `name`, creating it if necessary.
+///
+/// Clients bound to the same manager share locks, semaphores and broadcast channels. These are
+/// independent of other managers, including the default one. The manager is dropped once all
+/// handles and clients bound to it have been freed.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_manager_new(name_length: isize, name: *const u8) -> *const c_void {
+    let name = unsafe {
+        // Safety: Dart passes a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize))
+    };
+
+    Arc::into_raw(namespace_manager(name)).cast()
+}
+
+/// Returns the isolated [LockManager] called `name`, creating it if necessary.
+fn namespace_manager(name: &str) -> Arc<LockManager> {
+    let mut managers = MANAGERS.lock().unwrap();
+    match managers.get(name).and_then(Weak::upgrade) {
+        Some(manager) => manager,
+        None => {
+            // Also forget other managers that have been dropped in the meantime.
+            managers.retain(|_, manager| manager.strong_count() > 0);
+
+            let manager = Arc::new(LockManager::isolated());
+            managers.insert(name.to_string(), Arc::downgrade(&manager));
+            manager
+        }
+    }
+}
+
+/// Destructor for [pkg_weblocks_manager_new].
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_manager_free(ptr: *const c_void) {
+    drop(unsafe {
+        // Safety: Dart will pass a pointer returned by [pkg_weblocks_manager_new].
+        Arc::from_raw(ptr.cast::<LockManager>())
+    });
 }
 
 /// Creates a new [LockClient] instance owned by the Dart caller.
+///
+/// The client is bound to the `manager` returned by [pkg_weblocks_manager_new], or to the default
+/// manager if it's null. The client keeps its manager alive, so the handle may be freed afterwards.
 #[unsafe(no_mangle)]
-pub extern "C" fn pkg_weblocks_client(
+extern "C" fn pkg_weblocks_client(
     name_length: isize,
     name: *const u8,
     api: *mut c_void,
+    manager: *const c_void,
 ) -> *const c_void {
     let api = unsafe { DartApi::from_raw(api) };
+    new_client(name_length, name, api, manager)
+}
 
+/// Creates a new [LockClient] for native threads, which use [pkg_weblocks_obtain_blocking]
+/// instead of Dart ports to obtain locks.
+///
+/// Like [pkg_weblocks_client], the client is bound to `manager` or to the default manager if it's
+/// null. It must be freed with [pkg_weblocks_free_client].
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_native_client(
+    name_length: isize,
+    name: *const u8,
+    manager: *const c_void,
+) -> *const c_void {
+    new_client(name_length, name, DartApi::native(), manager)
+}
+
+fn new_client(
+    name_length: isize,
+    name: *const u8,
+    api: DartApi,
+    manager: *const c_void,
+) -> *const c_void {
     let name = unsafe {
         std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize))
     }
     .to_string();
 
-    Arc::into_raw(Arc::new(LockClient { name, api })).cast()
+    let manager = if manager.is_null() {
+        LOCKS.clone()
+    } else {
+        let manager = manager.cast::<LockManager>();
+        unsafe {
+            // Safety: Callers pass a pointer returned by [pkg_weblocks_manager_new].
+            Arc::increment_strong_count(manager);
+            Arc::from_raw(manager)
+        }
+    };
+
+    let client = LockClient {
+        name,
+        api,
+        manager,
+        shut_down: AtomicBool::new(false),
+    };
+    Arc::into_raw(Arc::new(client)).cast()
+}
+
+/// Releases all locks and semaphore permits held by `client`, cancels its pending requests and
+/// removes its broadcast channel subscriptions and watchers.
+///
+/// Cancelled lock requests are notified with a `shutdown` event. Afterwards, the client can still
+/// be used, but new lock requests are cancelled with a `shutdown` event right away, semaphore
+/// requests fail and new channels and watchers don't receive messages. Calling this again has no
+/// effect.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_client_shutdown(client: *const c_void) {
+    let client = unsafe {
+        // Safety: Dart passes a pointer returned by [pkg_weblocks_client].
+        LockClient::increment_from_raw(client)
+    };
+
+    client.shut_down();
 }
 
 /// Destructor for [pkg_weblocks_client].
 #[unsafe(no_mangle)]
-pub extern "C" fn pkg_weblocks_free_client(ptr: *const c_void) {
+extern "C" fn pkg_weblocks_free_client(ptr: *const c_void) {
     drop(unsafe {
         // Safety: Dart will pass a pointer returned by [pkg_weblocks_client].
         Arc::from_raw(ptr.cast::<LockClient>())
@@ -75,20 +239,29 @@ pub extern "C" fn pkg_weblocks_free_client(ptr: *const c_void) {
 
 /// Obtains a lock via its name - see [LockRequest] for details.
 ///
+/// For requests with the reentrant flag, `owner` is an explicit owner token. When it's zero, the
+/// requesting client is the owner.
+///
+/// When `timeout_micros` is not negative, the request is removed from the queue if it couldn't be
+/// granted in that time. In that case, a `timeout` event is posted to `port`.
+///
+/// When `lease_micros` is not negative, the lock is released that long after it has been granted,
+/// unless the lease is renewed with [pkg_weblocks_renew]. In that case, an `expired` event is
+/// posted to `port`.
+///
 /// Returns an instance of the lock request so that a native finalizer can cancel it when it's no
 /// longer used.
 #[unsafe(no_mangle)]
-pub extern "C" fn pkg_weblocks_obtain(
+extern "C" fn pkg_weblocks_obtain(
     name_length: isize,
     name: *const u8,
     client: *const c_void,
     flags: u32,
+    owner: u64,
+    timeout_micros: i64,
+    lease_micros: i64,
     port: DartPort,
 ) -> *const c_void {
-    const FLAG_SHARED: u32 = 0x01;
-    const FLAG_STEAL: u32 = 0x02;
-    const FLAG_IF_AVAILABLE: u32 = 0x04;
-
     let name = unsafe {
         // Safety: Dart passes a valid utf8 buffer.
         std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize))
@@ -100,50 +273,906 @@ pub extern "C" fn pkg_weblocks_obtain(
         LockClient::increment_from_raw(client)
     };
 
-    let request = Arc::new(LockRequest {
+    let request = Arc::new(new_request(
+        name,
+        client,
+        flags,
+        owner,
+        timeout_micros,
+        lease_micros,
+        port,
+    ));
+
+    request.client.manager.lock(request.clone());
+    Arc::into_raw(request).cast()
+}
+
+const FLAG_SHARED: u32 = 0x01;
+const FLAG_STEAL: u32 = 0x02;
+const FLAG_IF_AVAILABLE: u32 = 0x04;
+const FLAG_CROSS_PROCESS: u32 = 0x08;
+const FLAG_DETECT_DEADLOCKS: u32 = 0x10;
+const FLAG_REENTRANT: u32 = 0x20;
+
+/// Written by [pkg_weblocks_obtain_blocking] for clients not created with
+/// [pkg_weblocks_native_client]. Other failures are described by [LockFailure].
+const FAILURE_NOT_NATIVE: u32 = 6;
+
+/// Creates a [LockRequest] from the arguments passed to [pkg_weblocks_obtain].
+fn new_request(
+    name: String,
+    client: Arc<LockClient>,
+    flags: u32,
+    owner: u64,
+    timeout_micros: i64,
+    lease_micros: i64,
+    port: DartPort,
+) -> LockRequest {
+    LockRequest {
         name,
         client,
         shared: (flags & FLAG_SHARED) != 0,
         steal: (flags & FLAG_STEAL) != 0,
         if_available: (flags & FLAG_IF_AVAILABLE) != 0,
+        cross_process: (flags & FLAG_CROSS_PROCESS) != 0,
+        detect_deadlocks: (flags & FLAG_DETECT_DEADLOCKS) != 0,
+        reentrant: (flags & FLAG_REENTRANT) != 0,
+        owner: (owner != 0).then_some(owner),
+        deadline: u64::try_from(timeout_micros)
+            .ok()
+            .map(|micros| Instant::now() + Duration::from_micros(micros)),
+        lease: u64::try_from(lease_micros).ok().map(Duration::from_micros),
+        lease_expires: Default::default(),
+        group: None,
+        holds_lock: Default::default(),
+        timeline: Default::default(),
+        timer: Default::default(),
+        notify: port,
+    }
+}
+
+/// Obtains a lock via its name if it's available right now, without waiting for it.
+///
+/// This decides synchronously, like `if_available` requests but without a round trip through
+/// `port`: If the lock can be granted, a request holding it is returned. It must be released with
+/// [pkg_weblocks_unlock] like requests returned by [pkg_weblocks_obtain]. Otherwise, this returns
+/// null.
+///
+/// The only supported flags are `0x01` to obtain the lock in shared mode and `0x08` to also lock
+/// the lock file of a cross-process lock, which counts as unavailable if the file can't be locked
+/// without waiting. `port` only receives events after the lock has been granted, like `stolen`. It
+/// may be `0` (`ILLEGAL_PORT`) if the caller isn't interested in them.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_try_obtain(
+    name_length: isize,
+    name: *const u8,
+    client: *const c_void,
+    flags: u32,
+    port: DartPort,
+) -> *const c_void {
+    let name = unsafe {
+        // Safety: Dart passes a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize))
+    }
+    .to_string();
+
+    let client = unsafe {
+        // Safety: Dart should only pass valid pointers.
+        LockClient::increment_from_raw(client)
+    };
+
+    let request = Arc::new(LockRequest {
+        name,
+        client,
+        shared: (flags & FLAG_SHARED) != 0,
+        steal: false,
+        if_available: true,
+        cross_process: (flags & FLAG_CROSS_PROCESS) != 0,
+        detect_deadlocks: false,
+        reentrant: false,
+        owner: None,
+        deadline: None,
+        lease: None,
+        lease_expires: Default::default(),
+        group: None,
         holds_lock: Default::default(),
+        timeline: Default::default(),
+        timer: Default::default(),
         notify: port,
     });
 
-    LOCKS.lock(request.clone());
-    return Arc::into_raw(request).cast();
+    if request.client.manager.try_lock(&request) {
+        return Arc::into_raw(request).cast();
+    }
+    std::ptr::null()
+}
+
+/// Obtains a lock from a native thread, blocking the thread until the lock is granted.
+///
+/// `client` must have been created with [pkg_weblocks_native_client]. The other arguments are
+/// interpreted like for [pkg_weblocks_obtain]. When `timeout_micros` is not negative, this stops
+/// waiting after that time. Since native threads can't be notified about a stolen lock, requests
+/// stealing the lock from native holders should be avoided.
+///
+/// Returns a request holding the lock, which must be released with [pkg_weblocks_unlock]. If the
+/// lock couldn't be obtained, returns null and writes the reason to `failure` (unless it's null):
+/// `1` if the request has timed out, `2` if an `if_available` request was unavailable, `3` for
+/// detected deadlocks, `4` if the client has been shut down, `5` for I/O errors and `6` if
+/// `client` is not a native client. These are declared in `include/dart_locks.h`.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_obtain_blocking(
+    name_length: isize,
+    name: *const u8,
+    client: *const c_void,
+    flags: u32,
+    owner: u64,
+    timeout_micros: i64,
+    failure: *mut u32,
+) -> *const c_void {
+    let name = unsafe {
+        // Safety: Callers pass a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize))
+    }
+    .to_string();
+
+    let client = unsafe {
+        // Safety: Callers pass a pointer returned by [pkg_weblocks_native_client].
+        LockClient::increment_from_raw(client)
+    };
+    if !client.api.is_native() {
+        // Events for clients of a Dart isolate would never reach this thread.
+        if let Some(failure) = unsafe { failure.as_mut() } {
+            *failure = FAILURE_NOT_NATIVE;
+        }
+        return std::ptr::null();
+    }
+
+    let result = blocking::lock_blocking(|port| {
+        new_request(name, client, flags, owner, timeout_micros, -1, port)
+    });
+    match result {
+        Ok(request) => Arc::into_raw(request).cast(),
+        Err(reason) => {
+            if let Some(failure) = unsafe { failure.as_mut() } {
+                *failure = reason as u32;
+            }
+            std::ptr::null()
+        }
+    }
 }
 
 /// Destructor for [pkg_weblocks_obtain].
 #[unsafe(no_mangle)]
-pub extern "C" fn pkg_weblocks_unlock(ptr: *mut LockRequest) {
+extern "C" fn pkg_weblocks_unlock(ptr: *mut LockRequest) {
     let request = unsafe { Arc::from_raw(ptr) };
-    LOCKS.close_request(request);
+    request.client.manager.clone().close_request(request);
+}
+
+/// Obtains multiple locks at once - see [LockGroup] for details.
+///
+/// The names of the `count` locks are concatenated in `names`, with the length of each name in
+/// `name_lengths`. For each lock, `flags` contains `0x01` if it should be obtained in shared mode.
+///
+/// A single `locked` event is posted to `port` once all locks have been granted. If any of them
+/// is stolen afterwards, a `stolen` event is posted. A group requesting the same lock twice is
+/// rejected with an `error` event.
+///
+/// Returns an instance of the group so that a native finalizer can release all locks when it's
+/// no longer used. Empty groups are rejected by returning null without posting any event.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_obtain_all(
+    client: *const c_void,
+    count: usize,
+    names: *const u8,
+    name_lengths: *const isize,
+    flags: *const u32,
+    port: DartPort,
+) -> *const LockGroup {
+    if count == 0 {
+        // Dart may pass null pointers for empty arrays.
+        return std::ptr::null();
+    }
+
+    let (name_lengths, flags) = unsafe {
+        // Safety: Dart passes arrays with `count` entries.
+        (
+            std::slice::from_raw_parts(name_lengths, count),
+            std::slice::from_raw_parts(flags, count),
+        )
+    };
+    let total_length: isize = name_lengths.iter().sum();
+    let names = unsafe {
+        // Safety: Dart passes a valid utf8 buffer, and names only end on character boundaries.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(names, total_length as usize))
+    };
+
+    let mut locks = Vec::with_capacity(count);
+    let mut start = 0;
+    for (length, flags) in name_lengths.iter().zip(flags) {
+        let end = start + *length as usize;
+        locks.push((names[start..end].to_string(), (flags & FLAG_SHARED) != 0));
+        start = end;
+    }
+
+    let client = unsafe {
+        // Safety: Dart should only pass valid pointers.
+        LockClient::increment_from_raw(client)
+    };
+
+    let group = LockGroup::new(client, locks, port);
+    group.client.manager.clone().lock_group(group.clone());
+    Arc::into_raw(group)
+}
+
+/// Destructor for [pkg_weblocks_obtain_all], releasing all locks of the group.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_unlock_all(ptr: *const LockGroup) {
+    let group = unsafe {
+        // Safety: Dart passes a group returned by [pkg_weblocks_obtain_all] and then forgets
+        // about it.
+        Arc::from_raw(ptr)
+    };
+    group.client.manager.clone().close_group(&group);
+}
+
+/// Changes the [QueuePolicy] of the lock identified by `name` in the manager of `client`.
+///
+/// The policy is `0` for FIFO (the default), `1` for reader-preferring, `2` for writer-preferring
+/// and `3` for phase-fair. It applies until it's changed again, even if the lock is not in use.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_set_queue_policy(
+    client: *const c_void,
+    name_length: isize,
+    name: *const u8,
+    policy: u32,
+) {
+    let client = unsafe {
+        // Safety: Dart passes a [LockClient] that is valid for the duration of this call.
+        client.cast::<LockClient>().as_ref()
+    }
+    .unwrap();
+    let name = unsafe {
+        // Safety: Dart passes a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize))
+    };
+
+    if let Some(policy) = QueuePolicy::from_raw(policy) {
+        client.manager.set_policy(name, policy);
+    }
+}
+
+/// Aborts a request returned by [pkg_weblocks_obtain] - see [state::LockState::abort_request] for details.
+///
+/// Unlike [pkg_weblocks_unlock], this doesn't free the request.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_abort(ptr: *const LockRequest) {
+    let request = unsafe {
+        // Safety: Dart passes a request that is valid for the duration of this call.
+        ManuallyDrop::new(Arc::from_raw(ptr))
+    };
+    request.client.manager.abort_request(&request);
+}
+
+/// Renews the lease of a held request, see [state::LockState::renew_lease].
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_renew(ptr: *const LockRequest) {
+    let request = unsafe {
+        // Safety: Dart passes a request that is valid for the duration of this call.
+        ManuallyDrop::new(Arc::from_raw(ptr))
+    };
+    request.client.manager.renew_lease(&request);
+}
+
+/// Upgrades a held shared request to an exclusive lock - see [state::LockState::upgrade_request]
+/// for details.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_upgrade(ptr: *const LockRequest) {
+    let request = unsafe {
+        // Safety: Dart passes a request that is valid for the duration of this call.
+        ManuallyDrop::new(Arc::from_raw(ptr))
+    };
+    request.client.manager.upgrade_request(&request);
 }
 
-/// Requests a serialized snapshot of all locks to post to the `port`.
+/// Turns a held exclusive request into a shared lock without releasing it.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_downgrade(ptr: *const LockRequest) {
+    let request = unsafe {
+        // Safety: Dart passes a request that is valid for the duration of this call.
+        ManuallyDrop::new(Arc::from_raw(ptr))
+    };
+    request.client.manager.downgrade_request(&request);
+}
+
+/// Takes `permits` permits from the semaphore identified by `name`, which has `max_permits` permits
+/// in total - see [SemaphoreRequest] for details.
+///
+/// An `acquired` event is posted to `port` once the permits have been taken. If the semaphore is
+/// in use with a different amount of total permits, an `error` event is posted instead.
+///
+/// Returns an instance of the request so that a native finalizer can return the permits.
 #[unsafe(no_mangle)]
-pub extern "C" fn pkg_weblocks_snapshot(client: *const c_void, port: DartPort) {
+extern "C" fn pkg_weblocks_acquire_permits(
+    name_length: isize,
+    name: *const u8,
+    client: *const c_void,
+    max_permits: u32,
+    permits: u32,
+    port: DartPort,
+) -> *const c_void {
+    let name = unsafe {
+        // Safety: Dart passes a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize))
+    }
+    .to_string();
+
+    let client = unsafe {
+        // Safety: Dart should only pass valid pointers.
+        LockClient::increment_from_raw(client)
+    };
+
+    let request = Arc::new(SemaphoreRequest {
+        name,
+        client,
+        max_permits,
+        permits,
+        notify: port,
+        holds_permits: Default::default(),
+        timeline: Default::default(),
+    });
+
+    request.client.manager.acquire_permits(request.clone());
+    Arc::into_raw(request).cast()
+}
+
+/// Destructor for [pkg_weblocks_acquire_permits].
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_release_permits(ptr: *const SemaphoreRequest) {
+    let request = unsafe { Arc::from_raw(ptr) };
+    if request.holds_permits.mark_cancelled() {
+        request.client.manager.clone().release_permits(request);
+    }
+}
+
+/// Releases the exclusive lock held by a request and waits on a condition variable bound to the
+/// lock - see [state::LockState::wait_on_condition] for details.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_wait(
+    ptr: *const LockRequest,
+    condition_length: isize,
+    condition: *const u8,
+) {
+    let request = unsafe {
+        // Safety: Dart passes a request that is valid for the duration of this call.
+        ManuallyDrop::new(Arc::from_raw(ptr))
+    };
+    let condition = unsafe {
+        // Safety: Dart passes a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(
+            condition,
+            condition_length as usize,
+        ))
+    };
+
+    request
+        .client
+        .manager
+        .wait_on_condition(&request, condition.to_string());
+}
+
+/// Wakes up one or `all` requests waiting on the `condition` of the lock identified by `name` in
+/// the manager of `client`.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_notify(
+    client: *const c_void,
+    name_length: isize,
+    name: *const u8,
+    condition_length: isize,
+    condition: *const u8,
+    all: bool,
+) {
+    let client = unsafe {
+        // Safety: Dart passes a [LockClient] that is valid for the duration of this call.
+        client.cast::<LockClient>().as_ref()
+    }
+    .unwrap();
+    let (name, condition) = unsafe {
+        // Safety: Dart passes valid utf8 buffers.
+        (
+            std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize)),
+            std::str::from_utf8_unchecked(std::slice::from_raw_parts(
+                condition,
+                condition_length as usize,
+            )),
+        )
+    };
+
+    client.manager.notify_condition(name, condition, all);
+}
+
+/// Enables hierarchical lock names in the manager of `client`, using the given separator.
+///
+/// With a separator like `/`, holding `db/users/42` implies intention locks on `db` and `db/users`,
+/// so that it conflicts with incompatible requests for these ancestors. An empty separator disables
+/// hierarchical names again. Hierarchies only apply within this process, lock files of
+/// cross-process locks are not affected.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_set_hierarchy_separator(
+    client: *const c_void,
+    separator_length: isize,
+    separator: *const u8,
+) {
+    let client = unsafe {
+        // Safety: Dart passes a [LockClient] that is valid for the duration of this call.
+        client.cast::<LockClient>().as_ref()
+    }
+    .unwrap();
+    let separator = unsafe {
+        // Safety: Dart passes a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(
+            separator,
+            separator_length as usize,
+        ))
+    };
+
+    client
+        .manager
+        .set_hierarchy_separator(Some(separator).filter(|s| !s.is_empty()));
+}
+
+/// Sets the directory in which lock files for cross-process locks are stored.
+///
+/// Only processes using the same directory will see each other's locks.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_set_lock_directory(path_length: isize, path: *const u8) {
+    let path = unsafe {
+        // Safety: Dart passes a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(path, path_length as usize))
+    };
+
+    cross_process::set_lock_directory(PathBuf::from(path));
+}
+
+/// Registers a watcher posting changes to the lock called `name` in the manager of `client` to
+/// `port`. With `prefix`, all locks whose name starts with `name` are watched.
+///
+/// Events are posted as `[kind, lock name, client id, request id, exclusive]`, where the kind is
+/// one of `enqueued`, `granted`, `released`, `stolen` or `unavailable`. The watcher stays active
+/// until the returned handle is passed to [pkg_weblocks_unwatch].
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_watch(
+    client: *const c_void,
+    name_length: isize,
+    name: *const u8,
+    prefix: bool,
+    port: DartPort,
+) -> *const LockWatcher {
+    let pattern = unsafe {
+        // Safety: Dart passes a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_length as usize))
+    }
+    .to_string();
+    let client = unsafe {
+        // Safety: Dart passes a pointer returned by [pkg_weblocks_client].
+        LockClient::increment_from_raw(client)
+    };
+
+    let watcher = Arc::new(LockWatcher {
+        pattern,
+        prefix,
+        client,
+        notify: port,
+    });
+    watcher.client.manager.watchers.add(watcher.clone());
+    Arc::into_raw(watcher)
+}
+
+/// Destructor for [pkg_weblocks_watch], unregistering the watcher.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_unwatch(ptr: *const LockWatcher) {
+    let watcher = unsafe {
+        // Safety: Dart passes a watcher returned by [pkg_weblocks_watch] and then forgets about it.
+        Arc::from_raw(ptr)
+    };
+    watcher.client.manager.watchers.remove(&watcher);
+}
+
+/// Posts the contention metrics of all locks in the manager of `client` to `port`.
+///
+/// The message starts with the amount of histogram bucket bounds, followed by the bounds in
+/// microseconds. Then, each lock name is described by its name, the amount of acquisitions, steals
+/// and unavailable results, followed by its wait time and hold time histograms. Each histogram
+/// consists of its count, the sum of its durations in microseconds and one more bucket count than
+/// there are bounds. Merged metrics of names that haven't been used recently are reported with a
+/// null name.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_metrics(client: *const c_void, port: DartPort) {
+    let client = unsafe {
+        // Safety: Dart passes a [LockClient] that is valid for the duration of this call.
+        client.cast::<LockClient>().as_ref()
+    }
+    .unwrap();
+
+    let mut entries = Vec::<(Option<CString>, Vec<u64>)>::new();
+    client.manager.metrics.inspect(|name, metrics| {
+        let mut values = vec![metrics.acquisitions, metrics.steals, metrics.unavailable];
+        for histogram in [&metrics.wait_time, &metrics.hold_time] {
+            values.extend([histogram.count, histogram.sum_micros]);
+            values.extend(histogram.buckets);
+        }
+
+        entries.push((
+            name.map(|name| CString::new(name).unwrap_or_default()),
+            values,
+        ));
+    });
+
+    let mut serialized = vec![DartObject::from(metrics::BUCKET_BOUNDS.len() as i64)];
+    serialized.extend(metrics::BUCKET_BOUNDS.map(|bound| DartObject::from(bound as i64)));
+    for (name, values) in &entries {
+        serialized.push(match name {
+            Some(name) => DartObject::from(name.as_c_str()),
+            None => DartObject::NULL,
+        });
+        serialized.extend(values.iter().map(|value| DartObject::from(*value as i64)));
+    }
+
+    let mut double_indirection: Vec<&DartObject> = serialized.iter().collect();
+    port.send(&client.api, &mut DartObject::array(&mut double_indirection));
+}
+
+/// Posts the state and metrics of the manager of `client` to `port`, rendered in the OpenMetrics
+/// text format.
+///
+/// At most `max_lock_names` lock names and `max_channel_names` broadcast channel names are used as
+/// labels, see [openmetrics] for details.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_openmetrics(
+    client: *const c_void,
+    max_lock_names: u32,
+    max_channel_names: u32,
+    port: DartPort,
+) {
+    let client = unsafe {
+        // Safety: Dart passes a [LockClient] that is valid for the duration of this call.
+        client.cast::<LockClient>().as_ref()
+    }
+    .unwrap();
+
+    let limits = openmetrics::CardinalityLimits {
+        locks: max_lock_names as usize,
+        channels: max_channel_names as usize,
+    };
+    let text = CString::new(openmetrics::render(&client.manager, limits)).unwrap_or_default();
+    port.send(&client.api, &mut DartObject::from(text.as_c_str()));
+}
+
+/// Forgets the contention metrics recorded by the manager of `client`.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_reset_metrics(client: *const c_void) {
+    let client = unsafe {
+        // Safety: Dart passes a [LockClient] that is valid for the duration of this call.
+        client.cast::<LockClient>().as_ref()
+    }
+    .unwrap();
+
+    client.manager.metrics.reset();
+}
+
+/// Requests a serialized snapshot of all locks in the manager of `client` to post to the `port`.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_snapshot(client: *const c_void, port: DartPort) {
+    let client = unsafe {
+        // Safety: Dart passes a [LockClient] that is valid for the duration of this call.
+        client.cast::<LockClient>().as_ref()
+    }
+    .unwrap();
+
+    send_snapshot(client, SnapshotFilter::All, port);
+}
+
+/// Like [pkg_weblocks_snapshot], but only includes requests matching `filter`.
+///
+/// The `kind` is `0` to include requests on the lock or semaphore named `filter`, `1` for requests
+/// on names starting with `filter` and `2` for requests made by clients named `filter`. Unlike a
+/// full snapshot, filtering by name only inspects the matching locks.
+#[unsafe(no_mangle)]
+extern "C" fn pkg_weblocks_snapshot_filtered(
+    client: *const c_void,
+    kind: u32,
+    filter_length: isize,
+    filter: *const u8,
+    port: DartPort,
+) {
+    let client = unsafe {
+        // Safety: Dart passes a [LockClient] that is valid for the duration of this call.
+        client.cast::<LockClient>().as_ref()
+    }
+    .unwrap();
+    let filter = unsafe {
+        // Safety: Dart passes a valid utf8 buffer.
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(filter, filter_length as usize))
+    };
+
+    let filter = match kind {
+        0 => SnapshotFilter::Name(filter),
+        1 => SnapshotFilter::Prefix(filter),
+        2 => SnapshotFilter::Client(filter),
+        _ => return,
+    };
+    send_snapshot(client, filter, port);
+}
+
+/// Restricts the requests included in a snapshot.
+pub enum SnapshotFilter<'a> {
+    /// All requests.
+    All,
+    /// Only requests on the lock or semaphore with this name.
+    Name(&'a str),
+    /// Only requests on locks or semaphores whose name starts with this prefix.
+    Prefix(&'a str),
+    /// Only requests made by clients with this name.
+    Client(&'a str),
+}
+
+impl SnapshotFilter<'_> {
+    /// Whether requests on the lock or semaphore `name` may be included in the snapshot.
+    fn includes_name(&self, name: &str) -> bool {
+        match self {
+            Self::Name(filter) => name == *filter,
+            Self::Prefix(filter) => name.starts_with(filter),
+            Self::All | Self::Client(_) => true,
+        }
+    }
+
+    fn includes(&self, snapshot: &RequestSnapshot) -> bool {
+        match self {
+            Self::Client(filter) => snapshot.client_id.as_bytes() == filter.as_bytes(),
+            // Intention locks are reported on the ancestors of the inspected lock.
+            _ => self.includes_name(&snapshot.name.to_string_lossy()),
+        }
+    }
+}
+
+/// Describes the requests on locks and semaphores of `manager` that match `filter`.
+fn collect_snapshot(manager: &LockManager, filter: &SnapshotFilter) -> Vec<RequestSnapshot> {
     let mut descriptions = Vec::<RequestSnapshot>::new();
-    LOCKS.inspect(|state| {
+    let mut inspect = |state: &state::LockState| {
         state.snapshot_into(&mut descriptions);
+    };
+    match filter {
+        SnapshotFilter::Name(name) => manager.inspect_named(name, &mut inspect),
+        SnapshotFilter::Prefix(prefix) => {
+            manager.inspect_where(|name| name.starts_with(prefix), &mut inspect)
+        }
+        SnapshotFilter::All | SnapshotFilter::Client(_) => manager.inspect(&mut inspect),
+    }
+    manager.inspect_semaphores(|state| {
+        if filter.includes_name(&state.name) {
+            state.snapshot_into(&mut descriptions);
+        }
     });
+    descriptions.retain(|description| filter.includes(description));
+    descriptions
+}
 
-    let mut serialized_descriptions = Vec::<DartObject>::new();
+fn send_snapshot(client: &LockClient, filter: SnapshotFilter, port: DartPort) {
+    let descriptions = collect_snapshot(&client.manager, &filter);
+
+    // The snapshot starts with the amount of values per request. Fields are only ever appended to
+    // requests, so that readers can skip fields they don't know about.
+    let mut serialized_descriptions = vec![DartObject::from(SNAPSHOT_FIELDS as i64)];
     for description in &descriptions {
         serialized_descriptions.push(DartObject::from(description.name.as_c_str()));
         serialized_descriptions.push(DartObject::from(description.client_id.as_c_str()));
         serialized_descriptions.push(DartObject::from(description.exclusive));
         serialized_descriptions.push(DartObject::from(description.held));
+        serialized_descriptions.push(DartObject::from(description.policy.name()));
+        for value in [description.permits, description.max_permits] {
+            serialized_descriptions.push(match value {
+                Some(value) => DartObject::from(value as i64),
+                None => DartObject::NULL,
+            });
+        }
+        serialized_descriptions.push(DartObject::from(description.intention));
+        serialized_descriptions.push(DartObject::from(description.id as i64));
+        for time in [Some(description.enqueued_at), description.granted_at] {
+            serialized_descriptions.push(match time {
+                Some(time) => DartObject::from(micros_since_epoch(time)),
+                None => DartObject::NULL,
+            });
+        }
+        serialized_descriptions.push(match description.queue_position {
+            Some(position) => DartObject::from(position as i64),
+            None => DartObject::NULL,
+        });
+        serialized_descriptions.push(DartObject::from(description.steal));
+        serialized_descriptions.push(DartObject::from(description.if_available));
     }
 
-    let mut double_indirection: Vec<&DartObject> =
-        serialized_descriptions.iter().map(|r| r).collect();
+    let mut double_indirection: Vec<&DartObject> = serialized_descriptions.iter().collect();
+    port.send(&client.api, &mut DartObject::array(&mut double_indirection));
+}
 
-    let client = unsafe {
-        // Safety: Dart passes a [LockClient] that is valid for the duration of this call.
-        client.cast::<LockClient>().as_ref()
+/// The amount of values serialized for each [RequestSnapshot].
+const SNAPSHOT_FIELDS: usize = 14;
+
+fn micros_since_epoch(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::c_void,
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    fn manager(name: &str) -> *const c_void {
+        pkg_weblocks_manager_new(name.len() as isize, name.as_ptr())
+    }
+
+    fn native_client(manager: *const c_void) -> *const c_void {
+        pkg_weblocks_native_client(6, b"native".as_ptr(), manager)
+    }
+
+    /// Calls [pkg_weblocks_obtain_blocking], returning the request or the failure code.
+    fn obtain(
+        client: *const c_void,
+        name: &str,
+        flags: u32,
+        timeout_micros: i64,
+    ) -> Result<*const c_void, u32> {
+        let mut failure = 0;
+        let request = pkg_weblocks_obtain_blocking(
+            name.len() as isize,
+            name.as_ptr(),
+            client,
+            flags,
+            0,
+            timeout_micros,
+            &mut failure,
+        );
+        if request.is_null() {
+            Err(failure)
+        } else {
+            Ok(request)
+        }
+    }
+
+    fn unlock(request: *const c_void) {
+        pkg_weblocks_unlock(request.cast_mut().cast());
+    }
+
+    #[test]
+    fn blocks_until_released_on_other_thread() {
+        let manager = manager("blocks_until_released_on_other_thread");
+        let client = native_client(manager) as usize;
+        let held = obtain(client as _, "a", 0, -1).unwrap() as usize;
+
+        let (sender, receiver) = mpsc::channel();
+        let waiter = thread::spawn(move || {
+            let request = obtain(client as _, "a", 0, -1).unwrap();
+            sender.send(()).unwrap();
+            unlock(request);
+        });
+
+        // The waiting thread must not obtain the lock while it's held.
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        unlock(held as _);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiter.join().unwrap();
+
+        pkg_weblocks_free_client(client as _);
+        pkg_weblocks_manager_free(manager);
+    }
+
+    #[test]
+    fn reports_failures() {
+        let manager = manager("reports_failures");
+        let client = native_client(manager);
+        let held = obtain(client, "a", 0, -1).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(
+            obtain(client, "a", 0, 10_000),
+            Err(LockFailure::TimedOut as u32)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert_eq!(
+            obtain(client, "a", FLAG_IF_AVAILABLE, -1),
+            Err(LockFailure::Unavailable as u32)
+        );
+
+        // Shutting down a client from another thread cancels its waiting requests.
+        let waiting = native_client(manager) as usize;
+        let waiter = thread::spawn(move || obtain(waiting as _, "a", 0, -1).map(|r| r as usize));
+        thread::sleep(Duration::from_millis(50));
+        pkg_weblocks_client_shutdown(waiting as _);
+        assert_eq!(waiter.join().unwrap(), Err(LockFailure::ShutDown as u32));
+        assert_eq!(
+            obtain(waiting as _, "b", 0, -1),
+            Err(LockFailure::ShutDown as u32)
+        );
+
+        unlock(held);
+        pkg_weblocks_free_client(waiting as _);
+        pkg_weblocks_free_client(client);
+        pkg_weblocks_manager_free(manager);
+    }
+
+    #[test]
+    fn snapshots_report_downgrades() {
+        let manager = manager("snapshots_report_downgrades");
+        let client = native_client(manager);
+        let held = obtain(client, "a", 0, -1).unwrap();
+        let exclusive = || {
+            let manager = unsafe { &*manager.cast::<LockManager>() };
+            let snapshot = collect_snapshot(manager, &SnapshotFilter::All);
+            assert_eq!(snapshot.len(), 1);
+            snapshot[0].exclusive
+        };
+
+        assert!(exclusive());
+        pkg_weblocks_downgrade(held.cast());
+        assert!(!exclusive());
+        pkg_weblocks_upgrade(held.cast());
+        assert!(exclusive());
+
+        unlock(held);
+        pkg_weblocks_free_client(client);
+        pkg_weblocks_manager_free(manager);
+    }
+
+    #[test]
+    fn openmetrics_escapes_nul_bytes() {
+        let manager = manager("openmetrics_escapes_nul_bytes");
+        let client = native_client(manager);
+        let held = obtain(client, "a\0b", 0, -1).unwrap();
+
+        let limits = openmetrics::CardinalityLimits {
+            locks: 10,
+            channels: 10,
+        };
+        let text = openmetrics::render(unsafe { &*manager.cast::<LockManager>() }, limits);
+        assert!(!text.contains('\0'));
+        assert!(text.contains("name=\"a\u{FFFD}b\""));
+
+        unlock(held);
+        pkg_weblocks_free_client(client);
+        pkg_weblocks_manager_free(manager);
+    }
+
+    #[test]
+    fn isolated_managers_reject_cross_process_try_locks() {
+        let manager = manager("isolated_managers_reject_cross_process_try_locks");
+        let client = native_client(manager);
+        let try_obtain =
+            |flags| pkg_weblocks_try_obtain(1, b"a".as_ptr(), client, flags, DartPort::ILLEGAL);
+
+        assert!(try_obtain(FLAG_CROSS_PROCESS).is_null());
+        let held = try_obtain(0);
+        assert!(!held.is_null());
+
+        unlock(held);
+        pkg_weblocks_free_client(client);
+        pkg_weblocks_manager_free(manager);
+    }
+
+    #[test]
+    fn rejects_dart_clients() {
+        let manager = manager("rejects_dart_clients");
+        let client = new_client(4, b"dart".as_ptr(), DartApi::discarding(), manager);
+        assert_eq!(obtain(client, "a", 0, -1), Err(FAILURE_NOT_NATIVE));
+
+        pkg_weblocks_free_client(client);
+        pkg_weblocks_manager_free(manager);
     }
-    .unwrap();
-    port.send(&client.api, &mut DartObject::array(&mut double_indirection));
 }
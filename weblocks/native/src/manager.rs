@@ -1,51 +1,966 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    ffi::CStr,
+    hash::{BuildHasher, RandomState},
+    io,
+    sync::{
+        Arc, Mutex, MutexGuard, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
 };
 
-use crate::state::{LockRequest, LockState};
+use crate::{
+    LockClient,
+    broadcast_channel::ChannelRegistry,
+    cross_process::LockFile,
+    deadlock::WaitForGraph,
+    group::LockGroup,
+    metrics::MetricsRegistry,
+    semaphore::{SemaphoreRequest, SemaphoreState},
+    state::{LockRequest, LockState, QueuePolicy},
+    timer,
+    watcher::WatcherRegistry,
+};
 
-/// A lock manager maintaining multiple locks identified by their name.
+/// The amount of shards in a [LockManager].
+const SHARD_COUNT: usize = 32;
+
+type LockTable = HashMap<String, LockState>;
+
+/// The locks of a shard in a [LockManager].
 #[derive(Default)]
+struct Shard {
+    locks: LockTable,
+    /// The separator of hierarchical lock names, if enabled through
+    /// [LockManager::set_hierarchy_separator].
+    ///
+    /// Every shard has its own copy, so that operations can check it under the mutex of the shard
+    /// they need anyway. It's only changed while all shards are locked.
+    separator: Option<Arc<str>>,
+}
+
+/// A lock manager maintaining multiple locks identified by their name.
+///
+/// Names of locks, semaphores and broadcast channels are local to a manager. Besides the global
+/// default manager, clients can bind to isolated managers so that names never collide.
 pub struct LockManager {
-    /// For simplicity, we use a global mutex. That's not particularly efficient, but this mutex is
-    /// only held very briefly while we insert requests into a lock's queue.
-    locks: Mutex<HashMap<String, LockState>>,
+    /// Lock states, sharded by the hash of their name.
+    ///
+    /// Each shard has its own mutex, so that requests for unrelated locks don't contend with each
+    /// other. A shard is only locked very briefly while we insert requests into a lock's queue.
+    /// Operations on a single lock only hold the mutex of its shard, and the shards of its
+    /// ancestors with hierarchical names. [Self::inspect] locks all shards in order to obtain a
+    /// consistent snapshot. Multiple shards are always locked in the order of their index.
+    shards: Box<[Mutex<Shard>]>,
+    hasher: RandomState,
+    /// Locks with a [QueuePolicy] other than the default.
+    ///
+    /// This is kept outside of lock states so that the policy survives the lock becoming idle. The
+    /// mutex must not be locked before a shard.
+    policies: Mutex<HashMap<String, QueuePolicy>>,
+    /// Named semaphores that are currently in use.
+    ///
+    /// Semaphores are much less common than locks, so they're not sharded. The mutex must not be
+    /// locked before a shard.
+    semaphores: Mutex<HashMap<String, SemaphoreState>>,
+    /// Broadcast channels created by clients of this manager.
+    pub channels: ChannelRegistry,
+    /// Watchers observing changes to locks of this manager.
+    pub watchers: WatcherRegistry,
+    /// Contention metrics for locks of this manager, sharded like [Self::shards].
+    pub metrics: MetricsRegistry,
+    /// Locks whose ancestor has been released while they were waiting, which are checked by
+    /// [Self::grant_unblocked] once shards have been unlocked. The mutex must not be locked before
+    /// a shard.
+    unblocked_locks: Mutex<Vec<String>>,
+    /// Set after adding locks to [Self::unblocked_locks], so that updates without hierarchical
+    /// names don't need to lock it.
+    has_unblocked_locks: AtomicBool,
+    /// Groups with a member that may have become grantable, which are checked by
+    /// [Self::grant_unblocked] once shards have been unlocked. The mutex must not be locked before
+    /// a shard.
+    ready_groups: Mutex<Vec<Weak<LockGroup>>>,
+    /// Set after adding groups to [Self::ready_groups], so that updates without groups don't need
+    /// to lock it.
+    has_ready_groups: AtomicBool,
+    /// Whether [LockRequest::cross_process] requests are supported.
+    ///
+    /// Lock files and channel sockets are identified by names only, so only the default manager
+    /// can use them without colliding with other managers.
+    pub cross_process: bool,
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARD_COUNT).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            policies: Default::default(),
+            semaphores: Default::default(),
+            channels: Default::default(),
+            watchers: Default::default(),
+            metrics: MetricsRegistry::new(SHARD_COUNT),
+            unblocked_locks: Default::default(),
+            has_unblocked_locks: AtomicBool::new(false),
+            ready_groups: Default::default(),
+            has_ready_groups: AtomicBool::new(false),
+            cross_process: true,
+        }
+    }
 }
 
 impl LockManager {
-    fn lock_state<'a>(
-        locks: &'a mut HashMap<String, LockState>,
-        name: &String,
-    ) -> &'a mut LockState {
-        locks
-            .entry(name.clone())
-            .or_insert_with(|| LockState::new(name.clone()))
+    /// Creates a manager that is isolated from other managers, including the default one.
+    ///
+    /// Isolated managers don't support cross-process locks or channels.
+    pub fn isolated() -> Self {
+        Self {
+            cross_process: false,
+            ..Self::default()
+        }
+    }
+
+    fn shard_index(&self, name: &str) -> usize {
+        self.hasher.hash_one(name) as usize % self.shards.len()
     }
 
-    pub fn lock(&self, request: Arc<LockRequest>) {
-        let mut locks = self.locks.lock().unwrap();
-        let lock = Self::lock_state(&mut locks, &request.name);
-        lock.lock(request);
+    fn shard(&self, name: &str) -> MutexGuard<'_, Shard> {
+        self.shards[self.shard_index(name)].lock().unwrap()
     }
 
-    pub fn close_request(&self, request: Arc<LockRequest>) {
-        let mut locks = self.locks.lock().unwrap();
-        let lock = Self::lock_state(&mut locks, &request.name);
+    fn lock_all_shards(&self) -> LockedShards<'_> {
+        self.lock_shards(0..self.shards.len())
+    }
 
-        if request.holds_lock.mark_cancelled() {
-            lock.clear_request(&request);
+    /// Locks the shards with the given indices, which must be sorted.
+    fn lock_shards(&self, indices: impl IntoIterator<Item = usize>) -> LockedShards<'_> {
+        LockedShards {
+            manager: self,
+            guards: indices
+                .into_iter()
+                .map(|index| (index, self.shards[index].lock().unwrap()))
+                .collect(),
         }
+    }
+
+    fn new_state(&self, name: &str) -> LockState {
+        let policy = self.policies.lock().unwrap().get(name).copied();
+        LockState::new(name.to_string(), policy.unwrap_or_default())
+    }
+
+    /// Runs `f` on the state of the lock called `name` while holding the mutex of its shard.
+    ///
+    /// If `create` is false and the lock has no state, `f` is not called. Afterwards, this starts
+    /// waiting for lock files if necessary, removes the lock's state if it has become idle and
+    /// grants requests and [LockGroup]s that were unblocked by `f`.
+    fn update<R>(
+        self: &Arc<Self>,
+        name: &str,
+        create: bool,
+        f: impl FnOnce(&mut LockState) -> R,
+    ) -> Option<R> {
+        let result = self.update_shard(name, create, f);
+        self.grant_unblocked();
+        result
+    }
+
+    /// Implements [Self::update] without granting unblocked requests on other locks.
+    fn update_shard<R>(
+        self: &Arc<Self>,
+        name: &str,
+        create: bool,
+        f: impl FnOnce(&mut LockState) -> R,
+    ) -> Option<R> {
+        let mut shard = self.shard(name);
+        if let Some(separator) = shard.separator.clone() {
+            drop(shard);
+            return self.update_hierarchical(&separator, name, create, f);
+        }
+
+        let locks = &mut shard.locks;
+        let lock = if create {
+            locks
+                .entry(name.to_string())
+                .or_insert_with(|| self.new_state(name))
+        } else {
+            locks.get_mut(name)?
+        };
+
+        let result = f(lock);
+        self.start_background_work(lock);
 
         if lock.is_idle() {
-            locks.remove(&request.name);
+            locks.remove(name);
+            self.metrics.release(self.shard_index(name), name);
+        }
+        Some(result)
+    }
+
+    /// Like [Self::update_shard], but for hierarchical lock names.
+    ///
+    /// This also locks the shards of the lock's ancestors, since their intention locks change when
+    /// the lock is granted or released.
+    fn update_hierarchical<R>(
+        self: &Arc<Self>,
+        separator: &Arc<str>,
+        name: &str,
+        create: bool,
+        f: impl FnOnce(&mut LockState) -> R,
+    ) -> Option<R> {
+        let mut indices: Vec<usize> = ancestors(name, separator)
+            .chain([name])
+            .map(|name| self.shard_index(name))
+            .collect();
+        indices.sort_unstable();
+        indices.dedup();
+
+        let mut shards = self.lock_shards(indices);
+        // The separator may have changed while we weren't holding any shard.
+        if shards.separator().as_ref() != Some(separator) {
+            drop(shards);
+            return self.update_shard(name, create, f);
+        }
+
+        self.change_lock(&mut shards, name, create, f)
+    }
+
+    /// Runs `f` on the state of the lock called `name` in `shards`, which must include the shards
+    /// of its ancestors with hierarchical names.
+    ///
+    /// Like [Self::update_shard], this starts background work and removes the lock if it has become
+    /// idle. With hierarchical names, it also updates intention locks on ancestors of the lock.
+    fn change_lock<R>(
+        self: &Arc<Self>,
+        shards: &mut LockedShards,
+        name: &str,
+        create: bool,
+        f: impl FnOnce(&mut LockState) -> R,
+    ) -> Option<R> {
+        let lock = shards.prepare(name, create)?;
+        let before = lock.held_mode();
+        let result = f(lock);
+        self.start_background_work(lock);
+
+        match shards.separator() {
+            Some(separator) => self.propagate_hierarchy(shards, &separator, name, before),
+            None => {
+                shards.remove_if_idle(name);
+            }
+        }
+        Some(result)
+    }
+
+    /// Applies a change to the requests holding the lock called `name`, which was held in `before`
+    /// mode, to intention locks on its ancestors.
+    ///
+    /// Ancestors whose intention locks have been released may grant pending requests, which is
+    /// applied to their own ancestors in turn. Only the lock and its ancestors are touched here,
+    /// waiting descendants of released locks are checked later by [Self::grant_unblocked].
+    fn propagate_hierarchy(
+        self: &Arc<Self>,
+        shards: &mut LockedShards,
+        separator: &str,
+        name: &str,
+        before: Option<(bool, usize)>,
+    ) {
+        let (mut shared, mut exclusive) = self.settle_hierarchy(shards, separator, name, before);
+
+        for ancestor in ancestors(name, separator) {
+            if shared == 0 && exclusive == 0 {
+                break;
+            }
+
+            shards.entry(ancestor).add_intentions(shared, exclusive);
+            if shared >= 0 && exclusive >= 0 {
+                // Additional intention locks can't unblock anything.
+                continue;
+            }
+
+            let lock = shards.prepare(ancestor, false).unwrap();
+            let before = lock.held_mode();
+            lock.refresh_hierarchy();
+            self.start_background_work(lock);
+
+            let (more_shared, more_exclusive) =
+                self.settle_hierarchy(shards, separator, ancestor, before);
+            shared += more_shared;
+            exclusive += more_exclusive;
+        }
+    }
+
+    /// Finishes a change to the lock called `name`, which was held in `before` mode.
+    ///
+    /// This remembers descendants that may have been unblocked by the change, registers the lock
+    /// as a waiting descendant of its ancestors and removes it if it has become idle. Returns the
+    /// change in shared and exclusive intention locks to apply to its ancestors.
+    fn settle_hierarchy(
+        &self,
+        shards: &mut LockedShards,
+        separator: &str,
+        name: &str,
+        before: Option<(bool, usize)>,
+    ) -> (isize, isize) {
+        let lock = shards.get_mut(name).unwrap();
+        let after = lock.held_mode();
+
+        // A lock is released further when it's no longer held, or only held in shared mode.
+        let restriction = |mode: Option<(bool, usize)>| mode.map(|(shared, _)| !shared);
+        if restriction(after) < restriction(before) {
+            let descendants = lock.take_waiting_descendants();
+            if !descendants.is_empty() {
+                self.unblocked_locks.lock().unwrap().extend(descendants);
+                self.has_unblocked_locks.store(true, Ordering::Release);
+            }
+        }
+
+        let waiting = lock.has_waiters();
+        shards.remove_if_idle(name);
+        for ancestor in ancestors(name, separator) {
+            let ancestor = match waiting {
+                true => Some(shards.entry(ancestor)),
+                false => shards.get_mut(ancestor),
+            };
+            if let Some(ancestor) = ancestor {
+                ancestor.set_waiting_descendant(name, waiting);
+            }
+        }
+
+        let (shared_after, exclusive_after) = intentions(after);
+        let (shared_before, exclusive_before) = intentions(before);
+        (
+            shared_after - shared_before,
+            exclusive_after - exclusive_before,
+        )
+    }
+
+    /// Cancels all lock and semaphore requests of `client`, and removes its channel subscriptions and
+    /// watchers.
+    ///
+    /// The caller must mark the client as shut down first, so that it can't make new requests while
+    /// this runs.
+    pub fn shut_down_client(self: &Arc<Self>, client: &Arc<LockClient>) {
+        {
+            let mut shards = self.lock_all_shards();
+            let mut groups = Vec::<Arc<LockGroup>>::new();
+            for name in shards.names() {
+                self.change_lock(&mut shards, &name, false, |lock| {
+                    for group in lock.shut_down_client(client) {
+                        if !groups.iter().any(|other| Arc::ptr_eq(other, &group)) {
+                            groups.push(group);
+                        }
+                    }
+                });
+            }
+
+            // Groups are cancelled as a whole, so that they're only notified once.
+            for group in groups {
+                if group.holds_locks.mark_cancelled() {
+                    self.clear_group_members(&mut shards, &group);
+                    group.notify_shut_down();
+                }
+            }
+        }
+        self.grant_unblocked();
+
+        {
+            let mut semaphores = self.semaphores.lock().unwrap();
+            for semaphore in semaphores.values_mut() {
+                semaphore.shut_down_client(client);
+            }
+            semaphores.retain(|_, semaphore| !semaphore.is_idle());
+        }
+
+        self.channels.remove_client(client);
+        self.watchers.remove_client(client);
+    }
+
+    /// Enables hierarchical lock names separated by `separator`, or disables them if it's `None`.
+    pub fn set_hierarchy_separator(self: &Arc<Self>, separator: Option<&str>) {
+        {
+            let mut shards = self.lock_all_shards();
+            let separator: Option<Arc<str>> = separator.map(Arc::from);
+            for (_, shard) in &mut shards.guards {
+                shard.separator = separator.clone();
+            }
+
+            // Intention locks are recomputed from scratch, after which locks may grant requests
+            // that are no longer blocked by related locks.
+            let names = shards.names();
+            for name in &names {
+                shards.get_mut(name).unwrap().reset_hierarchy();
+            }
+            if let Some(separator) = &separator {
+                for name in &names {
+                    let lock = shards.get_mut(name).unwrap();
+                    let (shared, exclusive) = intentions(lock.held_mode());
+                    let waiting = lock.has_waiters();
+                    if shared == 0 && exclusive == 0 && !waiting {
+                        continue;
+                    }
+
+                    for ancestor in ancestors(name, separator) {
+                        let ancestor = shards.entry(ancestor);
+                        ancestor.add_intentions(shared, exclusive);
+                        ancestor.set_waiting_descendant(name, waiting);
+                    }
+                }
+            }
+
+            for name in &names {
+                self.change_lock(&mut shards, name, false, LockState::refresh_hierarchy);
+            }
+        }
+        self.grant_unblocked();
+    }
+
+    pub fn lock(self: &Arc<Self>, request: Arc<LockRequest>) {
+        if request.cross_process && !self.cross_process {
+            request.notify_failed(&io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Cross-process locks are only supported by the default lock manager",
+            ));
+            return;
+        }
+
+        let is_pending = self
+            .update(&request.name, true, |lock| {
+                // Checked while holding the shard, so that this can't race with shutting down the
+                // client.
+                if request.client.is_shut_down() {
+                    lock.cancel_for_shutdown(&request);
+                    return false;
+                }
+
+                lock.lock(request.clone());
+                let is_pending = lock.is_pending(&request);
+                // Scheduled while holding the shard, so that granting the request cancels it.
+                if let Some(deadline) = request.deadline
+                    && is_pending
+                {
+                    self.schedule_timeout(&request, deadline);
+                }
+                is_pending
+            })
+            .unwrap();
+
+        if request.detect_deadlocks && is_pending {
+            self.detect_deadlock(&request);
+        }
+    }
+
+    fn schedule_timeout(self: &Arc<Self>, request: &Arc<LockRequest>, deadline: Instant) {
+        let manager = Arc::downgrade(self);
+        let weak_request = Arc::downgrade(request);
+        request.set_timer(timer::schedule(deadline, move || {
+            if let (Some(manager), Some(request)) = (manager.upgrade(), weak_request.upgrade()) {
+                manager.time_out_request(&request);
+            }
+        }));
+    }
+
+    /// Grants `request` if it can be granted right away, see [LockState::try_lock].
+    ///
+    /// Returns whether the request now holds the lock. Requests of clients that have been shut
+    /// down are never granted, and neither are cross-process requests if this manager doesn't
+    /// support them.
+    pub fn try_lock(self: &Arc<Self>, request: &Arc<LockRequest>) -> bool {
+        if request.cross_process && !self.cross_process {
+            return false;
+        }
+
+        self.update(&request.name, true, |lock| {
+            !request.client.is_shut_down() && lock.try_lock(request)
+        })
+        .unwrap()
+    }
+
+    /// Requests all locks of `group`, which are granted together once all of them are available.
+    ///
+    /// Until then, the group doesn't hold any of its locks, so groups can't deadlock each other.
+    /// Members don't wait in the queue of their lock, but conflicting requests made after the group
+    /// aren't granted before it.
+    pub fn lock_group(self: &Arc<Self>, group: Arc<LockGroup>) {
+        {
+            let mut shards = self.lock_group_shards(&group);
+            // Checked while holding the shards of the group, so that this can't race with shutting
+            // down the client.
+            if group.client.is_shut_down() {
+                for member in &group.members {
+                    member.holds_lock.mark_cancelled();
+                }
+                group.notify_shut_down();
+                return;
+            }
+
+            if let Some(reason) = Self::group_conflict(&group, shards.separator().as_deref()) {
+                group.notify_failed(reason);
+                return;
+            }
+
+            for member in &group.members {
+                self.change_lock(&mut shards, &member.name, true, |lock| {
+                    lock.add_group_member(member.clone())
+                });
+            }
+        }
+        self.grant_unblocked();
+    }
+
+    /// Returns why the locks of `group` could never be granted together, if that's the case.
+    fn group_conflict(group: &LockGroup, separator: Option<&str>) -> Option<&'static CStr> {
+        if group.members.is_empty() {
+            return Some(c"A group must request at least one lock");
+        }
+
+        for (index, member) in group.members.iter().enumerate() {
+            for other in &group.members[..index] {
+                if member.name == other.name {
+                    return Some(c"A lock can only be requested once per group");
+                }
+
+                if let Some(separator) = separator
+                    && (is_descendant(&member.name, &other.name, separator)
+                        || is_descendant(&other.name, &member.name, separator))
+                {
+                    return Some(c"A group can't contain a lock and its ancestor");
+                }
+            }
+        }
+        None
+    }
+
+    /// Releases all locks of `group`, or stops waiting for them if the group hasn't been granted
+    /// yet.
+    pub fn close_group(self: &Arc<Self>, group: &Arc<LockGroup>) {
+        {
+            let mut shards = self.lock_group_shards(group);
+            group.holds_locks.mark_cancelled();
+            self.clear_group_members(&mut shards, group);
+        }
+        self.grant_unblocked();
+    }
+
+    /// Locks the shards of the locks requested by `group`, and of their ancestors with hierarchical
+    /// names.
+    fn lock_group_shards(&self, group: &LockGroup) -> LockedShards<'_> {
+        let separator = self.shards[0].lock().unwrap().separator.clone();
+        let mut indices = Vec::new();
+        for member in &group.members {
+            indices.push(self.shard_index(&member.name));
+            if let Some(separator) = &separator {
+                indices
+                    .extend(ancestors(&member.name, separator).map(|name| self.shard_index(name)));
+            }
+        }
+        indices.sort_unstable();
+        indices.dedup();
+
+        let shards = self.lock_shards(indices);
+        // The separator may have changed while we weren't holding any shard.
+        if !shards.guards.is_empty() && shards.separator() != separator {
+            drop(shards);
+            return self.lock_group_shards(group);
+        }
+        shards
+    }
+
+    fn clear_group_members(self: &Arc<Self>, shards: &mut LockedShards, group: &LockGroup) {
+        for member in &group.members {
+            self.change_lock(shards, &member.name, false, |lock| {
+                if member.holds_lock.mark_cancelled() {
+                    lock.clear_request(member);
+                }
+            });
+        }
+    }
+
+    /// Checks locks collected in [Self::unblocked_locks] again and grants groups collected in
+    /// [Self::ready_groups] if all of their locks are available.
+    ///
+    /// This must be called after unlocking shards whenever locks may have changed. Locks and groups
+    /// added by another thread are handled by that thread if we miss them here.
+    fn grant_unblocked(self: &Arc<Self>) {
+        loop {
+            if self.has_unblocked_locks.swap(false, Ordering::AcqRel) {
+                let names = std::mem::take(&mut *self.unblocked_locks.lock().unwrap());
+                for name in names {
+                    self.update_shard(&name, false, LockState::refresh_hierarchy);
+                }
+            } else if self.has_ready_groups.swap(false, Ordering::AcqRel) {
+                let groups = std::mem::take(&mut *self.ready_groups.lock().unwrap());
+                for group in groups.iter().filter_map(Weak::upgrade) {
+                    self.try_grant_group(&group);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn try_grant_group(self: &Arc<Self>, group: &Arc<LockGroup>) {
+        let mut shards = self.lock_group_shards(group);
+        let ready = group.members.iter().all(|member| {
+            shards
+                .prepare(&member.name, false)
+                .is_some_and(|lock| lock.can_grant_group_member(member))
+        });
+
+        // Marking the group fails if it has been cancelled or granted already.
+        if !ready || !group.holds_locks.mark_holds_lock() {
+            return;
+        }
+
+        for member in &group.members {
+            self.change_lock(&mut shards, &member.name, false, |lock| {
+                lock.grant_group_member(member)
+            });
+        }
+
+        if !group.notify_locked() {
+            // The port has been closed, so nobody is going to release these locks.
+            group.holds_locks.mark_cancelled();
+            self.clear_group_members(&mut shards, group);
+        }
+    }
+
+    /// Changes the [QueuePolicy] for the lock called `name`.
+    pub fn set_policy(self: &Arc<Self>, name: &str, policy: QueuePolicy) {
+        {
+            let mut policies = self.policies.lock().unwrap();
+            if policy == QueuePolicy::default() {
+                policies.remove(name);
+            } else {
+                policies.insert(name.to_string(), policy);
+            }
+        }
+
+        self.update(name, false, |lock| lock.set_policy(policy));
+    }
+
+    pub fn close_request(self: &Arc<Self>, request: Arc<LockRequest>) {
+        self.update(&request.name, false, |lock| {
+            request.cancel_timer();
+            if request.holds_lock.mark_cancelled() {
+                lock.clear_request(&request);
+            }
+        });
+    }
+
+    pub fn abort_request(self: &Arc<Self>, request: &Arc<LockRequest>) {
+        self.update(&request.name, true, |lock| lock.abort_request(request));
+    }
+
+    pub fn upgrade_request(self: &Arc<Self>, request: &Arc<LockRequest>) {
+        self.update(&request.name, true, |lock| lock.upgrade_request(request));
+    }
+
+    pub fn downgrade_request(self: &Arc<Self>, request: &Arc<LockRequest>) {
+        self.update(&request.name, false, |lock| lock.downgrade_request(request));
+    }
+
+    pub fn wait_on_condition(self: &Arc<Self>, request: &Arc<LockRequest>, condition: String) {
+        self.update(&request.name, true, |lock| {
+            lock.wait_on_condition(request, condition)
+        });
+    }
+
+    pub fn notify_condition(self: &Arc<Self>, name: &str, condition: &str, all: bool) {
+        self.update(name, false, |lock| lock.notify_condition(condition, all));
+    }
+
+    fn time_out_request(self: &Arc<Self>, request: &Arc<LockRequest>) {
+        self.update(&request.name, false, |lock| lock.time_out_request(request));
+    }
+
+    /// Fails `request` if it completes a cycle in the wait-for graph of all clients.
+    ///
+    /// This needs to lock all shards, which is why it's opt-in for requests.
+    fn detect_deadlock(self: &Arc<Self>, request: &Arc<LockRequest>) {
+        self.fail_if_deadlocked(request);
+        self.grant_unblocked();
+    }
+
+    fn fail_if_deadlocked(self: &Arc<Self>, request: &Arc<LockRequest>) {
+        let mut shards = self.lock_all_shards();
+
+        let mut graph = WaitForGraph::default();
+        for lock in shards.locks() {
+            graph.add_lock(lock);
+        }
+
+        let Some(cycle) = graph.find_cycle(request) else {
+            return;
+        };
+
+        self.change_lock(&mut shards, &request.name, false, |lock| {
+            lock.fail_deadlocked(request, &cycle)
+        });
+    }
+
+    /// Calls `f` for every lock in use in this manager.
+    ///
+    /// All shards are locked while `f` runs, so it observes a consistent state.
+    pub fn inspect(&self, f: impl FnMut(&LockState)) {
+        self.inspect_where(|_| true, f);
+    }
+
+    /// Calls `f` for every lock in use in this manager whose name satisfies `include`.
+    pub fn inspect_where(&self, include: impl Fn(&str) -> bool, mut f: impl FnMut(&LockState)) {
+        let shards = self.lock_all_shards();
+        for lock in shards.locks().filter(|lock| lock.is_in_use()) {
+            if include(&lock.name) {
+                f(lock);
+            }
+        }
+    }
+
+    /// Calls `f` for the lock called `name`, if it's in use.
+    ///
+    /// With hierarchical names, `f` is also called for descendants of the lock, since they hold
+    /// intention locks on it. Otherwise, this only locks a single shard.
+    pub fn inspect_named(&self, name: &str, mut f: impl FnMut(&LockState)) {
+        let shard = self.shard(name);
+        if shard.separator.is_none() {
+            if let Some(lock) = shard.locks.get(name) {
+                f(lock);
+            }
+            return;
+        }
+        drop(shard);
+
+        let shards = self.lock_all_shards();
+        let separator = shards.separator();
+        for lock in shards.locks().filter(|lock| lock.is_in_use()) {
+            if lock.name == name
+                || separator
+                    .as_ref()
+                    .is_some_and(|separator| is_descendant(&lock.name, name, separator))
+            {
+                f(lock);
+            }
         }
     }
 
-    pub fn inspect(&self, mut f: impl FnMut(&LockState)) {
-        let locks = self.locks.lock().unwrap();
-        for value in locks.values() {
-            f(value);
+    pub fn acquire_permits(&self, request: Arc<SemaphoreRequest>) {
+        let mut semaphores = self.semaphores.lock().unwrap();
+        if request.client.is_shut_down() {
+            request.notify_failed(c"The client has been shut down");
+            return;
         }
+
+        let semaphore = semaphores
+            .entry(request.name.clone())
+            .or_insert_with(|| SemaphoreState::new(request.name.clone(), request.max_permits));
+
+        let name = request.name.clone();
+        semaphore.acquire(request);
+        if semaphore.is_idle() {
+            semaphores.remove(&name);
+        }
+    }
+
+    pub fn release_permits(&self, request: Arc<SemaphoreRequest>) {
+        let mut semaphores = self.semaphores.lock().unwrap();
+        if let Some(semaphore) = semaphores.get_mut(&request.name) {
+            semaphore.release(&request);
+            if semaphore.is_idle() {
+                semaphores.remove(&request.name);
+            }
+        }
+    }
+
+    /// Calls `f` for every semaphore in use.
+    pub fn inspect_semaphores(&self, mut f: impl FnMut(&SemaphoreState)) {
+        for semaphore in self.semaphores.lock().unwrap().values() {
+            f(semaphore);
+        }
+    }
+
+    /// Reports changes to `lock` to metrics and watchers, schedules timers for leases granted by it,
+    /// remembers groups it may have unblocked, and starts waiting for its lock file if necessary.
+    fn start_background_work(self: &Arc<Self>, lock: &mut LockState) {
+        let events = lock.take_events();
+        if !events.is_empty() {
+            let shard = self.shard_index(&lock.name);
+            self.metrics.record(shard, &lock.name, &events);
+            self.watchers.dispatch(&lock.name, &events);
+        }
+
+        let groups = lock.ready_groups();
+        if !groups.is_empty() {
+            self.ready_groups.lock().unwrap().extend(groups);
+            self.has_ready_groups.store(true, Ordering::Release);
+        }
+
+        for (request, expires) in lock.take_lease_timers() {
+            self.schedule_lease_check(request, expires);
+        }
+
+        self.wait_for_lock_file(lock);
+    }
+
+    fn schedule_lease_check(self: &Arc<Self>, request: Weak<LockRequest>, at: Instant) {
+        let Some(strong_request) = request.upgrade() else {
+            return;
+        };
+
+        let manager = Arc::downgrade(self);
+        strong_request.set_timer(timer::schedule(at, move || {
+            if let (Some(manager), Some(request)) = (manager.upgrade(), request.upgrade()) {
+                manager.check_lease(&request);
+            }
+        }));
+    }
+
+    fn check_lease(self: &Arc<Self>, request: &Arc<LockRequest>) {
+        self.update(&request.name, false, |lock| {
+            // Rescheduled while holding the shard, so that this can't race with releasing the lock.
+            if let Some(expires) = lock.expire_lease(request) {
+                self.schedule_lease_check(Arc::downgrade(request), expires);
+            }
+        });
+    }
+
+    pub fn renew_lease(self: &Arc<Self>, request: &Arc<LockRequest>) {
+        self.update(&request.name, false, |lock| lock.renew_lease(request));
+    }
+
+    /// Starts a background thread locking the lock file of a cross-process lock if the queue of
+    /// `lock` is blocked on another process.
+    fn wait_for_lock_file(self: &Arc<Self>, lock: &mut LockState) {
+        if let Some(shared) = lock.take_lock_file_wait() {
+            let name = lock.name.clone();
+            let manager = self.clone();
+
+            std::thread::spawn(move || {
+                let result = LockFile::acquire(&name, shared);
+                manager.lock_file_acquired(name, result);
+            });
+        }
+    }
+
+    fn lock_file_acquired(self: &Arc<Self>, name: String, result: io::Result<LockFile>) {
+        self.update(&name, true, |lock| lock.lock_file_acquired(result));
+    }
+}
+
+/// Whether `child` is a descendant of `parent` with hierarchical names.
+fn is_descendant(child: &str, parent: &str, separator: &str) -> bool {
+    child
+        .strip_prefix(parent)
+        .is_some_and(|rest| rest.starts_with(separator))
+}
+
+/// The ancestors of `name` with hierarchical names, starting with its parent.
+fn ancestors<'a>(name: &'a str, separator: &'a str) -> impl Iterator<Item = &'a str> {
+    name.rmatch_indices(separator)
+        .filter(|(end, _)| *end > 0)
+        .map(|(end, _)| &name[..end])
+}
+
+/// The amount of shared and exclusive intention locks implied on ancestors by a lock held in
+/// `mode`.
+fn intentions(mode: Option<(bool, usize)>) -> (isize, isize) {
+    match mode {
+        Some((true, holders)) => (holders as isize, 0),
+        Some((false, holders)) => (0, holders as isize),
+        None => (0, 0),
+    }
+}
+
+/// Shards of a [LockManager] locked by an operation that may change multiple locks.
+struct LockedShards<'a> {
+    manager: &'a LockManager,
+    /// Locked shards and their index, sorted by index.
+    guards: Vec<(usize, MutexGuard<'a, Shard>)>,
+}
+
+impl LockedShards<'_> {
+    /// The separator of hierarchical lock names, which is the same in every shard.
+    fn separator(&self) -> Option<Arc<str>> {
+        self.guards
+            .first()
+            .and_then(|(_, shard)| shard.separator.clone())
+    }
+
+    /// The position of the shard containing `name` in [Self::guards], which must be locked.
+    fn position(&self, name: &str) -> usize {
+        let index = self.manager.shard_index(name);
+        self.guards
+            .binary_search_by_key(&index, |(index, _)| *index)
+            .expect("the shard of a lock must be locked to access it")
+    }
+
+    fn get(&self, name: &str) -> Option<&LockState> {
+        self.guards[self.position(name)].1.locks.get(name)
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut LockState> {
+        let position = self.position(name);
+        self.guards[position].1.locks.get_mut(name)
+    }
+
+    /// Returns the state of the lock called `name`, creating it if necessary.
+    fn entry(&mut self, name: &str) -> &mut LockState {
+        let position = self.position(name);
+        let manager = self.manager;
+        let locks = &mut self.guards[position].1.locks;
+        if !locks.contains_key(name) {
+            locks.insert(name.to_string(), manager.new_state(name));
+        }
+        locks.get_mut(name).unwrap()
+    }
+
+    /// Returns the state of the lock called `name` after updating whether its ancestors are held,
+    /// which needs to happen before processing its requests.
+    fn prepare(&mut self, name: &str, create: bool) -> Option<&mut LockState> {
+        let separator = self.separator();
+        let ancestor_held = separator
+            .as_deref()
+            .and_then(|separator| self.ancestor_held(name, separator));
+
+        let lock = match create {
+            true => self.entry(name),
+            false => self.get_mut(name)?,
+        };
+        lock.set_ancestor_held(separator, ancestor_held);
+        Some(lock)
+    }
+
+    /// Whether an ancestor of `name` is held in shared (`Some(true)`) or exclusive (`Some(false)`)
+    /// mode.
+    fn ancestor_held(&self, name: &str, separator: &str) -> Option<bool> {
+        let mut held = None;
+        for ancestor in ancestors(name, separator) {
+            match self.get(ancestor).and_then(LockState::held_mode) {
+                Some((false, _)) => return Some(false),
+                Some((true, _)) => held = Some(true),
+                None => {}
+            }
+        }
+        held
+    }
+
+    fn remove_if_idle(&mut self, name: &str) {
+        let position = self.position(name);
+        let (index, shard) = &mut self.guards[position];
+        if shard.locks.get(name).is_some_and(LockState::is_idle) {
+            shard.locks.remove(name);
+            self.manager.metrics.release(*index, name);
+        }
+    }
+
+    fn locks(&self) -> impl Iterator<Item = &LockState> {
+        self.guards
+            .iter()
+            .flat_map(|(_, shard)| shard.locks.values())
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.guards
+            .iter()
+            .flat_map(|(_, shard)| shard.locks.keys().cloned())
+            .collect()
     }
 }
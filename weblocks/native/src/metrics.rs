@@ -0,0 +1,184 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::state::{LockEvent, LockEventKind};
+
+/// How many lock names that are no longer in use keep their own metrics in each shard of a
+/// [MetricsRegistry].
+///
+/// Metrics of older names are merged into [MetricsShard::evicted], so that names derived from data
+/// (like one lock per row) don't grow the registry without bounds.
+const IDLE_NAMES_PER_SHARD: usize = 8;
+
+/// Upper bounds of the buckets in a [Histogram], in microseconds.
+///
+/// Durations above the last bound are counted in an additional bucket.
+pub const BUCKET_BOUNDS: [u64; 7] = [
+    100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000, 60_000_000,
+];
+
+/// A histogram of durations, with buckets bounded by [BUCKET_BOUNDS].
+#[derive(Clone, Default)]
+pub struct Histogram {
+    /// The amount of durations in each bucket. Unlike OpenMetrics histograms, buckets are not
+    /// cumulative.
+    pub buckets: [u64; BUCKET_BOUNDS.len() + 1],
+    pub count: u64,
+    pub sum_micros: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|bound| micros <= *bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_micros += micros;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.sum_micros += other.sum_micros;
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += other;
+        }
+    }
+}
+
+/// Contention counters for a lock name.
+#[derive(Clone, Default)]
+pub struct LockMetrics {
+    /// How often the lock has been granted.
+    pub acquisitions: u64,
+    /// How often the lock has been stolen from a holder.
+    pub steals: u64,
+    /// How often an `if_available` request could not be granted.
+    pub unavailable: u64,
+    /// The time requests spent between being enqueued and being granted.
+    pub wait_time: Histogram,
+    /// The time requests held the lock before releasing it or having it stolen.
+    pub hold_time: Histogram,
+}
+
+impl LockMetrics {
+    fn record(&mut self, event: &LockEvent) {
+        let timeline = &event.request.timeline;
+        let elapsed =
+            |since: SystemTime| SystemTime::now().duration_since(since).unwrap_or_default();
+
+        match event.kind {
+            LockEventKind::Enqueued | LockEventKind::Failed => {}
+            LockEventKind::Granted => {
+                self.acquisitions += 1;
+                self.wait_time.record(elapsed(timeline.enqueued_at()));
+            }
+            LockEventKind::Released | LockEventKind::Stolen => {
+                if event.kind == LockEventKind::Stolen {
+                    self.steals += 1;
+                }
+                if let Some(granted_at) = timeline.granted_at() {
+                    self.hold_time.record(elapsed(granted_at));
+                }
+            }
+            LockEventKind::Unavailable => self.unavailable += 1,
+        }
+    }
+
+    /// Adds the counters of `other` to these metrics.
+    pub fn merge(&mut self, other: &Self) {
+        self.acquisitions += other.acquisitions;
+        self.steals += other.steals;
+        self.unavailable += other.unavailable;
+        self.wait_time.merge(&other.wait_time);
+        self.hold_time.merge(&other.hold_time);
+    }
+}
+
+/// Metrics for all lock names of a [crate::manager::LockManager], kept until they're reset.
+///
+/// Like lock states, metrics are sharded so that recording them doesn't serialize changes to
+/// unrelated locks. The mutex of a shard is locked while holding the corresponding lock shard.
+pub struct MetricsRegistry {
+    shards: Box<[Mutex<MetricsShard>]>,
+}
+
+#[derive(Default)]
+struct MetricsShard {
+    /// Metrics of locks that are in use or have been used recently.
+    names: HashMap<String, LockMetrics>,
+    /// Names in [Self::names] whose lock is no longer in use, the least recently used first.
+    idle: VecDeque<String>,
+    /// Merged metrics of names that have been evicted from [Self::names].
+    evicted: Option<LockMetrics>,
+}
+
+impl MetricsRegistry {
+    pub fn new(shard_count: usize) -> Self {
+        Self {
+            shards: (0..shard_count).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    /// Records `events` that happened on the lock `name`, which is in the given shard.
+    pub fn record(&self, shard: usize, name: &str, events: &[LockEvent]) {
+        let mut shard = self.shards[shard].lock().unwrap();
+        if let Some(index) = shard.idle.iter().position(|idle| idle == name) {
+            shard.idle.remove(index);
+        }
+
+        if !shard.names.contains_key(name) {
+            shard.names.insert(name.to_string(), LockMetrics::default());
+        }
+        let metrics = shard.names.get_mut(name).unwrap();
+
+        for event in events {
+            metrics.record(event);
+        }
+    }
+
+    /// Notes that the lock `name`, which is in the given shard, is no longer in use.
+    ///
+    /// Its metrics are kept until [IDLE_NAMES_PER_SHARD] other names of the shard have become
+    /// unused, after which they're merged with other evicted names.
+    pub fn release(&self, shard: usize, name: &str) {
+        let mut shard = self.shards[shard].lock().unwrap();
+        if !shard.names.contains_key(name) || shard.idle.iter().any(|idle| idle == name) {
+            return;
+        }
+
+        shard.idle.push_back(name.to_string());
+        while shard.idle.len() > IDLE_NAMES_PER_SHARD {
+            let name = shard.idle.pop_front().unwrap();
+            let metrics = shard.names.remove(&name).unwrap();
+            shard.evicted.get_or_insert_default().merge(&metrics);
+        }
+    }
+
+    /// Calls `f` with the metrics of every lock name that has been in use recently, and with the
+    /// merged metrics of older names without a name.
+    pub fn inspect(&self, mut f: impl FnMut(Option<&str>, &LockMetrics)) {
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            for (name, metrics) in &shard.names {
+                f(Some(name), metrics);
+            }
+            if let Some(evicted) = &shard.evicted {
+                f(None, evicted);
+            }
+        }
+    }
+
+    /// Forgets all metrics recorded so far.
+    pub fn reset(&self) {
+        for shard in &self.shards {
+            *shard.lock().unwrap() = Default::default();
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Renders the state of a [LockManager] in the [OpenMetrics] text format.
+//!
+//! Lock and channel names are used as `name` labels. Since names are often derived from data (for
+//! instance one lock per row), the amount of distinct names in the output is limited. Names beyond
+//! the limit are aggregated into a single series without a `name` label.
+//!
+//! [OpenMetrics]: https://github.com/prometheus/OpenMetrics/blob/main/specification/OpenMetrics.md
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    manager::LockManager,
+    metrics::{BUCKET_BOUNDS, Histogram, LockMetrics},
+};
+
+/// How many distinct names may appear as labels.
+pub struct CardinalityLimits {
+    pub locks: usize,
+    pub channels: usize,
+}
+
+/// Everything we report about a lock name.
+#[derive(Default)]
+struct LockSeries {
+    pending: u64,
+    holders: u64,
+    metrics: LockMetrics,
+}
+
+impl LockSeries {
+    fn add(&mut self, other: &LockSeries) {
+        self.pending += other.pending;
+        self.holders += other.holders;
+        self.metrics.merge(&other.metrics);
+    }
+}
+
+pub fn render(manager: &LockManager, limits: CardinalityLimits) -> String {
+    let mut locks = HashMap::<String, LockSeries>::new();
+    manager.inspect(|lock| {
+        let (pending, holders) = lock.queue_lengths();
+        let series = locks.entry(lock.name.clone()).or_default();
+        series.pending = pending as u64;
+        series.holders = holders as u64;
+    });
+    let mut evicted = None::<LockMetrics>;
+    manager.metrics.inspect(|name, metrics| match name {
+        Some(name) => locks.entry(name.to_string()).or_default().metrics = metrics.clone(),
+        None => evicted.get_or_insert_default().merge(metrics),
+    });
+
+    // Keep the busiest locks, so that contended locks aren't hidden in the aggregate.
+    let mut locks: Vec<_> = locks.into_iter().collect();
+    locks.sort_by(|(a_name, a), (b_name, b)| {
+        (b.pending + b.holders)
+            .cmp(&(a.pending + a.holders))
+            .then(b.metrics.acquisitions.cmp(&a.metrics.acquisitions))
+            .then(a_name.cmp(b_name))
+    });
+    let mut locks = limit(locks, limits.locks, |aggregate, series| {
+        aggregate.add(series)
+    });
+
+    // The registry has already merged metrics of names that haven't been used recently.
+    if let Some(metrics) = evicted {
+        let series = LockSeries {
+            metrics,
+            ..Default::default()
+        };
+        match locks.last_mut() {
+            Some((None, aggregate)) => aggregate.add(&series),
+            _ => locks.push((None, series)),
+        }
+    }
+
+    let mut channels = manager.channels.subscriber_counts();
+    channels.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then(a_name.cmp(b_name)));
+    let channels = limit(channels, limits.channels, |aggregate, count| {
+        *aggregate += count
+    });
+
+    let mut out = String::new();
+    gauge(
+        &mut out,
+        "weblocks_lock_pending",
+        "Pending requests.",
+        &locks,
+        |s| s.pending,
+    );
+    gauge(
+        &mut out,
+        "weblocks_lock_holders",
+        "Requests holding the lock.",
+        &locks,
+        |s| s.holders,
+    );
+    counter(
+        &mut out,
+        "weblocks_lock_acquisitions",
+        "Granted requests.",
+        &locks,
+        |s| s.metrics.acquisitions,
+    );
+    counter(
+        &mut out,
+        "weblocks_lock_steals",
+        "Holders the lock has been stolen from.",
+        &locks,
+        |s| s.metrics.steals,
+    );
+    counter(
+        &mut out,
+        "weblocks_lock_unavailable",
+        "ifAvailable requests that could not be granted.",
+        &locks,
+        |s| s.metrics.unavailable,
+    );
+    histogram(
+        &mut out,
+        "weblocks_lock_wait_seconds",
+        "Time between requesting and being granted the lock.",
+        &locks,
+        |s| &s.metrics.wait_time,
+    );
+    histogram(
+        &mut out,
+        "weblocks_lock_hold_seconds",
+        "Time between being granted and releasing the lock.",
+        &locks,
+        |s| &s.metrics.hold_time,
+    );
+    gauge(
+        &mut out,
+        "weblocks_broadcast_channel_subscribers",
+        "Clients listening on the broadcast channel.",
+        &channels,
+        |count| *count as u64,
+    );
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Keeps the first `max` entries of `sorted` and merges the others into an entry without a name.
+fn limit<T: Default>(
+    mut sorted: Vec<(String, T)>,
+    max: usize,
+    merge: impl Fn(&mut T, &T),
+) -> Vec<(Option<String>, T)> {
+    let overflow = sorted.split_off(max.min(sorted.len()));
+    let mut limited: Vec<_> = sorted.into_iter().map(|(k, v)| (Some(k), v)).collect();
+
+    if !overflow.is_empty() {
+        let mut aggregate = T::default();
+        for (_, value) in &overflow {
+            merge(&mut aggregate, value);
+        }
+        limited.push((None, aggregate));
+    }
+    limited
+}
+
+fn labels(name: &Option<String>, extra: Option<(&str, &str)>) -> String {
+    let mut labels = Vec::new();
+    if let Some(name) = name {
+        labels.push(format!("name=\"{}\"", escape(name)));
+    }
+    if let Some((key, value)) = extra {
+        labels.push(format!("{key}=\"{value}\""));
+    }
+
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// Escapes a label value. OpenMetrics has no escape for nul bytes, which are replaced so that the
+/// output can be passed to Dart as a C string.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\0', "\u{FFFD}")
+}
+
+fn header(out: &mut String, family: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {family} {kind}");
+    let _ = writeln!(out, "# HELP {family} {help}");
+}
+
+fn gauge<T>(
+    out: &mut String,
+    family: &str,
+    help: &str,
+    series: &[(Option<String>, T)],
+    value: impl Fn(&T) -> u64,
+) {
+    header(out, family, "gauge", help);
+    for (name, entry) in series {
+        let _ = writeln!(out, "{family}{} {}", labels(name, None), value(entry));
+    }
+}
+
+fn counter<T>(
+    out: &mut String,
+    family: &str,
+    help: &str,
+    series: &[(Option<String>, T)],
+    value: impl Fn(&T) -> u64,
+) {
+    header(out, family, "counter", help);
+    for (name, entry) in series {
+        let _ = writeln!(out, "{family}_total{} {}", labels(name, None), value(entry));
+    }
+}
+
+fn histogram<T>(
+    out: &mut String,
+    family: &str,
+    help: &str,
+    series: &[(Option<String>, T)],
+    value: impl Fn(&T) -> &Histogram,
+) {
+    header(out, family, "histogram", help);
+    for (name, entry) in series {
+        let histogram = value(entry);
+        let mut cumulative = 0;
+
+        for (i, count) in histogram.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = match BUCKET_BOUNDS.get(i) {
+                Some(micros) => seconds(*micros),
+                None => "+Inf".to_string(),
+            };
+            let labels = labels(name, Some(("le", &bound)));
+            let _ = writeln!(out, "{family}_bucket{labels} {cumulative}");
+        }
+
+        let labels = labels(name, None);
+        let _ = writeln!(out, "{family}_count{labels} {}", histogram.count);
+        let _ = writeln!(
+            out,
+            "{family}_sum{labels} {}",
+            seconds(histogram.sum_micros)
+        );
+    }
+}
+
+fn seconds(micros: u64) -> String {
+    format!("{:?}", micros as f64 / 1_000_000.0)
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::VecDeque,
+    ffi::{CStr, CString},
+    rc::Rc,
+    sync::Arc,
+};
+
+use crate::{
+    LockClient, RequestSnapshot,
+    dart::{DartObject, DartPort},
+    state::{LockRequestState, QueuePolicy, RequestTimeline},
+};
+
+/// A request to take permits from a named semaphore.
+pub struct SemaphoreRequest {
+    /// The name of the semaphore.
+    pub name: String,
+    /// The client issueing the request.
+    pub(crate) client: Arc<LockClient>,
+    /// The total amount of permits of the semaphore, which must be the same for all requests on a
+    /// semaphore while it's in use.
+    pub max_permits: u32,
+    /// The amount of permits to take, at most [Self::max_permits].
+    pub permits: u32,
+    /// The `SendPort` to send a message to once the permits have been taken.
+    pub notify: DartPort,
+    /// The current state of this request as it progresses.
+    pub holds_permits: LockRequestState,
+    /// The id of this request and when it was made and granted, for snapshots.
+    pub timeline: RequestTimeline,
+}
+
+/// The state of a semaphore that is currently in use.
+///
+/// Requests are granted in the order in which they were made: A request for many permits blocks
+/// later requests for fewer permits, so that it can't starve.
+pub struct SemaphoreState {
+    pub name: String,
+    max_permits: u32,
+    available: u32,
+    pending: VecDeque<Arc<SemaphoreRequest>>,
+    held: Vec<Arc<SemaphoreRequest>>,
+}
+
+impl SemaphoreState {
+    pub fn new(name: String, max_permits: u32) -> Self {
+        Self {
+            name,
+            max_permits,
+            available: max_permits,
+            pending: Default::default(),
+            held: Default::default(),
+        }
+    }
+
+    /// Processes an incoming [SemaphoreRequest].
+    pub fn acquire(&mut self, request: Arc<SemaphoreRequest>) {
+        if request.max_permits != self.max_permits {
+            let message = format!(
+                "Semaphore {} has {} permits, not {}",
+                self.name, self.max_permits, request.max_permits
+            );
+            request.notify_failed(&CString::new(message).unwrap_or_default());
+            return;
+        }
+
+        if request.permits > self.max_permits {
+            request.notify_failed(c"Requested more permits than the semaphore has");
+            return;
+        }
+
+        self.pending.push_back(request);
+        self.process_queue();
+    }
+
+    /// Removes a request, returning its permits if it has taken them.
+    ///
+    /// This runs as a native finalizer for a request, ensuring stopped isolates won't hold on to
+    /// permits.
+    pub fn release(&mut self, request: &Arc<SemaphoreRequest>) {
+        if let Some(index) = self.held.iter().position(|r| Arc::ptr_eq(r, request)) {
+            self.held.swap_remove(index);
+            self.available += request.permits;
+        }
+
+        self.pending.retain(|r| !Arc::ptr_eq(r, request));
+        self.process_queue();
+    }
+
+    /// Returns the permits taken by `client` and fails its pending requests.
+    pub fn shut_down_client(&mut self, client: &Arc<LockClient>) {
+        let is_client = |r: &Arc<SemaphoreRequest>| Arc::ptr_eq(&r.client, client);
+
+        for request in self.held.iter().filter(|r| is_client(r)) {
+            request.holds_permits.mark_cancelled();
+            self.available += request.permits;
+        }
+        self.held.retain(|r| !is_client(r));
+
+        for request in self.pending.iter().filter(|r| is_client(r)) {
+            if request.holds_permits.mark_cancelled() {
+                request.notify_failed(c"The client has been shut down");
+            }
+        }
+        self.pending.retain(|r| !is_client(r));
+        self.process_queue();
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.held.is_empty() && self.pending.is_empty()
+    }
+
+    /// Creates a snapshot of requests on this semaphore into the [RequestSnapshot], allowing
+    /// clients to inspect semaphores alongside locks.
+    pub fn snapshot_into(&self, into: &mut Vec<RequestSnapshot>) {
+        let name = Rc::new(CString::new(self.name.clone()).unwrap());
+        let pending = self.pending.iter().enumerate().map(|(i, r)| (r, Some(i)));
+        let held = self.held.iter().map(|r| (r, None));
+
+        for (request, queue_position) in pending.chain(held) {
+            into.push(RequestSnapshot {
+                name: name.clone(),
+                client_id: CString::new(request.client.name.clone()).unwrap(),
+                exclusive: false,
+                held: queue_position.is_none(),
+                policy: QueuePolicy::Fifo,
+                id: request.timeline.id,
+                enqueued_at: request.timeline.enqueued_at(),
+                granted_at: request.timeline.granted_at(),
+                queue_position,
+                steal: false,
+                if_available: false,
+                permits: Some(request.permits),
+                max_permits: Some(self.max_permits),
+                intention: false,
+            });
+        }
+    }
+
+    fn process_queue(&mut self) {
+        while let Some(front) = self.pending.front() {
+            if front.permits > self.available {
+                break;
+            }
+
+            let request = self.pending.pop_front().unwrap();
+            if request.holds_permits.mark_holds_lock() {
+                if request.notify_acquired() {
+                    self.available -= request.permits;
+                    request.timeline.mark_granted();
+                    self.held.push(request);
+                } else {
+                    request.holds_permits.reset_locked_bit();
+                }
+            }
+        }
+    }
+}
+
+impl SemaphoreRequest {
+    /// Notifies the attached Dart port that the permits have been taken.
+    fn notify_acquired(&self) -> bool {
+        let acquired = c"acquired".into();
+        let mut parts = [&acquired];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that the request could not be made.
+    pub(crate) fn notify_failed(&self, reason: &CStr) -> bool {
+        let failed = c"error".into();
+        let reason = reason.into();
+        let mut parts = [&failed, &reason];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+}
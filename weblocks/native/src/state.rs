@@ -1,16 +1,22 @@
 use std::{
-    collections::VecDeque,
-    ffi::CString,
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::{CStr, CString},
+    io,
     rc::Rc,
     sync::{
-        Arc,
-        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, Weak,
+        atomic::{AtomicU8, AtomicU64, Ordering},
     },
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
     LockClient, RequestSnapshot,
+    cross_process::{LockFile, LockFileSlot},
     dart::{DartObject, DartPort},
+    deadlock::CycleEntry,
+    group::LockGroup,
+    timer::Timer,
 };
 
 /// A request to obtain an exclusive or shared lease for a lock.
@@ -31,17 +37,207 @@ pub struct LockRequest {
     ///
     /// This is mutually exclusive with [Self::steal].
     pub if_available: bool,
+    /// Whether the lock should also be exclusive across processes, which is implemented with a
+    /// [LockFile].
+    ///
+    /// Holders in other processes can't be preempted, so stealing such a lock only steals it from
+    /// holders in this process.
+    pub cross_process: bool,
+    /// Whether the request should be failed if waiting for it would complete a cycle in the
+    /// [crate::deadlock::WaitForGraph].
+    pub detect_deadlocks: bool,
+    /// Whether the request should be granted immediately if the lock is already held by the same
+    /// owner (see [Self::is_same_owner]).
+    ///
+    /// The lock is then held until all requests of that owner have been released.
+    pub reentrant: bool,
+    /// An explicit owner token for [Self::reentrant] requests. If this is not set, the owner is the
+    /// [LockClient].
+    pub owner: Option<u64>,
+    /// If set, the request is dropped from the queue if it hasn't been granted by then.
+    pub deadline: Option<Instant>,
+    /// If set, the lock is only granted for this duration unless the lease is renewed with
+    /// [LockState::renew_lease]. Expired requests are released.
+    pub lease: Option<Duration>,
+    /// When the lease of a held request expires.
+    pub lease_expires: Mutex<Option<Instant>>,
+    /// The group this request is a member of, if it has been made through
+    /// [crate::manager::LockManager::lock_group].
+    pub group: Option<Weak<LockGroup>>,
     /// The `SendPort` to send completed, aborted or stolen lock events too.
     pub notify: DartPort,
     /// The current state of this request as it progresses.
     pub holds_lock: LockRequestState,
+    /// The id of this request and when it was made and granted, for snapshots.
+    pub timeline: RequestTimeline,
+    /// The timer for the [Self::deadline] of a pending request or the [Self::lease] of a held one.
+    pub timer: Mutex<Option<Timer>>,
+}
+
+/// A change to a [LockState], reported to [crate::watcher::LockWatcher]s.
+pub struct LockEvent {
+    pub kind: LockEventKind,
+    pub request: Arc<LockRequest>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LockEventKind {
+    /// The request has been added to the queue of the lock.
+    Enqueued,
+    /// The request left the queue without being granted because the lock file of a
+    /// [LockRequest::cross_process] request could not be locked.
+    Failed,
+    /// The lock has been granted to the request.
+    Granted,
+    /// The request has left the lock, either after holding it or while it was still pending (for
+    /// instance because it has been aborted or timed out).
+    Released,
+    /// The lock has been stolen from the request.
+    Stolen,
+    /// The request was an [LockRequest::if_available] request that could not be granted.
+    Unavailable,
+}
+
+impl LockEventKind {
+    pub fn name(self) -> &'static CStr {
+        match self {
+            Self::Enqueued => c"enqueued",
+            Self::Failed => c"failed",
+            Self::Granted => c"granted",
+            Self::Released => c"released",
+            Self::Stolen => c"stolen",
+            Self::Unavailable => c"unavailable",
+        }
+    }
+}
+
+/// Decides the order in which pending requests are granted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Requests are granted in the order in which they were made, as in the Web Locks API.
+    ///
+    /// A pending exclusive request blocks all later shared requests, even if the lock is currently
+    /// held in shared mode.
+    #[default]
+    Fifo,
+    /// Shared requests overtake pending exclusive requests whenever they can be granted.
+    ///
+    /// This maximizes concurrency for readers, but can starve exclusive requests.
+    ReaderPreferring,
+    /// Exclusive requests overtake pending shared requests, and no new shared requests are granted
+    /// while an exclusive request is waiting.
+    ///
+    /// This can starve shared requests.
+    WriterPreferring,
+    /// Shared and exclusive phases alternate: After an exclusive holder releases the lock, all
+    /// waiting shared requests are granted together. After that phase, the next exclusive request
+    /// is granted. Neither readers nor writers can starve.
+    PhaseFair,
+}
+
+impl QueuePolicy {
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(Self::Fifo),
+            1 => Some(Self::ReaderPreferring),
+            2 => Some(Self::WriterPreferring),
+            3 => Some(Self::PhaseFair),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static CStr {
+        match self {
+            Self::Fifo => c"fifo",
+            Self::ReaderPreferring => c"reader-preferring",
+            Self::WriterPreferring => c"writer-preferring",
+            Self::PhaseFair => c"phase-fair",
+        }
+    }
+}
+
+/// Restrictions a lock inherits from related locks when lock names are hierarchical.
+///
+/// With a separator like `/`, `db/users` is the parent of `db/users/42`. Requests holding a lock
+/// implicitly hold an intention lock on each ancestor: IS for shared requests and IX for exclusive
+/// requests. Intention locks are compatible with each other, IS is also compatible with shared
+/// locks. See [crate::manager::LockManager::set_hierarchy_separator].
+#[derive(Default)]
+struct HierarchyConstraints {
+    /// The separator of hierarchical names, or `None` if names are unrelated.
+    separator: Option<Arc<str>>,
+    /// The amount of shared requests holding a descendant of this lock.
+    ///
+    /// Intention counts are updated by the [crate::manager::LockManager] whenever a descendant is
+    /// granted or released.
+    intention_shared: usize,
+    /// The amount of exclusive requests holding a descendant of this lock.
+    intention_exclusive: usize,
+    /// Whether an ancestor of this lock is held in shared (`Some(true)`) or exclusive
+    /// (`Some(false)`) mode.
+    ///
+    /// This is refreshed before every change to this lock, since ancestors aren't tracking their
+    /// descendants.
+    ancestor_held: Option<bool>,
+}
+
+impl HierarchyConstraints {
+    /// Whether a request in the given mode is compatible with related locks.
+    fn allows(&self, shared: bool) -> bool {
+        if shared {
+            return self.intention_exclusive == 0 && self.ancestor_held != Some(false);
+        }
+
+        self.intention_shared == 0 && self.intention_exclusive == 0 && self.ancestor_held.is_none()
+    }
 }
 
 /// The state of a currently-referenced lock.
 pub struct LockState {
     pub name: String,
+    policy: QueuePolicy,
     pending: VecDeque<Arc<LockRequest>>,
     held: Option<HeldLockSet>,
+    /// Members of a [LockGroup] waiting for this lock.
+    ///
+    /// They're not part of the queue since a group is only granted once all of its locks are
+    /// available. Instead, they reserve the lock against requests made after them, see
+    /// [Self::reserved_for_group]. The [crate::manager::LockManager] grants them through
+    /// [Self::grant_group_member].
+    group_waiters: Vec<Arc<LockRequest>>,
+    /// A shared holder waiting for the other holders to leave so that it can hold the lock
+    /// exclusively. No pending requests are granted in the meantime.
+    upgrading: Option<Arc<LockRequest>>,
+    /// Requests waiting on a condition variable bound to this lock, keyed by the name of the
+    /// condition.
+    ///
+    /// These requests have released the lock and are put back into the queue when the condition
+    /// is notified.
+    conditions: HashMap<String, VecDeque<Arc<LockRequest>>>,
+    /// Restrictions from ancestors and descendants of this lock, kept up-to-date by the
+    /// [crate::manager::LockManager] while hierarchical names are enabled.
+    hierarchy: HierarchyConstraints,
+    /// Names of descendants with requests waiting for them, which need to be checked again once
+    /// this lock is released.
+    waiting_descendants: HashSet<String>,
+    /// Whether the last request to be granted was exclusive, used to alternate phases with
+    /// [QueuePolicy::PhaseFair].
+    last_grant_exclusive: bool,
+    /// With [QueuePolicy::PhaseFair], the amount of shared requests that may still be granted in
+    /// the current shared phase even though an exclusive request is waiting.
+    shared_phase_quota: usize,
+    /// The lock file held while this lock is granted to [LockRequest::cross_process] requests.
+    lock_file: LockFileSlot,
+    /// Set when we need to block on a lock file, in which case the [crate::manager::LockManager]
+    /// has to start a background thread doing that.
+    ///
+    /// The boolean is whether the file should be locked in shared mode.
+    lock_file_wait: Option<bool>,
+    /// Leases of newly granted requests, for which the [crate::manager::LockManager] needs to
+    /// schedule a timer.
+    lease_timers: Vec<(Weak<LockRequest>, Instant)>,
+    /// Changes to this lock that the [crate::manager::LockManager] reports to watchers.
+    events: Vec<LockEvent>,
 }
 
 struct HeldLockSet {
@@ -50,36 +246,331 @@ struct HeldLockSet {
 }
 
 impl LockState {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, policy: QueuePolicy) -> Self {
         Self {
             name,
+            policy,
             pending: Default::default(),
             held: Default::default(),
+            group_waiters: Vec::new(),
+            upgrading: None,
+            conditions: Default::default(),
+            hierarchy: Default::default(),
+            waiting_descendants: Default::default(),
+            last_grant_exclusive: false,
+            shared_phase_quota: 0,
+            lock_file: Default::default(),
+            lock_file_wait: None,
+            lease_timers: Vec::new(),
+            events: Vec::new(),
         }
     }
 
     /// Processes an incoming [LockRequest].
     pub fn lock(&mut self, request: Arc<LockRequest>) {
+        if request.reentrant && !request.steal && self.grant_reentrant(&request) {
+            return;
+        }
+
         // Loosely based on https://w3c.github.io/web-locks/#algorithm-request-lock.
         if request.steal {
+            self.upgrading = None;
             if let Some(held) = self.held.take() {
                 for entry in held.entries {
                     entry.notify_stolen();
+                    self.record(LockEventKind::Stolen, &entry);
                 }
             }
 
+            self.record(LockEventKind::Enqueued, &request);
             self.pending.push_front(request);
         } else {
             if request.if_available {
-                if !self.is_grantable(request.shared) {
+                if !self.is_available_for(&request) {
                     request.notify_not_available();
+                    self.record(LockEventKind::Unavailable, &request);
                     return;
                 }
+
+                if request.cross_process {
+                    match self.lock_file_for(request.shared, None) {
+                        LockFileStatus::Available => {}
+                        LockFileStatus::Blocked => {
+                            request.notify_not_available();
+                            self.record(LockEventKind::Unavailable, &request);
+                            return;
+                        }
+                        LockFileStatus::Failed(e) => {
+                            request.notify_failed(&e);
+                            return;
+                        }
+                    }
+                }
             }
 
+            self.record(LockEventKind::Enqueued, &request);
+            self.pending.push_back(request);
+        }
+
+        self.process_queue();
+    }
+
+    /// Grants `request` if that's possible without waiting, returning whether it has been granted.
+    ///
+    /// This succeeds exactly when a [LockRequest::if_available] request would be granted, see
+    /// [Self::is_available_for]. Since the caller learns about the outcome right away, no `locked`
+    /// or `unavailable` event is posted to the port of the request. Failing to lock the file of a
+    /// [LockRequest::cross_process] request is reported as the lock being unavailable.
+    pub fn try_lock(&mut self, request: &Arc<LockRequest>) -> bool {
+        let available = self.is_available_for(request)
+            && (!request.cross_process
+                || matches!(
+                    self.lock_file_for(request.shared, None),
+                    LockFileStatus::Available
+                ));
+        if !available {
+            self.record(LockEventKind::Unavailable, request);
+            return false;
+        }
+
+        request.holds_lock.mark_holds_lock();
+        self.record(LockEventKind::Enqueued, request);
+        self.hold(request.clone());
+        true
+    }
+
+    /// Grants a [LockRequest::reentrant] request if its owner already holds the lock at the time of
+    /// the request.
+    ///
+    /// A shared request can re-enter an exclusive hold, but an exclusive request can't re-enter a
+    /// shared hold since other owners may also hold the lock. Such requests are queued normally.
+    fn grant_reentrant(&mut self, request: &Arc<LockRequest>) -> bool {
+        let Some(held) = &mut self.held else {
+            return false;
+        };
+        if self.upgrading.is_some() {
+            return false;
+        }
+
+        let hold_count = held
+            .entries
+            .iter()
+            .filter(|e| e.is_same_owner(request))
+            .count();
+        if hold_count == 0 || (held.shared && !request.shared) {
+            return false;
+        }
+
+        // Cross-process requests need the lock file, which we may not hold if the lock has only
+        // been granted to requests local to this process.
+        if request.cross_process && !matches!(self.lock_file, LockFileSlot::Held(_)) {
+            return false;
+        }
+
+        if request.holds_lock.mark_holds_lock() {
+            if request.notify_reentrant_locked(hold_count + 1) {
+                held.entries.push(request.clone());
+                request.timeline.mark_granted();
+                request.cancel_timer();
+                self.start_lease(request);
+                self.record(LockEventKind::Granted, request);
+            } else {
+                request.holds_lock.reset_locked_bit();
+            }
+        }
+        true
+    }
+
+    /// Upgrades a held shared request to hold the lock exclusively.
+    ///
+    /// This waits for the other holders to release the lock, and no pending requests are granted
+    /// in the meantime. An `upgraded` event is posted to the request's port once it holds the lock
+    /// exclusively. If the request can't be upgraded, an `upgrade-failed` event with a reason is
+    /// posted instead.
+    pub fn upgrade_request(&mut self, request: &Arc<LockRequest>) {
+        let Some(held) = &self.held else {
+            request.notify_upgrade_failed(c"The lock is not held by this request");
+            return;
+        };
+        if !held.entries.iter().any(|e| Arc::ptr_eq(e, request)) {
+            request.notify_upgrade_failed(c"The lock is not held by this request");
+            return;
+        }
+
+        if !held.shared {
+            request.notify_upgraded();
+            return;
+        }
+
+        // Converting the lock file to an exclusive lock isn't atomic, another process could grab it
+        // in between.
+        if request.cross_process {
+            request.notify_upgrade_failed(c"Cross-process locks can't be upgraded");
+            return;
+        }
+
+        // Two holders waiting for each other to leave would never make progress.
+        if self.upgrading.is_some() {
+            request.notify_upgrade_failed(c"Another holder is already upgrading this lock");
+            return;
+        }
+
+        self.upgrading = Some(request.clone());
+        self.complete_upgrade();
+    }
+
+    /// Completes a pending [Self::upgrade_request] once the upgrading request is the only holder
+    /// left.
+    fn complete_upgrade(&mut self) {
+        let (Some(upgrading), Some(held)) = (&self.upgrading, &mut self.held) else {
+            return;
+        };
+
+        if held.entries.iter().all(|e| Arc::ptr_eq(e, upgrading)) && self.hierarchy.allows(false) {
+            held.shared = false;
+            self.last_grant_exclusive = true;
+            self.shared_phase_quota = 0;
+
+            upgrading.notify_upgraded();
+            self.upgrading = None;
+        }
+    }
+
+    /// Turns an exclusive lock held by `request` into a shared lock without releasing it.
+    ///
+    /// Pending shared requests may be granted afterwards, depending on the [QueuePolicy]. This does
+    /// nothing if the lock is already held in shared mode. For [LockRequest::cross_process]
+    /// requests, the lock file stays locked exclusively until the lock is released, so other
+    /// processes can't join.
+    pub fn downgrade_request(&mut self, request: &Arc<LockRequest>) {
+        let Some(held) = &mut self.held else {
+            return;
+        };
+
+        if !held.shared && held.entries.iter().any(|e| Arc::ptr_eq(e, request)) {
+            held.shared = true;
+            self.process_queue();
+        }
+    }
+
+    /// Atomically releases the exclusive lock held by `request` and waits for the `condition` to be
+    /// notified with [Self::notify_condition].
+    ///
+    /// If `request` doesn't hold the lock exclusively, a `wait-failed` event is posted to its port
+    /// instead.
+    pub fn wait_on_condition(&mut self, request: &Arc<LockRequest>, condition: String) {
+        let Some(held) = &mut self.held else {
+            request.notify_wait_failed();
+            return;
+        };
+        if held.shared || !held.entries.iter().any(|e| Arc::ptr_eq(e, request)) {
+            request.notify_wait_failed();
+            return;
+        }
+
+        held.entries.retain(|r| !Arc::ptr_eq(r, request));
+        if held.entries.is_empty() {
+            self.held = None;
+        }
+        self.record(LockEventKind::Released, request);
+
+        // Allow the request to be granted again once it's woken up.
+        request.holds_lock.reset_locked_bit();
+        self.conditions
+            .entry(condition)
+            .or_default()
+            .push_back(request.clone());
+        self.process_queue();
+    }
+
+    /// Wakes up one or `all` requests waiting on `condition`.
+    ///
+    /// Woken requests are notified with a `woken` event and put back into the queue, where they
+    /// wait for the lock to be granted again like any other request.
+    pub fn notify_condition(&mut self, condition: &str, all: bool) {
+        let Some(waiters) = self.conditions.get_mut(condition) else {
+            return;
+        };
+
+        let count = if all { waiters.len() } else { 1 };
+        let woken: Vec<_> = waiters.drain(..count).collect();
+        if waiters.is_empty() {
+            self.conditions.remove(condition);
+        }
+
+        for request in woken {
+            request.notify_woken();
+            request.timeline.requeue();
+            self.record(LockEventKind::Enqueued, &request);
             self.pending.push_back(request);
         }
+        self.process_queue();
+    }
+
+    /// Sets the separator of hierarchical names and whether an ancestor of this lock is currently
+    /// held, which needs to happen before requests for this lock are processed.
+    pub fn set_ancestor_held(&mut self, separator: Option<Arc<str>>, ancestor_held: Option<bool>) {
+        self.hierarchy.separator = separator;
+        self.hierarchy.ancestor_held = ancestor_held;
+    }
+
+    /// Adds to the amount of shared and exclusive requests holding descendants of this lock.
+    ///
+    /// Call [Self::refresh_hierarchy] afterwards if this has released intention locks.
+    pub fn add_intentions(&mut self, shared: isize, exclusive: isize) {
+        let hierarchy = &mut self.hierarchy;
+        hierarchy.intention_shared = hierarchy.intention_shared.saturating_add_signed(shared);
+        hierarchy.intention_exclusive = hierarchy
+            .intention_exclusive
+            .saturating_add_signed(exclusive);
+    }
+
+    /// Grants requests that may have been unblocked by related locks.
+    pub fn refresh_hierarchy(&mut self) {
+        self.complete_upgrade();
+        self.process_queue();
+    }
+
+    /// Forgets intention locks and waiting descendants, which need to be recomputed after the
+    /// separator of hierarchical names has changed.
+    pub fn reset_hierarchy(&mut self) {
+        self.hierarchy = Default::default();
+        self.waiting_descendants.clear();
+    }
+
+    /// Whether requests or group members are waiting for this lock to be granted.
+    pub fn has_waiters(&self) -> bool {
+        !self.pending.is_empty() || self.upgrading.is_some() || !self.group_waiters.is_empty()
+    }
+
+    /// Remembers whether the descendant called `name` has [Self::has_waiters].
+    pub fn set_waiting_descendant(&mut self, name: &str, waiting: bool) {
+        if !waiting {
+            self.waiting_descendants.remove(name);
+        } else if !self.waiting_descendants.contains(name) {
+            self.waiting_descendants.insert(name.to_string());
+        }
+    }
+
+    /// Takes the descendants that were waiting while this lock was held, which may have been
+    /// unblocked by releasing it.
+    pub fn take_waiting_descendants(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.waiting_descendants)
+    }
 
+    /// If this lock is held, returns whether it's held in shared mode and the amount of requests
+    /// holding it.
+    pub fn held_mode(&self) -> Option<(bool, usize)> {
+        self.held
+            .as_ref()
+            .map(|held| (held.shared, held.entries.len()))
+    }
+
+    /// Changes the [QueuePolicy] of this lock, which also applies to requests that are already
+    /// pending.
+    pub fn set_policy(&mut self, policy: QueuePolicy) {
+        self.policy = policy;
+        self.shared_phase_quota = 0;
         self.process_queue();
     }
 
@@ -88,6 +579,15 @@ impl LockState {
     /// This runs as a native finalizer for a request, ensuring stopped isolates won't cause
     /// deadlocks.
     pub fn clear_request(&mut self, request: &Arc<LockRequest>) {
+        let is_held = self
+            .held
+            .as_ref()
+            .is_some_and(|held| held.entries.iter().any(|r| Arc::ptr_eq(r, request)));
+        let is_group_waiter = self.group_waiters.iter().any(|r| Arc::ptr_eq(r, request));
+        if is_held || is_group_waiter || self.is_pending(request) {
+            self.record(LockEventKind::Released, request);
+        }
+
         if let Some(ref mut held) = self.held {
             held.entries.retain(|r| !Arc::ptr_eq(r, request));
             if held.entries.is_empty() {
@@ -95,12 +595,381 @@ impl LockState {
             }
         }
 
+        if let Some(upgrading) = &self.upgrading
+            && Arc::ptr_eq(upgrading, request)
+        {
+            self.upgrading = None;
+        }
+
         self.pending.retain(|r| !Arc::ptr_eq(r, request));
+        self.group_waiters.retain(|r| !Arc::ptr_eq(r, request));
+        self.conditions.retain(|_, waiters| {
+            waiters.retain(|r| !Arc::ptr_eq(r, request));
+            !waiters.is_empty()
+        });
+        self.complete_upgrade();
         self.process_queue();
     }
 
+    /// Aborts a request on behalf of its client, similar to an `AbortSignal` on the web.
+    ///
+    /// Unlike [Self::clear_request], this posts an `aborted` event to the request's port. Since
+    /// [LockRequest::steal] and [LockRequest::if_available] requests are resolved immediately, the
+    /// client may abort them before it has seen the outcome. In that case, the abort wins: A lock
+    /// that has already been granted is released again (although holders it has been stolen from
+    /// remain stolen), and the `aborted` event is posted after the `locked` or `unavailable`
+    /// event.
+    ///
+    /// This does nothing if the request has already been stolen, timed out or cancelled.
+    pub fn abort_request(&mut self, request: &Arc<LockRequest>) {
+        if request.holds_lock.mark_cancelled() {
+            self.clear_request(request);
+            request.notify_aborted();
+        }
+    }
+
+    /// Cancels all requests of `client`, notifying them with a `shutdown` event.
+    ///
+    /// Members of a [LockGroup] are not cancelled individually. Instead, their groups are returned
+    /// so that the [crate::manager::LockManager] can cancel each group as a whole.
+    pub fn shut_down_client(&mut self, client: &Arc<LockClient>) -> Vec<Arc<LockGroup>> {
+        let mut requests: Vec<_> = self.pending.iter().cloned().collect();
+        if let Some(held) = &self.held {
+            requests.extend(held.entries.iter().cloned());
+        }
+        requests.extend(self.conditions.values().flatten().cloned());
+        requests.extend(self.group_waiters.iter().cloned());
+        requests.retain(|r| Arc::ptr_eq(&r.client, client));
+
+        let mut groups = Vec::new();
+        for request in requests {
+            match request.group.as_ref().and_then(Weak::upgrade) {
+                Some(group) => groups.push(group),
+                None => self.cancel_for_shutdown(&request),
+            }
+        }
+        groups
+    }
+
+    /// Cancels a request because its client has been shut down.
+    ///
+    /// This does nothing if the request has already been cancelled.
+    pub fn cancel_for_shutdown(&mut self, request: &Arc<LockRequest>) {
+        if request.holds_lock.mark_cancelled() {
+            self.clear_request(request);
+            request.notify_shut_down();
+        }
+    }
+
+    /// Removes a request from the queue because its [LockRequest::deadline] has passed.
+    ///
+    /// This does nothing if the request has been granted or cancelled in the meantime.
+    pub fn time_out_request(&mut self, request: &Arc<LockRequest>) {
+        let Some(index) = self.pending.iter().position(|r| Arc::ptr_eq(r, request)) else {
+            return;
+        };
+
+        if request.holds_lock.mark_cancelled() {
+            self.pending.remove(index);
+            request.notify_timed_out();
+            self.record(LockEventKind::Released, request);
+            self.process_queue();
+        }
+    }
+
+    /// Adds a member of a [LockGroup] that waits for this lock until its group can be granted.
+    pub fn add_group_member(&mut self, request: Arc<LockRequest>) {
+        self.record(LockEventKind::Enqueued, &request);
+        self.group_waiters.push(request);
+    }
+
+    /// Whether a waiting member of a [LockGroup] could be granted right now.
+    ///
+    /// Members don't overtake requests made before them, so this also requires that no such
+    /// request is pending.
+    pub fn can_grant_group_member(&self, request: &Arc<LockRequest>) -> bool {
+        self.group_waiters.iter().any(|r| Arc::ptr_eq(r, request))
+            && !self.pending.iter().any(|r| r.is_older_than(request))
+            && !self.reserved_for_group(request)
+            && self.is_grantable(request.shared)
+    }
+
+    /// Returns groups with a waiting member that could be granted right now.
+    ///
+    /// The [crate::manager::LockManager] then checks whether the other locks of these groups are
+    /// available too.
+    pub fn ready_groups(&self) -> Vec<Weak<LockGroup>> {
+        self.group_waiters
+            .iter()
+            .filter(|r| self.can_grant_group_member(r))
+            .filter_map(|r| r.group.clone())
+            .collect()
+    }
+
+    /// Whether a waiting member of a [LockGroup] made before `request` conflicts with it.
+    ///
+    /// Such requests aren't granted before the group, so that groups can't be starved by a steady
+    /// stream of requests for one of their locks. Since requests are ordered by their id across all
+    /// locks, the oldest waiting group never waits for younger groups.
+    fn reserved_for_group(&self, request: &LockRequest) -> bool {
+        self.group_waiters
+            .iter()
+            .any(|member| member.is_older_than(request) && !(member.shared && request.shared))
+    }
+
+    /// Grants the lock to a waiting member of a [LockGroup], which must have been checked with
+    /// [Self::can_grant_group_member].
+    ///
+    /// Members are not notified individually, the group posts a single event once all of its
+    /// members have been granted.
+    pub fn grant_group_member(&mut self, request: &Arc<LockRequest>) {
+        self.group_waiters.retain(|r| !Arc::ptr_eq(r, request));
+        self.add_to_held(request.clone());
+        // Compatible requests that were waiting for the group may join it.
+        self.process_queue();
+    }
+
+    /// Starts the [LockRequest::lease] of a request that has just been granted.
+    fn start_lease(&mut self, request: &Arc<LockRequest>) {
+        if let Some(lease) = request.lease {
+            let expires = Instant::now() + lease;
+            *request.lease_expires.lock().unwrap() = Some(expires);
+            self.lease_timers.push((Arc::downgrade(request), expires));
+        }
+    }
+
+    /// Takes the changes recorded since the last call, which are reported to metrics and watchers.
+    pub fn take_events(&mut self) -> Vec<LockEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn record(&mut self, kind: LockEventKind, request: &Arc<LockRequest>) {
+        self.events.push(LockEvent {
+            kind,
+            request: request.clone(),
+        });
+    }
+
+    /// Returns leases for which a timer calling [Self::expire_lease] needs to be scheduled.
+    pub fn take_lease_timers(&mut self) -> Vec<(Weak<LockRequest>, Instant)> {
+        std::mem::take(&mut self.lease_timers)
+    }
+
+    /// Extends the [LockRequest::lease] of a held request, starting from now.
+    ///
+    /// This does nothing if the request doesn't hold the lock, for instance because its lease has
+    /// already expired.
+    pub fn renew_lease(&mut self, request: &Arc<LockRequest>) {
+        let Some(lease) = request.lease else {
+            return;
+        };
+
+        let holds_lock = self
+            .held
+            .as_ref()
+            .is_some_and(|held| held.entries.iter().any(|e| Arc::ptr_eq(e, request)));
+        if holds_lock {
+            *request.lease_expires.lock().unwrap() = Some(Instant::now() + lease);
+        }
+    }
+
+    /// Releases a held request if its lease has expired, notifying it with an `expired` event.
+    ///
+    /// If the lease has been renewed in the meantime, returns the new expiry time at which this
+    /// needs to be checked again.
+    pub fn expire_lease(&mut self, request: &Arc<LockRequest>) -> Option<Instant> {
+        let holds_lock = self
+            .held
+            .as_ref()
+            .is_some_and(|held| held.entries.iter().any(|e| Arc::ptr_eq(e, request)));
+        if !holds_lock {
+            return None;
+        }
+
+        let expires = (*request.lease_expires.lock().unwrap())?;
+        if expires > Instant::now() {
+            return Some(expires);
+        }
+
+        if request.holds_lock.mark_cancelled() {
+            self.clear_request(request);
+            request.notify_expired();
+        }
+        None
+    }
+
+    /// Reports edges of the wait-for graph introduced by this lock.
+    ///
+    /// `f` is called with a waiting client and a client blocking it, which is either a client
+    /// holding the lock in an incompatible mode or a client with a request that the [QueuePolicy]
+    /// grants first (see [Self::waits_behind]). Clients waiting on themselves are not reported,
+    /// since clients are asynchronous and may release the lock while waiting. A holder upgrading
+    /// the lock waits for the other holders, and pending requests wait for the upgrade.
+    pub fn wait_for_edges(&self, mut f: impl FnMut(&Arc<LockClient>, &Arc<LockClient>)) {
+        let mut report = |waiting: &LockRequest, blocker: &LockRequest| {
+            if !Arc::ptr_eq(&waiting.client, &blocker.client) {
+                f(&waiting.client, &blocker.client);
+            }
+        };
+
+        if let (Some(upgrading), Some(held)) = (&self.upgrading, &self.held) {
+            for blocker in &held.entries {
+                report(upgrading, blocker);
+            }
+        }
+
+        for (index, waiting) in self.pending.iter().enumerate() {
+            if let Some(upgrading) = &self.upgrading {
+                report(waiting, upgrading);
+            }
+
+            if let Some(held) = &self.held
+                && (!waiting.shared || !held.shared)
+            {
+                for blocker in &held.entries {
+                    report(waiting, blocker);
+                }
+            }
+
+            for (other_index, blocker) in self.pending.iter().enumerate() {
+                if other_index != index && self.waits_behind(index, other_index) {
+                    report(waiting, blocker);
+                }
+            }
+
+            for member in &self.group_waiters {
+                if member.is_older_than(waiting) && !(member.shared && waiting.shared) {
+                    report(waiting, member);
+                }
+            }
+        }
+
+        for member in &self.group_waiters {
+            if let Some(held) = &self.held
+                && (!member.shared || !held.shared)
+            {
+                for blocker in &held.entries {
+                    report(member, blocker);
+                }
+            }
+
+            for blocker in self.pending.iter().filter(|r| r.is_older_than(member)) {
+                report(member, blocker);
+            }
+        }
+    }
+
+    /// Whether the pending request at `index` can only be granted after the one at `other_index`.
+    ///
+    /// This mirrors the order in which [Self::next_by_policy] grants requests: A request doesn't
+    /// wait for requests it may overtake, or for compatible requests that are granted together
+    /// with it.
+    fn waits_behind(&self, index: usize, other_index: usize) -> bool {
+        self.waits_for(
+            &self.pending[index],
+            &self.pending[other_index],
+            other_index < index,
+        )
+    }
+
+    /// Whether `waiting` can only be granted after the pending request `other`, which is ahead of
+    /// it in the queue if `ahead` is set.
+    fn waits_for(&self, waiting: &LockRequest, other: &LockRequest, ahead: bool) -> bool {
+        if other.steal {
+            return ahead;
+        }
+
+        match self.policy {
+            QueuePolicy::Fifo => ahead,
+            // Shared requests are granted whenever the lock isn't held exclusively, exclusive
+            // requests wait for all of them.
+            QueuePolicy::ReaderPreferring => !waiting.shared && (other.shared || ahead),
+            // Exclusive requests overtake shared requests, which wait for all of them.
+            QueuePolicy::WriterPreferring => !other.shared && (waiting.shared || ahead),
+            // Waiting shared requests are granted together when an exclusive holder leaves, so
+            // they only wait for exclusive requests ahead of them during a shared phase.
+            QueuePolicy::PhaseFair if waiting.shared => {
+                !other.shared && ahead && self.held.as_ref().is_some_and(|held| held.shared)
+            }
+            QueuePolicy::PhaseFair => other.shared || ahead,
+        }
+    }
+
+    /// Removes a pending request that would complete a cycle in the wait-for graph, notifying it
+    /// with the `cycle`.
+    pub fn fail_deadlocked(&mut self, request: &Arc<LockRequest>, cycle: &[CycleEntry]) {
+        if !self.is_pending(request) {
+            return;
+        }
+
+        if request.holds_lock.mark_cancelled() {
+            self.pending.retain(|r| !Arc::ptr_eq(r, request));
+            request.notify_deadlock(cycle);
+            self.record(LockEventKind::Released, request);
+            self.process_queue();
+        }
+    }
+
+    /// Returns the amount of pending requests and the amount of requests holding this lock.
+    pub fn queue_lengths(&self) -> (usize, usize) {
+        let holders = self.held.as_ref().map_or(0, |held| held.entries.len());
+        (self.pending.len() + self.group_waiters.len(), holders)
+    }
+
+    /// Whether `request` is currently waiting in the queue of this lock.
+    pub fn is_pending(&self, request: &Arc<LockRequest>) -> bool {
+        self.pending.iter().any(|r| Arc::ptr_eq(r, request))
+    }
+
+    /// Whether requests are holding or waiting for this lock.
+    ///
+    /// Locks that aren't in use may still be kept around for the intention locks of their
+    /// descendants.
+    pub fn is_in_use(&self) -> bool {
+        self.held.is_some()
+            || !self.pending.is_empty()
+            || !self.group_waiters.is_empty()
+            || !self.conditions.is_empty()
+    }
+
     pub fn is_idle(&self) -> bool {
-        return self.held.is_none() && self.pending.is_empty();
+        !self.is_in_use()
+            && matches!(self.lock_file, LockFileSlot::Unlocked)
+            && self.hierarchy.intention_shared == 0
+            && self.hierarchy.intention_exclusive == 0
+            && self.waiting_descendants.is_empty()
+    }
+
+    /// If [Self::process_queue] is blocked on a lock file held by another process, returns
+    /// whether the lock file needs to be locked in shared mode.
+    ///
+    /// The caller is responsible for acquiring the lock file in the background and then calling
+    /// [Self::lock_file_acquired].
+    pub fn take_lock_file_wait(&mut self) -> Option<bool> {
+        self.lock_file_wait.take()
+    }
+
+    /// Completes a blocking wait started after [Self::take_lock_file_wait].
+    pub fn lock_file_acquired(&mut self, result: io::Result<LockFile>) {
+        match result {
+            Ok(file) => self.lock_file = LockFileSlot::Held(file),
+            Err(e) => {
+                let waiter = match std::mem::take(&mut self.lock_file) {
+                    LockFileSlot::Waiting(waiter) => waiter.upgrade(),
+                    _ => None,
+                };
+                // Fail the request the wait was started for, unless it has left the queue since.
+                if let Some(waiter) = waiter
+                    && self.is_pending(&waiter)
+                {
+                    self.pending.retain(|r| !Arc::ptr_eq(r, &waiter));
+                    waiter.notify_failed(&e);
+                    self.record(LockEventKind::Failed, &waiter);
+                }
+            }
+        }
+
+        self.process_queue();
     }
 
     /// Creates a snapshot of the current lock state into the [RequestSnapshot], allowing clients
@@ -108,74 +977,345 @@ impl LockState {
     pub fn snapshot_into(&self, into: &mut Vec<RequestSnapshot>) {
         let name = Rc::new(CString::new(self.name.clone()).unwrap());
 
-        for pending in &self.pending {
+        for (position, pending) in self.pending.iter().enumerate() {
             into.push(RequestSnapshot {
                 name: name.clone(),
                 client_id: CString::new(pending.client.name.clone()).unwrap(),
                 exclusive: !pending.shared,
                 held: false,
+                policy: self.policy,
+                id: pending.timeline.id,
+                enqueued_at: pending.timeline.enqueued_at(),
+                granted_at: None,
+                queue_position: Some(position),
+                steal: pending.steal,
+                if_available: pending.if_available,
+                permits: None,
+                max_permits: None,
+                intention: false,
             });
         }
 
-        if let Some(active) = &self.held {
-            for active in &active.entries {
+        for waiting in &self.group_waiters {
+            into.push(RequestSnapshot {
+                name: name.clone(),
+                client_id: CString::new(waiting.client.name.clone()).unwrap(),
+                exclusive: !waiting.shared,
+                held: false,
+                policy: self.policy,
+                id: waiting.timeline.id,
+                enqueued_at: waiting.timeline.enqueued_at(),
+                granted_at: None,
+                // Groups wait outside of the queue.
+                queue_position: None,
+                steal: false,
+                if_available: false,
+                permits: None,
+                max_permits: None,
+                intention: false,
+            });
+        }
+
+        if let Some(held) = &self.held {
+            for active in &held.entries {
                 into.push(RequestSnapshot {
                     name: name.clone(),
                     client_id: CString::new(active.client.name.clone()).unwrap(),
-                    exclusive: !active.shared,
+                    // Requests may have been upgraded or downgraded since they were granted, so
+                    // report the mode the lock is held in.
+                    exclusive: !held.shared,
                     held: true,
+                    policy: self.policy,
+                    id: active.timeline.id,
+                    enqueued_at: active.timeline.enqueued_at(),
+                    granted_at: active.timeline.granted_at(),
+                    queue_position: None,
+                    steal: active.steal,
+                    if_available: active.if_available,
+                    permits: None,
+                    max_permits: None,
+                    intention: false,
                 });
             }
+
+            if let Some(separator) = &self.hierarchy.separator {
+                self.snapshot_intentions(separator, held, into);
+            }
         }
     }
 
-    /// Grants the lock to a pending request, if possible.
+    /// Reports the intention locks implicitly held on ancestors of this lock.
+    fn snapshot_intentions(
+        &self,
+        separator: &str,
+        held: &HeldLockSet,
+        into: &mut Vec<RequestSnapshot>,
+    ) {
+        for (end, _) in self.name.match_indices(separator) {
+            if end == 0 {
+                continue;
+            }
+
+            let ancestor = Rc::new(CString::new(&self.name[..end]).unwrap());
+            for active in &held.entries {
+                into.push(RequestSnapshot {
+                    name: ancestor.clone(),
+                    client_id: CString::new(active.client.name.clone()).unwrap(),
+                    exclusive: !held.shared,
+                    held: true,
+                    policy: QueuePolicy::Fifo,
+                    // Intention locks are described by the request implying them.
+                    id: active.timeline.id,
+                    enqueued_at: active.timeline.enqueued_at(),
+                    granted_at: active.timeline.granted_at(),
+                    queue_position: None,
+                    steal: active.steal,
+                    if_available: active.if_available,
+                    permits: None,
+                    max_permits: None,
+                    intention: true,
+                });
+            }
+        }
+    }
+
+    /// Grants the lock to pending requests, if possible.
     fn process_queue(&mut self) {
-        while !self.pending.is_empty() {
-            let Some(entry) = self.pending.get(0) else {
-                break;
-            };
+        while let Some(index) = self.next_grantable() {
+            let entry = self.pending[index].clone();
+            let shared = entry.shared;
+
+            if entry.cross_process {
+                match self.lock_file_for(shared, Some(&entry)) {
+                    LockFileStatus::Available => {}
+                    LockFileStatus::Blocked => break,
+                    LockFileStatus::Failed(e) => {
+                        self.pending.remove(index);
+                        entry.notify_failed(&e);
+                        self.record(LockEventKind::Failed, &entry);
+                        continue;
+                    }
+                }
+            }
 
-            if !self.is_grantable(entry.shared) {
-                // If this entry can't be granted, subsequent entries are blocked too.
-                break;
+            let entry = self.pending.remove(index).unwrap();
+            if shared && self.shared_phase_quota > 0 {
+                self.shared_phase_quota -= 1;
             }
-            let entry = self.pending.pop_front().unwrap();
             self.add_to_held(entry);
         }
+
+        self.release_unused_lock_file();
+    }
+
+    /// Picks the index of the next pending request to grant.
+    fn next_grantable(&mut self) -> Option<usize> {
+        if self.upgrading.is_some() {
+            return None;
+        }
+
+        let index = self.next_by_policy()?;
+        let request = &self.pending[index];
+        let reserved = !request.steal && self.reserved_for_group(request);
+        (self.hierarchy.allows(request.shared) && !reserved).then_some(index)
+    }
+
+    /// Picks the index of the next pending request according to the [QueuePolicy], without
+    /// considering [HierarchyConstraints].
+    fn next_by_policy(&mut self) -> Option<usize> {
+        let front = self.pending.front()?;
+        if front.steal {
+            // Stealing requests are put in front of the queue and take precedence over policies.
+            return self.is_grantable(false).then_some(0);
+        }
+
+        let first_shared = self.pending.iter().position(|r| r.shared);
+        let first_exclusive = self.pending.iter().position(|r| !r.shared);
+
+        match self.policy {
+            QueuePolicy::Fifo => {
+                let shared = front.shared;
+                // If the first entry can't be granted, subsequent entries are blocked too.
+                self.is_grantable(shared).then_some(0)
+            }
+            QueuePolicy::ReaderPreferring => match first_shared {
+                Some(index) if self.is_grantable(true) => Some(index),
+                _ => first_exclusive.filter(|_| self.is_grantable(false)),
+            },
+            QueuePolicy::WriterPreferring => match first_exclusive {
+                Some(index) => self.is_grantable(false).then_some(index),
+                None => first_shared.filter(|_| self.is_grantable(true)),
+            },
+            QueuePolicy::PhaseFair => match &self.held {
+                // In a shared phase, new shared requests may only join if no exclusive request is
+                // waiting, or if they were already waiting when the phase started.
+                Some(held) if held.shared => first_shared
+                    .filter(|_| first_exclusive.is_none() || self.shared_phase_quota > 0),
+                Some(_) => None,
+                None => {
+                    if self.last_grant_exclusive && first_shared.is_some() {
+                        // Start a shared phase including all currently waiting shared requests.
+                        self.shared_phase_quota = self.pending.iter().filter(|r| r.shared).count();
+                        first_shared
+                    } else {
+                        first_exclusive.or(first_shared)
+                    }
+                }
+            },
+        }
+    }
+
+    /// Makes sure this process holds the lock file in a mode that is compatible with `shared`.
+    ///
+    /// If the file is locked by another process and a pending `waiter` is given, schedules a
+    /// blocking wait on its behalf through [Self::take_lock_file_wait].
+    fn lock_file_for(&mut self, shared: bool, waiter: Option<&Arc<LockRequest>>) -> LockFileStatus {
+        match &self.lock_file {
+            LockFileSlot::Waiting(_) => return LockFileStatus::Blocked,
+            LockFileSlot::Held(file) if shared || !file.shared => {
+                return LockFileStatus::Available;
+            }
+            LockFileSlot::Held(_) => {
+                // We hold a shared lock but need an exclusive one. This is only reachable when
+                // no request holds the lock in this process, so we can release the file first.
+                self.lock_file = LockFileSlot::Unlocked;
+            }
+            LockFileSlot::Unlocked => {}
+        }
+
+        match LockFile::try_acquire(&self.name, shared) {
+            Ok(Some(file)) => {
+                self.lock_file = LockFileSlot::Held(file);
+                LockFileStatus::Available
+            }
+            Ok(None) => {
+                if let Some(waiter) = waiter {
+                    self.lock_file = LockFileSlot::Waiting(Arc::downgrade(waiter));
+                    self.lock_file_wait = Some(shared);
+                }
+                LockFileStatus::Blocked
+            }
+            Err(e) => LockFileStatus::Failed(e),
+        }
+    }
+
+    /// Unlocks the lock file once no cross-process request holds the lock anymore.
+    fn release_unused_lock_file(&mut self) {
+        if !matches!(self.lock_file, LockFileSlot::Held(_)) {
+            return;
+        }
+
+        let in_use = match &self.held {
+            Some(held) => held.entries.iter().any(|e| e.cross_process),
+            None => false,
+        };
+        if !in_use {
+            self.lock_file = LockFileSlot::Unlocked;
+        }
+    }
+
+    /// Whether a new `request` could be granted right away, which is how
+    /// [LockRequest::if_available] requests and [Self::try_lock] are admitted.
+    ///
+    /// Besides the lock being [grantable](Self::is_grantable), this requires that the request
+    /// doesn't have to wait for a pending request according to the [QueuePolicy], or for a
+    /// [LockGroup] it can't overtake.
+    fn is_available_for(&self, request: &LockRequest) -> bool {
+        self.is_grantable(request.shared)
+            && !self.reserved_for_group(request)
+            && !self
+                .pending
+                .iter()
+                .any(|other| self.waits_for(request, other, true))
     }
 
     /// Whether a given request could be granted immediately.
     ///
     /// This is the case if the lock is not currently held, or if a shared request is made while the
-    /// lock is held by shared requests.
-    fn is_grantable(&mut self, shared: bool) -> bool {
+    /// lock is held by shared requests that aren't being upgraded. With hierarchical names, the
+    /// request must also be compatible with intention locks and ancestors.
+    fn is_grantable(&self, shared: bool) -> bool {
+        if self.upgrading.is_some() || !self.hierarchy.allows(shared) {
+            return false;
+        }
+
         let Some(held) = &self.held else {
             return true;
         };
 
-        return held.shared && shared;
+        held.shared && shared
     }
 
     fn add_to_held(&mut self, request: Arc<LockRequest>) {
+        if request.holds_lock.mark_holds_lock() {
+            // Members of a group are notified once the whole group has been granted.
+            if request.group.is_some() || request.notify_locked() {
+                self.hold(request);
+            } else {
+                request.holds_lock.reset_locked_bit();
+            }
+        }
+    }
+
+    /// Adds a granted request to the holders of this lock.
+    fn hold(&mut self, request: Arc<LockRequest>) {
         let held = self.held.get_or_insert_with(|| HeldLockSet {
             shared: request.shared,
             entries: Vec::default(),
         });
 
         assert!(held.shared == request.shared);
-        if request.holds_lock.mark_holds_lock() {
-            if request.notify_locked() {
-                request.holds_lock.mark_holds_lock();
-                held.entries.push(request);
-            } else {
-                request.holds_lock.reset_locked_bit();
-            }
+        self.last_grant_exclusive = !request.shared;
+        if !request.shared {
+            self.shared_phase_quota = 0;
         }
+
+        held.entries.push(request.clone());
+        request.timeline.mark_granted();
+        request.cancel_timer();
+        self.start_lease(&request);
+        self.record(LockEventKind::Granted, &request);
     }
 }
 
+enum LockFileStatus {
+    Available,
+    Blocked,
+    Failed(io::Error),
+}
+
 impl LockRequest {
+    /// Whether this request has been made before `other`.
+    fn is_older_than(&self, other: &LockRequest) -> bool {
+        self.timeline.id < other.timeline.id
+    }
+
+    /// Replaces the [Self::timer] of this request, cancelling the previous one.
+    pub fn set_timer(&self, timer: Timer) {
+        if let Some(previous) = self.timer.lock().unwrap().replace(timer) {
+            previous.cancel();
+        }
+    }
+
+    /// Cancels the [Self::timer] of this request, if it has one.
+    pub fn cancel_timer(&self) {
+        if let Some(timer) = self.timer.lock().unwrap().take() {
+            timer.cancel();
+        }
+    }
+
+    /// Whether this request and `other` have the same owner for [Self::reentrant] requests.
+    ///
+    /// That's the case if they have the same explicit [Self::owner] token, or if neither of them
+    /// has a token and both have been made by the same client.
+    fn is_same_owner(&self, other: &LockRequest) -> bool {
+        match (self.owner, other.owner) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => Arc::ptr_eq(&self.client, &other.client),
+            _ => false,
+        }
+    }
+
     /// Notifies the attached Dart port that the request has been granted.
     fn notify_locked(&self) -> bool {
         let locked = c"locked".into();
@@ -185,6 +1325,69 @@ impl LockRequest {
             .send(&self.client.api, &mut DartObject::array(&mut parts))
     }
 
+    /// Notifies the attached Dart port that a [Self::reentrant] request has been granted because
+    /// its owner already held the lock.
+    ///
+    /// The message includes the amount of requests of that owner holding the lock.
+    fn notify_reentrant_locked(&self, hold_count: usize) -> bool {
+        let locked = c"locked".into();
+        let hold_count = DartObject::from(hold_count as i64);
+        let mut parts = [&locked, &hold_count];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that the request now holds the lock exclusively after
+    /// [LockState::upgrade_request].
+    fn notify_upgraded(&self) -> bool {
+        let upgraded = c"upgraded".into();
+        let mut parts = [&upgraded];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that [LockState::upgrade_request] failed.
+    fn notify_upgrade_failed(&self, reason: &CStr) -> bool {
+        let failed = c"upgrade-failed".into();
+        let reason = reason.into();
+        let mut parts = [&failed, &reason];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that [LockState::wait_on_condition] failed because the
+    /// request doesn't hold the lock exclusively.
+    fn notify_wait_failed(&self) -> bool {
+        let failed = c"wait-failed".into();
+        let mut parts = [&failed];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that the condition this request was waiting on has been
+    /// notified. A `locked` event follows once the lock has been granted again.
+    fn notify_woken(&self) -> bool {
+        let woken = c"woken".into();
+        let mut parts = [&woken];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that the request has been released because its
+    /// [LockRequest::lease] has expired.
+    fn notify_expired(&self) -> bool {
+        let expired = c"expired".into();
+        let mut parts = [&expired];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
     /// Notifies the attached Dart port that the request has been stolen.
     fn notify_stolen(&self) {
         if self.holds_lock.mark_cancelled() {
@@ -196,6 +1399,46 @@ impl LockRequest {
         }
     }
 
+    /// Notifies the attached Dart port that the request has been removed because waiting for it
+    /// would deadlock.
+    ///
+    /// The message contains the name of each lock in the cycle, followed by the name of the client
+    /// waiting for it.
+    fn notify_deadlock(&self, cycle: &[CycleEntry]) -> bool {
+        // Names containing a nul byte can't be represented as C strings and are reported as empty.
+        let mut names = Vec::<CString>::new();
+        for entry in cycle {
+            names.push(CString::new(entry.lock.clone()).unwrap_or_default());
+            names.push(CString::new(entry.client.name.clone()).unwrap_or_default());
+        }
+
+        let mut parts = vec![DartObject::from(c"deadlock")];
+        parts.extend(names.iter().map(|name| DartObject::from(name.as_c_str())));
+        let mut parts: Vec<&DartObject> = parts.iter().collect();
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that the request has been cancelled because its client has
+    /// been shut down.
+    fn notify_shut_down(&self) -> bool {
+        let shutdown = c"shutdown".into();
+        let mut parts = [&shutdown];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that the request has been aborted by its client.
+    fn notify_aborted(&self) -> bool {
+        let aborted = c"aborted".into();
+        let mut parts = [&aborted];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
     /// Notifies the attached Dart port that a [LockRequest::if_available] request did not go
     /// through.
     fn notify_not_available(&self) -> bool {
@@ -205,6 +1448,28 @@ impl LockRequest {
         self.notify
             .send(&self.client.api, &mut DartObject::array(&mut parts))
     }
+
+    /// Notifies the attached Dart port that the request has been removed from the queue after its
+    /// [LockRequest::deadline].
+    fn notify_timed_out(&self) -> bool {
+        let timeout = c"timeout".into();
+        let mut parts = [&timeout];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
+
+    /// Notifies the attached Dart port that the request could not be completed because of an
+    /// I/O error on the lock file of a [LockRequest::cross_process] request.
+    pub(crate) fn notify_failed(&self, error: &io::Error) -> bool {
+        let message = CString::new(error.to_string()).unwrap_or_default();
+        let failed = c"error".into();
+        let message = message.as_c_str().into();
+        let mut parts = [&failed, &message];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts))
+    }
 }
 
 #[repr(transparent)]
@@ -219,7 +1484,7 @@ impl LockRequestState {
     /// before.
     pub fn mark_holds_lock(&self) -> bool {
         let prev = self.0.fetch_or(Self::FLAG_HOLDS_LOCK, Ordering::SeqCst);
-        return prev == 0;
+        prev == 0
     }
 
     /// Marks this request as no longer holding the lock.
@@ -230,6 +1495,65 @@ impl LockRequestState {
     /// Marks this request as cancelled.
     pub fn mark_cancelled(&self) -> bool {
         let previous = self.0.fetch_or(Self::FLAG_CANCELLED, Ordering::SeqCst);
-        return previous & Self::FLAG_CANCELLED == 0;
+        previous & Self::FLAG_CANCELLED == 0
+    }
+}
+
+/// Describes the lifetime of a lock or semaphore request in snapshots.
+pub struct RequestTimeline {
+    /// An id identifying the request across snapshots, unique within the process.
+    pub id: u64,
+    times: Mutex<TimelineTimes>,
+}
+
+struct TimelineTimes {
+    /// When the request has been added to the queue it's currently waiting in or was granted from.
+    enqueued_at: SystemTime,
+    granted_at: Option<SystemTime>,
+}
+
+impl RequestTimeline {
+    /// Records that the request has been granted now.
+    pub fn mark_granted(&self) {
+        self.times
+            .lock()
+            .unwrap()
+            .granted_at
+            .get_or_insert_with(SystemTime::now);
+    }
+
+    /// Records that a request which has been granted before waits in the queue again, for instance
+    /// after waiting on a condition.
+    ///
+    /// This restarts the wait, so that it's not mixed up with the time the lock was held before.
+    pub fn requeue(&self) {
+        *self.times.lock().unwrap() = TimelineTimes {
+            enqueued_at: SystemTime::now(),
+            granted_at: None,
+        };
+    }
+
+    /// When the request was made, or last [requeued](Self::requeue).
+    pub fn enqueued_at(&self) -> SystemTime {
+        self.times.lock().unwrap().enqueued_at
+    }
+
+    /// When the request was granted, if it has been granted.
+    pub fn granted_at(&self) -> Option<SystemTime> {
+        self.times.lock().unwrap().granted_at
+    }
+}
+
+impl Default for RequestTimeline {
+    fn default() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            times: Mutex::new(TimelineTimes {
+                enqueued_at: SystemTime::now(),
+                granted_at: None,
+            }),
+        }
     }
 }
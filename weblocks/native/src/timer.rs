@@ -0,0 +1,108 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Condvar, Mutex},
+    time::Instant,
+};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// The global timer queue, processed by a single background thread.
+    static ref TIMERS: TimerQueue = TimerQueue::start();
+}
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// Identifies a scheduled callback. The sequence number breaks ties between timers with the same
+/// deadline, so that they fire in the order in which they were scheduled.
+type TimerKey = (Instant, u64);
+
+#[derive(Default)]
+struct TimerQueueState {
+    timers: BTreeMap<TimerKey, Callback>,
+    next_sequence: u64,
+}
+
+/// A queue of callbacks to invoke at a given point in time.
+///
+/// We don't have an event loop in this library, so this spawns a thread sleeping until the next
+/// timer is due. Callbacks run on that thread, so they must not block.
+struct TimerQueue {
+    state: Mutex<TimerQueueState>,
+    changed: Condvar,
+}
+
+impl TimerQueue {
+    fn start() -> Self {
+        std::thread::spawn(|| TIMERS.run());
+
+        Self {
+            state: Default::default(),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let next_deadline = state
+                .timers
+                .first_key_value()
+                .map(|((deadline, _), _)| *deadline);
+
+            match next_deadline {
+                Some(deadline) if deadline <= now => {
+                    let (_, callback) = state.timers.pop_first().unwrap();
+
+                    // Don't hold the lock while running the callback, it might schedule timers.
+                    drop(state);
+                    callback();
+                    state = self.state.lock().unwrap();
+                }
+                Some(deadline) => {
+                    state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+                }
+                None => {
+                    state = self.changed.wait(state).unwrap();
+                }
+            }
+        }
+    }
+
+    fn schedule(&self, deadline: Instant, callback: Callback) -> Timer {
+        let mut state = self.state.lock().unwrap();
+        let key = (deadline, state.next_sequence);
+        state.next_sequence += 1;
+        state.timers.insert(key, callback);
+
+        self.changed.notify_one();
+        Timer { key }
+    }
+
+    fn cancel(&self, key: TimerKey) {
+        // The background thread may still wake up at the deadline of the removed timer, which is
+        // harmless.
+        self.state.lock().unwrap().timers.remove(&key);
+    }
+}
+
+/// A callback scheduled with [schedule].
+pub struct Timer {
+    key: TimerKey,
+}
+
+impl Timer {
+    /// Removes the callback from the queue if it hasn't run yet.
+    pub fn cancel(self) {
+        TIMERS.cancel(self.key);
+    }
+}
+
+/// Invokes `callback` on a background thread once `deadline` has passed.
+///
+/// The returned [Timer] can be used to cancel the callback. Dropping it doesn't cancel the
+/// callback.
+pub fn schedule(deadline: Instant, callback: impl FnOnce() + Send + 'static) -> Timer {
+    TIMERS.schedule(deadline, Box::new(callback))
+}
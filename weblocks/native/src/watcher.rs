@@ -0,0 +1,94 @@
+use std::{
+    ffi::CString,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    LockClient,
+    dart::{DartObject, DartPort},
+    state::LockEvent,
+};
+
+/// A registration observing changes to a lock, or to all locks whose name starts with a prefix.
+pub struct LockWatcher {
+    /// The name of the watched lock, or the prefix of watched lock names if [Self::prefix] is set.
+    pub pattern: String,
+    pub prefix: bool,
+    /// The client that registered this watcher.
+    pub(crate) client: Arc<LockClient>,
+    /// The `SendPort` to post events to.
+    pub notify: DartPort,
+}
+
+impl LockWatcher {
+    fn matches(&self, name: &str) -> bool {
+        if self.prefix {
+            name.starts_with(&self.pattern)
+        } else {
+            name == self.pattern
+        }
+    }
+
+    /// Posts an event as `[kind, lock name, client id, request id, exclusive]`.
+    fn notify(&self, name: &CString, event: &LockEvent) {
+        let kind = event.kind.name().into();
+        let name = name.as_c_str().into();
+        let client = CString::new(event.request.client.name.clone()).unwrap_or_default();
+        let client = client.as_c_str().into();
+        let id = DartObject::from(event.request.timeline.id as i64);
+        let exclusive = DartObject::from(!event.request.shared);
+        let mut parts = [&kind, &name, &client, &id, &exclusive];
+
+        self.notify
+            .send(&self.client.api, &mut DartObject::array(&mut parts));
+    }
+}
+
+/// All watchers registered on a [crate::manager::LockManager].
+///
+/// This is read after every change to a lock while its shard is locked, so it uses a read-write
+/// lock to not serialize changes to unrelated locks. Shards must not be locked while holding it.
+#[derive(Default)]
+pub struct WatcherRegistry {
+    watchers: RwLock<Vec<Arc<LockWatcher>>>,
+}
+
+impl WatcherRegistry {
+    /// Registers `watcher` unless its client has been shut down.
+    pub fn add(&self, watcher: Arc<LockWatcher>) {
+        // Checked while holding the lock so that this can't race with [Self::remove_client] when
+        // the client is being shut down.
+        let mut watchers = self.watchers.write().unwrap();
+        if !watcher.client.is_shut_down() {
+            watchers.push(watcher);
+        }
+    }
+
+    pub fn remove(&self, watcher: &Arc<LockWatcher>) {
+        self.watchers
+            .write()
+            .unwrap()
+            .retain(|w| !Arc::ptr_eq(w, watcher));
+    }
+
+    /// Removes all watchers registered by `client`.
+    pub fn remove_client(&self, client: &Arc<LockClient>) {
+        self.watchers
+            .write()
+            .unwrap()
+            .retain(|w| !Arc::ptr_eq(&w.client, client));
+    }
+
+    /// Posts `events` that happened on the lock called `name` to matching watchers.
+    pub fn dispatch(&self, name: &str, events: &[LockEvent]) {
+        let watchers = self.watchers.read().unwrap();
+        let mut encoded_name = None;
+
+        for watcher in watchers.iter().filter(|w| w.matches(name)) {
+            let name = encoded_name.get_or_insert_with(|| CString::new(name).unwrap_or_default());
+            for event in events {
+                watcher.notify(name, event);
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+import 'dart:async';
+
 import 'package:weblocks/weblocks.dart';
 import 'package:test/test.dart';
 
@@ -90,6 +92,69 @@ void main() {
 
       blocker!.release();
     });
+
+    test('cancel with steal', () async {
+      final held = (await lockManager.request('cancel-steal').completion)!;
+
+      final request = lockManager.request('cancel-steal', steal: true);
+      request.cancel();
+      await expectLater(
+        request.completion,
+        throwsA(isA<LockRequestCancelled>()),
+      );
+
+      await pumpEventQueue();
+      expect(held.isStolen, isTrue);
+      final snapshot = await lockManager.query();
+      expect(snapshot.held, isEmpty);
+    });
+
+    test('cancel with ifAvailable', () async {
+      final request = lockManager.request(
+        'cancel-if-available',
+        ifAvailable: true,
+      );
+      request.cancel();
+      await expectLater(
+        request.completion,
+        throwsA(isA<LockRequestCancelled>()),
+      );
+
+      // The lock must have been released after cancelling the request.
+      final again = await lockManager
+          .request('cancel-if-available', ifAvailable: true)
+          .completion;
+      expect(again, isNotNull);
+      again!.release();
+    });
+  });
+
+  group('timeout', () {
+    test('completes with TimeoutException', () async {
+      final blocker = await lockManager.request('timeout').completion;
+
+      final request = lockManager.request(
+        'timeout',
+        timeout: const Duration(milliseconds: 50),
+      );
+      await expectLater(request.completion, throwsA(isA<TimeoutException>()));
+
+      final snapshot = await lockManager.query();
+      expect(snapshot.pending, isEmpty);
+      blocker!.release();
+    });
+
+    test('does not apply to granted requests', () async {
+      final request = lockManager.request(
+        'timeout-granted',
+        timeout: const Duration(milliseconds: 50),
+      );
+      final held = await request.completion;
+      await Future<void>.delayed(const Duration(milliseconds: 100));
+
+      expect(held!.isStolen, isFalse);
+      held.release();
+    });
   });
 
   group('request validation', () {
@@ -115,20 +180,13 @@ void main() {
       );
     });
 
-    test('cannot cancel with steal', () async {
-      final request = lockManager.request('cancel-with-steal', steal: true);
-      expect(() => request.cancel(), _throws);
-
-      (await request.completion)!.release();
-    });
-
-    test('cannot cancel with ifAvailable', () async {
-      final request = lockManager.request(
-        'cancel-with-if-available',
-        ifAvailable: true,
+    test('cannot combine timeout and steal', () async {
+      expect(
+        () => lockManager
+            .request('invalid', steal: true, timeout: Duration.zero)
+            .completion,
+        _throws,
       );
-      expect(() => request.cancel(), _throws);
-      (await request.completion)!.release();
     });
 
     test('cannot cancel after lock was granted', () async {
@@ -160,6 +218,19 @@ void main() {
       expect(await allMessagesOnB, ['a']);
     });
 
+    test('cross-process channels deliver messages locally', () async {
+      final a = lockManager.broadcastChannel('cross', crossProcess: true);
+      final b = lockManager.broadcastChannel('cross');
+      final messagesOnB = b.toList();
+
+      a.send('a');
+      await pumpEventQueue();
+      a.close();
+      b.close();
+
+      expect(await messagesOnB, ['a']);
+    });
+
     test('closing emits done event', () async {
       final channel = lockManager.broadcastChannel('close-done');
       final didClose = expectLater(channel, emitsDone);
@@ -0,0 +1,29 @@
+/// Obtains cross-process locks for `locks_test.dart`, which runs this script
+/// in another process.
+///
+/// For every `try` line read from stdin, this requests the lock with
+/// `ifAvailable` and reports whether it has been `acquired`. Obtained locks are
+/// held until the process exits.
+library;
+
+import 'dart:convert';
+import 'dart:io';
+
+import 'package:weblocks/weblocks.dart';
+
+void main(List<String> args) async {
+  final [name] = args;
+  final held = <HeldLock>[];
+  final lines = stdin.transform(utf8.decoder).transform(const LineSplitter());
+
+  print('ready');
+  await for (final line in lines) {
+    if (line != 'try') continue;
+
+    final lock = await lockManager
+        .request(name, crossProcess: true, ifAvailable: true)
+        .completion;
+    if (lock != null) held.add(lock);
+    print(lock != null ? 'acquired' : 'unavailable');
+  }
+}
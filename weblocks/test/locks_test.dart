@@ -1,6 +1,9 @@
 @TestOn('vm')
 library;
 
+import 'dart:async';
+import 'dart:convert';
+import 'dart:io';
 import 'dart:isolate';
 import 'dart:math';
 
@@ -30,6 +33,527 @@ void main() {
     var snapshot = await lockManager.query();
     expect(snapshot.held, isEmpty);
   });
+
+  test('reader-preferring queue policy', () async {
+    lockManager.setQueuePolicy(prefix, QueuePolicy.readerPreferring);
+    addTearDown(() => lockManager.setQueuePolicy(prefix, QueuePolicy.fifo));
+
+    final shared =
+        (await lockManager.request(prefix, exclusive: false).completion)!;
+    final exclusive = lockManager.request(prefix);
+
+    // The second shared request may overtake the pending exclusive one.
+    final secondShared =
+        (await lockManager.request(prefix, exclusive: false).completion)!;
+    final snapshot = await lockManager.query();
+    expect(snapshot.held, hasLength(2));
+    expect(snapshot.pending, [
+      isA<LockInfo>().having(
+        (e) => e.queuePolicy,
+        'queuePolicy',
+        QueuePolicy.readerPreferring,
+      ),
+    ]);
+
+    shared.release();
+    secondShared.release();
+    (await exclusive.completion)!.release();
+  });
+
+  test('detects deadlocks', () async {
+    final a = '$prefix-a';
+    final b = '$prefix-b';
+    final heldA = (await lockManager.request(a).completion)!;
+
+    final otherIsolate = Isolate.run(() async {
+      final heldB = (await lockManager.request(b).completion)!;
+      // Wait for the main isolate to request b.
+      while ((await lockManager.query()).pending.isEmpty) {
+        await Future<void>.delayed(const Duration(milliseconds: 10));
+      }
+
+      try {
+        await lockManager.request(a, detectDeadlocks: true).completion;
+        return null;
+      } on DeadlockDetected catch (e) {
+        return e.cycle.map((e) => e.lock).toList();
+      } finally {
+        heldB.release();
+      }
+    });
+
+    // Wait for the other isolate to hold b.
+    while ((await lockManager.query()).held.length < 2) {
+      await Future<void>.delayed(const Duration(milliseconds: 10));
+    }
+    final requestB = lockManager.request(b);
+
+    expect(await otherIsolate, [a, b]);
+    heldA.release();
+    (await requestB.completion)!.release();
+  });
+
+  test(
+    'cross-process locks are exclusive',
+    () async {
+      final other = await _OtherProcess.start(prefix);
+      addTearDown(other.kill);
+
+      final held =
+          (await lockManager.request(prefix, crossProcess: true).completion)!;
+      expect(await other.tryLock(), isFalse);
+      held.release();
+
+      expect(await other.tryLock(), isTrue);
+      final whileHeld = await lockManager
+          .request(prefix, crossProcess: true, ifAvailable: true)
+          .completion;
+      expect(whileHeld, isNull);
+
+      // The operating system releases the lock file when the process exits.
+      await other.kill();
+      final afterExit = await lockManager
+          .request(prefix, crossProcess: true, ifAvailable: true)
+          .completion;
+      expect(afterExit, isNotNull);
+      afterExit!.release();
+    },
+    timeout: const Timeout(Duration(minutes: 2)),
+  );
+
+  test('reentrant requests', () async {
+    final outer = (await lockManager.request(prefix).completion)!;
+    expect(outer.holdCount, 1);
+
+    final inner =
+        (await lockManager.request(prefix, reentrant: true).completion)!;
+    expect(inner.holdCount, 2);
+
+    outer.release();
+    final otherIsolate = Isolate.run(() async {
+      final held = (await lockManager.request(prefix).completion)!;
+      held.release();
+    });
+
+    // The lock is still held by the inner request.
+    while ((await lockManager.query()).pending.isEmpty) {
+      await Future<void>.delayed(const Duration(milliseconds: 10));
+    }
+    expect((await lockManager.query()).held, hasLength(1));
+
+    inner.release();
+    await otherIsolate;
+  });
+
+  test('reentrant requests with owner token', () async {
+    final owner = _random.nextInt(1 << 32) + 1;
+    final held = await lockManager
+        .request(prefix, reentrant: true, owner: owner)
+        .completion;
+
+    final holdCount = await Isolate.run(() async {
+      final held = await lockManager
+          .request(prefix, reentrant: true, owner: owner)
+          .completion;
+      held!.release();
+      return held.holdCount;
+    });
+    expect(holdCount, 2);
+
+    held!.release();
+  });
+
+  test('upgrade and downgrade', () async {
+    final a = (await lockManager.request(prefix, exclusive: false).completion)!;
+    final b = (await lockManager.request(prefix, exclusive: false).completion)!;
+
+    var upgraded = false;
+    final upgrade = a.upgrade().then((_) => upgraded = true);
+    final pending = lockManager.request(prefix);
+
+    await expectLater(b.upgrade(), throwsStateError);
+    expect(upgraded, isFalse);
+
+    b.release();
+    await upgrade;
+    expect(a.exclusive, isTrue);
+    var held = (await lockManager.query(name: prefix)).held;
+    expect(held.single.exclusive, isTrue);
+
+    a.downgrade();
+    expect(a.exclusive, isFalse);
+    held = (await lockManager.query(name: prefix)).held;
+    expect(held.single.exclusive, isFalse);
+    a.release();
+    (await pending.completion)!.release();
+  });
+
+  test('semaphores', () async {
+    Future<SemaphorePermits> acquire(int permits) {
+      return lockManager.acquirePermits(
+        prefix,
+        maxPermits: 3,
+        permits: permits,
+      );
+    }
+
+    final first = await acquire(2);
+    var grantedSecond = false;
+    final second = acquire(2).then((permits) {
+      grantedSecond = true;
+      return permits;
+    });
+
+    await expectLater(
+      lockManager.acquirePermits(prefix, maxPermits: 4),
+      throwsStateError,
+    );
+    final snapshot = await lockManager.query();
+    expect(snapshot.held.single.permits, 2);
+    expect(snapshot.pending.single.maxPermits, 3);
+    expect(grantedSecond, isFalse);
+
+    first.release();
+    (await second).release();
+  });
+
+  test('condition variables', () async {
+    final held = (await lockManager.request(prefix).completion)!;
+    final waiting = held.wait('ready');
+
+    // Waiting releases the lock, so another request can obtain it.
+    final other = (await lockManager.request(prefix).completion)!;
+    lockManager.notifyCondition(prefix, 'ready');
+    other.release();
+
+    await waiting;
+    expect((await lockManager.query()).held, hasLength(1));
+    held.release();
+  });
+
+  test('namespaces are isolated', () async {
+    final namespace = lockManager.namespace(prefix);
+    final held = (await lockManager.request(prefix).completion)!;
+
+    final inNamespace =
+        await namespace.request(prefix, ifAvailable: true).completion;
+    expect(inNamespace, isNotNull);
+    expect((await namespace.query()).held, hasLength(1));
+
+    // Other isolates using the same namespace share its locks.
+    final otherIsolate = await Isolate.run(() async {
+      final other = await lockManager
+          .namespace(prefix)
+          .request(prefix, ifAvailable: true)
+          .completion;
+      other?.release();
+      return other != null;
+    });
+    expect(otherIsolate, isFalse);
+
+    inNamespace!.release();
+    held.release();
+  });
+
+  test('hierarchical lock names', () async {
+    // Use a namespace so that the separator doesn't affect other tests.
+    final manager = lockManager.namespace(prefix)..setHierarchySeparator('/');
+    final row = (await manager.request('db/users/42').completion)!;
+
+    final table =
+        await manager.request('db/users', ifAvailable: true).completion;
+    expect(table, isNull);
+    final other =
+        (await manager.request('db/other', exclusive: false).completion)!;
+
+    final snapshot = await manager.query();
+    expect(
+      snapshot.held.where((e) => e.intention).map((e) => e.name),
+      unorderedEquals(['db', 'db/users', 'db']),
+    );
+
+    row.release();
+    other.release();
+    (await manager.request('db').completion)!.release();
+  });
+
+  test('leases expire unless renewed', () async {
+    final held = (await lockManager
+        .request(prefix, lease: const Duration(milliseconds: 200))
+        .completion)!;
+    final waiting = lockManager.request(prefix);
+
+    await Future<void>.delayed(const Duration(milliseconds: 100));
+    held.renew();
+    await Future<void>.delayed(const Duration(milliseconds: 150));
+    expect((await lockManager.query()).pending, hasLength(1));
+
+    await held.expired;
+    (await waiting.completion)!.release();
+  });
+
+  test('snapshots describe requests', () async {
+    final held = (await lockManager.request(prefix).completion)!;
+    final first = lockManager.request(prefix);
+    final second = lockManager.request(prefix, ifAvailable: true);
+    final third = lockManager.request(prefix);
+    await second.completion;
+
+    final snapshot = await lockManager.query();
+    final [heldInfo] = snapshot.held.where((e) => e.name == prefix).toList();
+    expect(heldInfo.grantedAt, isNotNull);
+    expect(heldInfo.queuePosition, isNull);
+
+    final pending = snapshot.pending.where((e) => e.name == prefix).toList();
+    expect(pending.map((e) => e.queuePosition), [0, 1]);
+    expect(pending[0].requestId, lessThan(pending[1].requestId!));
+    expect(pending[0].enqueuedAt, isNotNull);
+    expect(pending[0].grantedAt, isNull);
+    expect(pending.map((e) => e.ifAvailable), [false, false]);
+
+    held.release();
+    (await first.completion)!.release();
+    (await third.completion)!.release();
+  });
+
+  test('filtered snapshots', () async {
+    final first = (await lockManager.request('$prefix/a').completion)!;
+    final second = (await lockManager.request('$prefix/b').completion)!;
+
+    expect((await lockManager.query(name: '$prefix/a')).held, [
+      isA<LockInfo>().having((e) => e.name, 'name', '$prefix/a'),
+    ]);
+    expect((await lockManager.query(prefix: '$prefix/')).held, hasLength(2));
+    expect((await lockManager.query(clientId: 'nobody')).held, isEmpty);
+    expect(
+      () => lockManager.query(name: prefix, prefix: prefix),
+      throwsArgumentError,
+    );
+
+    first.release();
+    second.release();
+  });
+
+  test('watching locks', () async {
+    final events = <LockEvent>[];
+    final subscription = lockManager
+        .watch('$prefix/', prefix: true)
+        .listen(events.add);
+
+    final held = (await lockManager.request('$prefix/a').completion)!;
+    final pending = lockManager.request('$prefix/a');
+    await lockManager.request('$prefix/a', ifAvailable: true).completion;
+    held.release();
+    (await pending.completion)!.release();
+    (await lockManager.request('unrelated$prefix').completion)!.release();
+
+    await pumpEventQueue();
+    expect(events.map((e) => e.type), [
+      LockEventType.enqueued,
+      LockEventType.granted,
+      LockEventType.enqueued,
+      LockEventType.unavailable,
+      LockEventType.released,
+      LockEventType.granted,
+      LockEventType.released,
+    ]);
+    expect(events.map((e) => e.name).toSet(), {'$prefix/a'});
+    await subscription.cancel();
+  });
+
+  test('metrics', () async {
+    final held = (await lockManager.request(prefix).completion)!;
+    final pending = lockManager.request(prefix);
+    await lockManager.request(prefix, ifAvailable: true).completion;
+    held.release();
+    (await pending.completion)!.release();
+
+    final [metrics] = (await lockManager.metrics())
+        .where((e) => e.name == prefix)
+        .toList();
+    expect(metrics.acquisitions, 2);
+    expect(metrics.steals, 0);
+    expect(metrics.unavailable, 1);
+    expect(metrics.waitTime.count, 2);
+    expect(metrics.holdTime.count, 2);
+    expect(
+      metrics.holdTime.counts,
+      hasLength(metrics.holdTime.bounds.length + 1),
+    );
+
+    lockManager.resetMetrics();
+    expect(await lockManager.metrics(), isEmpty);
+  });
+
+  test('OpenMetrics export', () async {
+    final namespace = lockManager.namespace(prefix);
+    final held = (await namespace.request('a').completion)!;
+    final pending = namespace.request('a');
+    final other = (await namespace.request('b').completion)!;
+    final channel = namespace.broadcastChannel('channel');
+
+    final text = await namespace.openMetrics(maxLockNames: 1);
+    expect(text, contains('weblocks_lock_pending{name="a"} 1\n'));
+    expect(text, contains('weblocks_lock_holders 1\n'));
+    expect(
+      text,
+      contains('weblocks_broadcast_channel_subscribers{name="channel"} 1\n'),
+    );
+    expect(text, endsWith('# EOF\n'));
+
+    channel.close();
+    held.release();
+    other.release();
+    (await pending.completion)!.release();
+  });
+
+  test('shutdown', () async {
+    final client = lockManager.namespace(prefix);
+    final other = lockManager.namespace(prefix);
+
+    final held = (await client.request('a').completion)!;
+    final pending = client.request('a');
+    final waiting = other.request('a');
+
+    client.shutdown();
+    await expectLater(pending.completion, throwsA(isA<LockRequestCancelled>()));
+    (await waiting.completion)!.release();
+    held.release();
+
+    client.shutdown();
+    await expectLater(
+      client.request('b').completion,
+      throwsA(isA<LockRequestCancelled>()),
+    );
+  });
+
+  test('requesting multiple locks', () async {
+    final a = '$prefix-a', b = '$prefix-b';
+    final held = (await lockManager.request(a).completion)!;
+    final group = lockManager.requestAll([
+      (name: a, exclusive: true),
+      (name: b, exclusive: false),
+    ]);
+
+    // The group doesn't hold b while waiting for a, but later conflicting
+    // requests wait for the group.
+    final other = (await lockManager.request(b, exclusive: false).completion)!;
+    final conflicting = lockManager.request(b);
+    other.release();
+    held.release();
+
+    final locks = await group.completion;
+    expect(locks.locks.map((l) => l.name), [a, b]);
+    expect((await lockManager.query(name: b)).held, hasLength(1));
+
+    final waiting = lockManager.request(a);
+    locks.release();
+    (await waiting.completion)!.release();
+    (await conflicting.completion)!.release();
+
+    expect(
+      () => lockManager.requestAll([
+        (name: a, exclusive: true),
+        (name: a, exclusive: false),
+      ]),
+      throwsArgumentError,
+    );
+    expect(() => lockManager.requestAll([]), throwsArgumentError);
+  });
+
+  test('requesting multiple hierarchical locks', () async {
+    final manager = lockManager.namespace(prefix)..setHierarchySeparator('/');
+    final locks = await manager.requestAll([
+      (name: 'db/users/1', exclusive: true),
+      (name: 'db/orders/2', exclusive: false),
+    ]).completion;
+
+    // The group holds intention locks on the ancestors of its locks.
+    final snapshot = await manager.query();
+    expect(
+      snapshot.held.where((e) => e.intention).map((e) => e.name),
+      unorderedEquals(['db', 'db/users', 'db', 'db/orders']),
+    );
+    final table = manager.request('db');
+    locks.release();
+    (await table.completion)!.release();
+  });
+
+  test('synchronous requests', () async {
+    final held = lockManager.tryRequest(prefix)!;
+    expect(lockManager.tryRequest(prefix), isNull);
+    expect(lockManager.tryRequest(prefix, exclusive: false), isNull);
+
+    final pending = lockManager.request(prefix);
+    held.release();
+    (await pending.completion)!.release();
+
+    // Shared try-locks don't overtake queued exclusive requests.
+    final reader = lockManager.tryRequest(prefix, exclusive: false)!;
+    final writer = lockManager.request(prefix);
+    expect(lockManager.tryRequest(prefix, exclusive: false), isNull);
+    reader.release();
+    (await writer.completion)!.release();
+
+    final crossProcess = lockManager.tryRequest(prefix, crossProcess: true)!;
+    expect(lockManager.tryRequest(prefix, crossProcess: true), isNull);
+    crossProcess.release();
+    expect(
+      lockManager.namespace(prefix).tryRequest(prefix, crossProcess: true),
+      isNull,
+    );
+
+    final observed = lockManager.tryRequest(prefix, observeStolen: true)!;
+    (await lockManager.request(prefix, steal: true).completion)!.release();
+    await observed.stolen;
+  });
 }
 
 final Random _random = Random();
+
+/// Another process obtaining cross-process locks through
+/// `test/cross_process_helper.dart`.
+final class _OtherProcess {
+  final Process _process;
+  final StreamIterator<String> _lines;
+
+  _OtherProcess(this._process)
+    : _lines = StreamIterator(
+        _process.stdout.transform(utf8.decoder).transform(const LineSplitter()),
+      );
+
+  static Future<_OtherProcess> start(String name) async {
+    final process = await Process.start(Platform.resolvedExecutable, [
+      'run',
+      'test/cross_process_helper.dart',
+      name,
+    ]);
+    process.stderr.listen(stderr.add);
+
+    final other = _OtherProcess(process);
+    expect(await other._nextLine(), 'ready');
+    return other;
+  }
+
+  Future<String> _nextLine() async {
+    if (!await _lines.moveNext()) {
+      throw StateError('Helper process exited unexpectedly');
+    }
+    return _lines.current;
+  }
+
+  /// Tries to obtain the lock in the other process, which holds it afterwards.
+  Future<bool> tryLock() async {
+    _process.stdin.writeln('try');
+    return switch (await _nextLine()) {
+      'acquired' => true,
+      'unavailable' => false,
+      final line => throw StateError('Unexpected response: $line'),
+    };
+  }
+
+  Future<void> kill() async {
+    _process.kill();
+    await _process.exitCode;
+  }
+}